@@ -9,7 +9,90 @@ use anchor_spl::{
 declare_id!("7y5yBiJ1Jv4dotxMciA4YKT2cE5oYLigtuwkhP1rXRJs");
 
 pub mod constants {
-    pub const SOLANA_PRICE: u64 = 168000000000;
+    pub const MAX_ROUNDS: usize = 10;
+    pub const MAX_PAYMENT_MINTS: usize = 4;
+    // Widest confidence interval accepted from a price feed, relative to its price
+    pub const MAX_CONFIDENCE_BPS: u128 = 200;
+}
+
+// Reads the aggregate price out of a Pyth v2 price account
+pub mod oracle {
+    use super::*;
+
+    const MAGIC: u32 = 0xa1b2c3d4;
+    const VERSION: u32 = 2;
+    const PRICE_ACCOUNT_TYPE: u32 = 3;
+    const STATUS_TRADING: u32 = 1;
+    // Everything up to and including the aggregate price info
+    const MIN_LEN: usize = 240;
+    // Widest exponent accepted, Pyth feeds stay well within it
+    const MAX_EXPO: u32 = 18;
+
+    pub struct FeedPrice {
+        pub price: i64,
+        pub conf: u64,
+        pub expo: i32,
+        pub publish_time: i64,
+    }
+
+    // Only called on offsets below `MIN_LEN` once `load` has checked the length, so the slice
+    // always has `N` bytes
+    fn read<const N: usize>(data: &[u8], offset: usize) -> [u8; N] {
+        data[offset..offset + N].try_into().unwrap()
+    }
+
+    pub fn load(data: &[u8]) -> Result<FeedPrice> {
+        if data.len() < MIN_LEN
+            || u32::from_le_bytes(read(data, 0)) != MAGIC
+            || u32::from_le_bytes(read(data, 4)) != VERSION
+            || u32::from_le_bytes(read(data, 8)) != PRICE_ACCOUNT_TYPE
+        {
+            return Err(ErrorCode::PriceFeedInvalid.into());
+        }
+        if u32::from_le_bytes(read(data, 224)) != STATUS_TRADING {
+            return Err(ErrorCode::PriceFeedInvalid.into());
+        }
+        let expo = i32::from_le_bytes(read(data, 20));
+        if expo.unsigned_abs() > MAX_EXPO {
+            return Err(ErrorCode::PriceFeedInvalid.into());
+        }
+        Ok(FeedPrice {
+            price: i64::from_le_bytes(read(data, 208)),
+            conf: u64::from_le_bytes(read(data, 216)),
+            expo,
+            publish_time: i64::from_le_bytes(read(data, 96)),
+        })
+    }
+
+    // USD price of one whole unit of the feed's asset, in base units of a USD stablecoin with
+    // `decimals` decimals
+    pub fn price(data: &[u8], now: i64, max_age: i64, decimals: u8) -> Result<u64> {
+        let feed = load(data)?;
+        if feed.price <= 0 {
+            return Err(ErrorCode::PriceFeedInvalid.into());
+        }
+        if now.saturating_sub(feed.publish_time) > max_age {
+            return Err(ErrorCode::PriceStale.into());
+        }
+        if (feed.conf as u128) * 10000 > (feed.price as u128) * constants::MAX_CONFIDENCE_BPS {
+            return Err(ErrorCode::PriceConfidenceTooWide.into());
+        }
+
+        let pow10 = |exp: u32| 10u128.checked_pow(exp).ok_or(ErrorCode::PriceFeedInvalid);
+        let scaled = (feed.price as u128)
+            .checked_mul(pow10(decimals as u32)?)
+            .ok_or(ErrorCode::PriceFeedInvalid)?;
+        let price = if feed.expo < 0 {
+            scaled
+                .checked_div(pow10(feed.expo.unsigned_abs())?)
+                .ok_or(ErrorCode::PriceFeedInvalid)?
+        } else {
+            scaled
+                .checked_mul(pow10(feed.expo.unsigned_abs())?)
+                .ok_or(ErrorCode::PriceFeedInvalid)?
+        };
+        u64::try_from(price).map_err(|_| ErrorCode::PriceFeedInvalid.into())
+    }
 }
 fn burn_tokens<'info>(
     mint_of_token_program_sent: AccountInfo<'info>,
@@ -58,6 +141,42 @@ fn transfer_token<'info>(
     Ok(())
 }
 
+// `amount * mul / div`, rounded down, failing instead of truncating to a u64
+fn mul_div(amount: u64, mul: u64, div: u64) -> Result<u64> {
+    let value = (amount as u128)
+        .checked_mul(mul as u128)
+        .and_then(|value| value.checked_div(div as u128))
+        .ok_or(ErrorCode::MathOverflow)?;
+    u64::try_from(value).map_err(|_| ErrorCode::MathOverflow.into())
+}
+
+// `amount * mul / div`, rounded up
+fn mul_div_ceil(amount: u64, mul: u64, div: u64) -> Result<u64> {
+    let product = (amount as u128)
+        .checked_mul(mul as u128)
+        .ok_or(ErrorCode::MathOverflow)?;
+    if div == 0 {
+        return Err(ErrorCode::MathOverflow.into());
+    }
+    u64::try_from(product.div_ceil(div as u128)).map_err(|_| ErrorCode::MathOverflow.into())
+}
+
+fn transfer_sol_from_vault<'info>(
+    sol_vault: AccountInfo<'info>,
+    to: AccountInfo<'info>,
+    system_program: AccountInfo<'info>,
+    sol_vault_bump: u8,
+    lamports: u64,
+) -> Result<()> {
+    let signer: &[&[&[u8]]] = &[&[b"sol_vault".as_ref(), &[sol_vault_bump]]];
+    anchor_lang::solana_program::program::invoke_signed(
+        &system_instruction::transfer(&sol_vault.key(), &to.key(), lamports),
+        &[sol_vault, to, system_program],
+        signer,
+    )?;
+    Ok(())
+}
+
 fn close_vault<'info>(
     admin: AccountInfo<'info>,
     vault: &mut Account<'info, TokenAccount>,
@@ -89,6 +208,29 @@ fn close_vault<'info>(
     Ok(())
 }
 
+// Merkle whitelists of `(wallet, cap)` leaves. Leaves and nodes are SHA-256 hashes, and each
+// node hashes its two children in ascending order so proofs carry no left/right flags.
+pub mod whitelist {
+    use anchor_lang::solana_program::hash::hashv;
+
+    use super::*;
+
+    pub fn leaf(buyer: &Pubkey, cap: u64) -> [u8; 32] {
+        hashv(&[buyer.as_ref(), &cap.to_le_bytes()]).to_bytes()
+    }
+
+    pub fn verify(proof: &[[u8; 32]], root: [u8; 32], leaf: [u8; 32]) -> bool {
+        let computed = proof.iter().fold(leaf, |node, sibling| {
+            if node <= *sibling {
+                hashv(&[&node, sibling]).to_bytes()
+            } else {
+                hashv(&[sibling, &node]).to_bytes()
+            }
+        });
+        computed == root
+    }
+}
+
 #[program]
 pub mod presale {
     use solana_program::native_token::LAMPORTS_PER_SOL;
@@ -97,33 +239,118 @@ pub mod presale {
 
     pub fn initialize(
         ctx: Context<InitializePresale>,
-        round_one_allocation: u64,
-        round_two_allocation: u64,
-        round_three_allocation: u64,
-        round_one_price: u64,
-        round_two_price: u64,
-        round_three_price: u64,
+        rounds: Vec<RoundConfig>,
+        vesting: VestingSchedule,
+        soft_cap: u64,
+        hard_cap: u64,
+        unsold_policy: UnsoldPolicy,
     ) -> Result<()> {
+        if hard_cap != 0 && hard_cap < soft_cap {
+            return Err(ErrorCode::CapsInvalid.into());
+        }
+        if vesting.tge_basis_points > 10000
+            || vesting.cliff_seconds < 0
+            || vesting.duration_seconds < 0
+        {
+            return Err(ErrorCode::VestingScheduleInvalid.into());
+        }
+
         let info = &mut ctx.accounts.presale_info;
-        info.round_one_allocation_remaining = round_one_allocation;
-        info.round_two_allocation_remaining = round_two_allocation;
-        info.round_three_allocation_remaining = round_three_allocation;
-        info.round_one_price = round_one_price;
-        info.round_two_price = round_two_price;
-        info.round_three_price = round_three_price;
+        info.rounds = rounds.into_iter().map(Round::from).collect();
+        info.validate_rounds()?;
+        info.vesting = vesting;
+        info.soft_cap = soft_cap;
+        info.hard_cap = hard_cap;
+        info.unsold_policy = unsold_policy;
         info.owner = ctx.accounts.admin.key();
+
+        // USDC is the quote unit every price and contribution is expressed in
+        let usdc = &ctx.accounts.mint_of_token_user_send;
+        info.quote_decimals = usdc.decimals;
+        info.payment_mint_count = 1;
+        ctx.accounts.payment_mint.set_inner(PaymentMint {
+            mint: usdc.key(),
+            vault: ctx.accounts.usdc_vault.key(),
+            decimals: usdc.decimals,
+            index: 0,
+            price_feed: Pubkey::default(),
+            max_price_age: 0,
+            raised: 0,
+        });
+
+        // Keeps the SOL vault rent exempt, so refunds can take it down to exactly this
+        let sol_vault = &ctx.accounts.sol_vault;
+        let rent_exempt_minimum = Rent::get()?.minimum_balance(0);
+        if sol_vault.lamports() < rent_exempt_minimum {
+            anchor_lang::solana_program::program::invoke(
+                &system_instruction::transfer(
+                    &ctx.accounts.admin.key(),
+                    &sol_vault.key(),
+                    rent_exempt_minimum - sol_vault.lamports(),
+                ),
+                &[
+                    ctx.accounts.admin.to_account_info(),
+                    sol_vault.to_account_info(),
+                    ctx.accounts.system_program.to_account_info(),
+                ],
+            )?;
+        }
+        Ok(())
+    }
+
+    // Appends a round after the last one, as long as the first round has not opened yet
+    pub fn add_round(ctx: Context<AddRound>, round: RoundConfig) -> Result<()> {
+        let info = &mut ctx.accounts.presale_info;
+        if info.stage()? != Stage::PresaleNotStartedYet
+            || Clock::get()?.unix_timestamp >= info.rounds[0].start_ts
+        {
+            return Err(ErrorCode::PresaleAlreadyStarted.into());
+        }
+
+        info.rounds.push(Round::from(round));
+        info.validate_rounds()
+    }
+
+    // Accepts another SPL mint as payment. Without a price feed it is valued 1:1 with USDC,
+    // otherwise at the feed's USD price.
+    pub fn add_payment_mint(
+        ctx: Context<AddPaymentMint>,
+        price_feed: Option<Pubkey>,
+        max_price_age: i64,
+    ) -> Result<()> {
+        if price_feed.is_some() && max_price_age <= 0 {
+            return Err(ErrorCode::PriceSourceInvalid.into());
+        }
+        let info = &mut ctx.accounts.presale_info;
+        if info.payment_mint_count as usize >= constants::MAX_PAYMENT_MINTS {
+            return Err(ErrorCode::TooManyPaymentMints.into());
+        }
+
+        let mint = &ctx.accounts.mint_of_token_user_send;
+        ctx.accounts.payment_mint.set_inner(PaymentMint {
+            mint: mint.key(),
+            vault: ctx.accounts.payment_vault.key(),
+            decimals: mint.decimals,
+            index: info.payment_mint_count,
+            price_feed: price_feed.unwrap_or_default(),
+            max_price_age,
+            raised: 0,
+        });
+        info.payment_mint_count += 1;
         Ok(())
     }
 
     pub fn fund_pda(ctx: Context<InitializeTokenVault>) -> Result<()> {
         let info: &mut Account<PreSaleDetails> = &mut ctx.accounts.presale_info;
 
-        let total_amount_to_be_deposit = (info
-            .round_one_allocation_remaining
-            .checked_add(info.round_two_allocation_remaining)
-            .unwrap())
-        .checked_add(info.round_three_allocation_remaining)
-        .unwrap();
+        // Tops the vault up to the allocation of every round, so it can be called again after
+        // `add_round`
+        let total_amount_to_be_deposit = info
+            .rounds
+            .iter()
+            .map(|round| round.allocation_remaining)
+            .sum::<u64>()
+            .saturating_sub(ctx.accounts.token_vault.amount);
 
         //sending DL tokens into valut upon initialization
         transfer(
@@ -141,89 +368,167 @@ pub mod presale {
         Ok(())
     }
 
-    pub fn start_next_round(ctx: Context<StartNextRound>) -> Result<()> {
+    // Permissionless crank: moves `stage` past every round boundary that has already passed,
+    // burning the unsold allocation of each round that ended and closing the vault after the last
+    pub fn finalize_round(ctx: Context<FinalizeRound>) -> Result<()> {
+        let now = Clock::get()?.unix_timestamp;
         let info = &mut ctx.accounts.presale_info;
-        let current_stage = Stage::from(info.stage)?;
+        let mut current_stage = info.stage()?;
         if current_stage == Stage::PresaleEnded {
             return Err(ErrorCode::PresaleEnded.into());
         }
+        if now < info.stage_ends_at(current_stage) {
+            return Err(ErrorCode::RoundNotEnded.into());
+        }
 
-        if current_stage != Stage::PresaleNotStartedYet {
-            //checking for burning the remaining round allocation
-            let info_bump = ctx.bumps.presale_info;
-            let signer: &[&[&[u8]]] = &[&[b"presale_info".as_ref(), &[info_bump]]];
-            if current_stage == Stage::RoundOne {
-                if info.round_one_allocation_remaining != 0 {
-                    burn_tokens(
-                        ctx.accounts.mint_of_token_program_sent.to_account_info(),
-                        &mut ctx.accounts.token_vault,
-                        info.to_account_info(),
-                        ctx.accounts.token_program.to_account_info(),
-                        info.round_one_allocation_remaining,
-                        signer,
-                    )?;
-
-                    info.round_one_allocation_remaining = 0;
+        let info_bump = ctx.bumps.presale_info;
+        let signer: &[&[&[u8]]] = &[&[b"presale_info".as_ref(), &[info_bump]]];
+        while current_stage != Stage::PresaleEnded && now >= info.stage_ends_at(current_stage) {
+            if let Stage::Round(idx) = current_stage {
+                // Settling what is left of the round's allocation
+                let unsold = info.take_unsold(idx);
+                let is_last_round = idx == info.rounds.len() - 1;
+                if unsold != 0 {
+                    match info.unsold_policy {
+                        UnsoldPolicy::ReturnToAdmin => {
+                            let admin_token_wallet = ctx
+                                .accounts
+                                .admin_token_wallet
+                                .as_ref()
+                                .ok_or(ErrorCode::AdminTokenWalletMissing)?;
+                            transfer_token(
+                                admin_token_wallet.to_account_info(),
+                                &mut ctx.accounts.token_vault,
+                                info.to_account_info(),
+                                info_bump,
+                                ctx.accounts.token_program.to_account_info(),
+                                unsold,
+                            )?;
+                        }
+                        // Also what rolled over into the last round
+                        _ => burn_tokens(
+                            ctx.accounts.mint_of_token_program_sent.to_account_info(),
+                            &mut ctx.accounts.token_vault,
+                            info.to_account_info(),
+                            ctx.accounts.token_program.to_account_info(),
+                            unsold,
+                            signer,
+                        )?,
+                    }
                 }
-            } else if current_stage == Stage::RoundTwo {
-                if info.round_two_allocation_remaining != 0 {
-                    burn_tokens(
-                        ctx.accounts.mint_of_token_program_sent.to_account_info(),
-                        &mut ctx.accounts.token_vault,
-                        info.to_account_info(),
-                        ctx.accounts.token_program.to_account_info(),
-                        info.round_two_allocation_remaining,
-                        signer,
-                    )?;
 
-                    info.round_two_allocation_remaining = 0;
-                }
-            } else {
-                if info.round_three_allocation_remaining != 0 {
-                    burn_tokens(
-                        ctx.accounts.mint_of_token_program_sent.to_account_info(),
+                if is_last_round {
+                    close_vault(
+                        ctx.accounts.admin.to_account_info(),
                         &mut ctx.accounts.token_vault,
                         info.to_account_info(),
                         ctx.accounts.token_program.to_account_info(),
-                        info.round_three_allocation_remaining,
                         signer,
                     )?;
-
-                    info.round_three_allocation_remaining = 0;
                 }
-
-                close_vault(
-                    ctx.accounts.admin.to_account_info(),
-                    &mut ctx.accounts.token_vault,
-                    info.to_account_info(),
-                    ctx.accounts.token_program.to_account_info(),
-                    signer,
-                )?;
             }
+
+            info.stage = info.stage.checked_add(1).unwrap();
+            current_stage = info.stage()?;
         }
 
-        info.stage = info.stage.checked_add(1).unwrap();
+        Ok(())
+    }
+
+    // Points native purchases at a SOL/USD feed, or at a fixed price (in USDC base units per
+    // SOL) when `price_feed` is `None`
+    pub fn set_price_source(
+        ctx: Context<SetPriceSource>,
+        price_feed: Option<Pubkey>,
+        fallback_sol_price: u64,
+        max_price_age: i64,
+    ) -> Result<()> {
+        if (price_feed.is_none() && fallback_sol_price == 0)
+            || (price_feed.is_some() && max_price_age <= 0)
+        {
+            return Err(ErrorCode::PriceSourceInvalid.into());
+        }
 
+        let info = &mut ctx.accounts.presale_info;
+        info.price_feed = price_feed.unwrap_or_default();
+        info.fallback_sol_price = fallback_sol_price;
+        info.max_price_age = max_price_age;
         Ok(())
     }
 
-    pub fn buy_tokens(ctx: Context<BuyTokens>, input_amount: u64, is_native: bool) -> Result<()> {
+    // Limits on what a single wallet contributes, in USDC base units. A maximum of 0 means
+    // no cap.
+    pub fn set_contribution_limits(
+        ctx: Context<SetContributionLimits>,
+        min_round_contribution: u64,
+        max_round_contribution: u64,
+        min_wallet_contribution: u64,
+        max_wallet_contribution: u64,
+    ) -> Result<()> {
+        if (max_round_contribution != 0 && min_round_contribution > max_round_contribution)
+            || (max_wallet_contribution != 0 && min_wallet_contribution > max_wallet_contribution)
+        {
+            return Err(ErrorCode::ContributionLimitsInvalid.into());
+        }
+
         let info = &mut ctx.accounts.presale_info;
-        let current_stage = Stage::from(info.stage)?;
-        let dl_to_sent;
-        let mut usdc_amount = input_amount;
-        if current_stage == Stage::PresaleNotStartedYet {
-            return Err(ErrorCode::PresaleNotStartedYet.into());
+        info.min_round_contribution = min_round_contribution;
+        info.max_round_contribution = max_round_contribution;
+        info.min_wallet_contribution = min_wallet_contribution;
+        info.max_wallet_contribution = max_wallet_contribution;
+        Ok(())
+    }
+
+    // Replaces the whitelist of a round that has not ended yet. An all-zero root opens the
+    // round to everyone.
+    pub fn set_round_whitelist(
+        ctx: Context<SetRoundWhitelist>,
+        round_idx: u8,
+        merkle_root: [u8; 32],
+    ) -> Result<()> {
+        let now = Clock::get()?.unix_timestamp;
+        let round = ctx
+            .accounts
+            .presale_info
+            .rounds
+            .get_mut(round_idx as usize)
+            .ok_or(ErrorCode::RoundNotFound)?;
+        if now >= round.end_ts {
+            return Err(ErrorCode::RoundEnded.into());
         }
 
-        if current_stage == Stage::PresaleEnded {
-            return Err(ErrorCode::PresaleEnded.into());
+        round.merkle_root = merkle_root;
+        Ok(())
+    }
+
+    // `whitelist_cap` and `proof` are only checked in whitelisted rounds, where they must prove
+    // that the buyer's leaf with that cap is in the round's Merkle tree
+    pub fn buy_tokens(
+        ctx: Context<BuyTokens>,
+        input_amount: u64,
+        is_native: bool,
+        whitelist_cap: u64,
+        proof: Vec<[u8; 32]>,
+    ) -> Result<()> {
+        let now = Clock::get()?.unix_timestamp;
+        let info = &mut ctx.accounts.presale_info;
+        let round_idx = info.active_round(now)?;
+        let merkle_root = info.rounds[round_idx].merkle_root;
+        let is_whitelisted_round = merkle_root != [0; 32];
+        if is_whitelisted_round
+            && !whitelist::verify(
+                &proof,
+                merkle_root,
+                whitelist::leaf(&ctx.accounts.buyer.key(), whitelist_cap),
+            )
+        {
+            return Err(ErrorCode::NotWhitelisted.into());
         }
         if is_native {
             // Sending SOL into pda
             let ix = system_instruction::transfer(
                 &ctx.accounts.buyer.key(),
-                &ctx.accounts.usdc_vault.key(),
+                &ctx.accounts.sol_vault.key(),
                 input_amount,
             );
 
@@ -232,7 +537,7 @@ pub mod presale {
                 &ix,
                 &[
                     ctx.accounts.buyer.to_account_info(),
-                    ctx.accounts.usdc_vault.to_account_info(),
+                    ctx.accounts.sol_vault.to_account_info(),
                     ctx.accounts.system_program.to_account_info(),
                 ],
             )?;
@@ -241,8 +546,8 @@ pub mod presale {
                 CpiContext::new(
                     ctx.accounts.token_program.to_account_info(),
                     Transfer {
-                        from: ctx.accounts.buyer_usdc_account.to_account_info(),
-                        to: ctx.accounts.usdc_vault.to_account_info(),
+                        from: ctx.accounts.buyer_payment_account.to_account_info(),
+                        to: ctx.accounts.payment_vault.to_account_info(),
                         authority: ctx.accounts.buyer.to_account_info(),
                     },
                 ),
@@ -250,86 +555,242 @@ pub mod presale {
             )?;
         }
 
-        if is_native {
-            usdc_amount = ((constants::SOLANA_PRICE as u128)
-                .checked_mul(input_amount as u128)
-                .unwrap())
-            .checked_div(LAMPORTS_PER_SOL as u128)
-            .unwrap() as u64;
-        }
+        // The price of one whole SOL or payment token in USDC base units, from the feed when
+        // one is configured
+        let payment_mint = &ctx.accounts.payment_mint;
+        let (price_feed, max_price_age) = if is_native {
+            (info.price_feed, info.max_price_age)
+        } else {
+            (payment_mint.price_feed, payment_mint.max_price_age)
+        };
+        let unit_price = if price_feed != Pubkey::default() {
+            let price_feed = ctx
+                .accounts
+                .price_feed
+                .as_ref()
+                .filter(|feed| feed.key() == price_feed)
+                .ok_or(ErrorCode::PriceFeedMismatch)?;
+            oracle::price(
+                &price_feed.try_borrow_data()?,
+                now,
+                max_price_age,
+                info.quote_decimals,
+            )?
+        } else if is_native {
+            // The fallback price only applies while no feed is configured
+            if info.fallback_sol_price == 0 {
+                return Err(ErrorCode::SolPriceNotConfigured.into());
+            }
+            info.fallback_sol_price
+        } else {
+            10u64
+                .checked_pow(info.quote_decimals as u32)
+                .ok_or(ErrorCode::MathOverflow)?
+        };
+        let unit = if is_native {
+            LAMPORTS_PER_SOL
+        } else {
+            10u64
+                .checked_pow(payment_mint.decimals as u32)
+                .ok_or(ErrorCode::MathOverflow)?
+        };
 
-        if current_stage == Stage::RoundOne {
-            // dl_to_sent = (input_amount.checked_mul(LAMPORTS_PER_SOL).unwrap())
-            //     .checked_div(info.round_one_price)
-            //     .unwrap();
+        // USDC value of the input
+        let usdc_value = mul_div(input_amount, unit_price, unit)?;
 
-            dl_to_sent = (usdc_amount as u128)
-                .checked_mul(LAMPORTS_PER_SOL as u128)
-                .unwrap()
-                .checked_div(info.round_one_price as u128)
-                .unwrap() as u64;
+        // The purchase that reaches the hard cap, or sells out the last round, is only filled
+        // up to what is left
+        let is_last_round = round_idx + 1 == info.rounds.len();
+        let mut usdc_amount = usdc_value;
+        if info.hard_cap != 0 {
+            usdc_amount = usdc_amount.min(info.hard_cap.saturating_sub(info.total_raised));
+        }
+        let round = &mut info.rounds[round_idx];
+        let mut dl_to_sent = mul_div(usdc_amount, LAMPORTS_PER_SOL, round.price)?;
 
-            if dl_to_sent > info.round_one_allocation_remaining {
+        if dl_to_sent > round.allocation_remaining {
+            if !is_last_round {
                 return Err(ErrorCode::InsufficientAllocation.into());
             }
+            dl_to_sent = round.allocation_remaining;
+            usdc_amount = mul_div(dl_to_sent, round.price, LAMPORTS_PER_SOL)?;
+        }
 
-            info.round_one_allocation_remaining = info
-                .round_one_allocation_remaining
-                .checked_sub(dl_to_sent)
-                .unwrap();
-        } else if current_stage == Stage::RoundTwo {
-            dl_to_sent = (usdc_amount as u128)
-                .checked_mul(LAMPORTS_PER_SOL as u128)
-                .unwrap()
-                .checked_div(info.round_two_price as u128)
-                .unwrap() as u64;
-
-            if dl_to_sent > info.round_two_allocation_remaining {
-                return Err(ErrorCode::InsufficientAllocation.into());
-            }
+        round.allocation_remaining = round
+            .allocation_remaining
+            .checked_sub(dl_to_sent)
+            .ok_or(ErrorCode::MathOverflow)?;
 
-            info.round_two_allocation_remaining = info
-                .round_two_allocation_remaining
-                .checked_sub(dl_to_sent)
-                .unwrap();
+        // A trimmed fill charges the part of the input that covers it, rounded up so the
+        // sale is never short
+        let is_trimmed = usdc_amount != usdc_value;
+        let amount_paid = if is_trimmed {
+            mul_div_ceil(usdc_amount, unit, unit_price)?.min(input_amount)
         } else {
-            dl_to_sent = (usdc_amount as u128)
-                .checked_mul(LAMPORTS_PER_SOL as u128)
-                .unwrap()
-                .checked_div(info.round_three_price as u128)
-                .unwrap() as u64;
+            input_amount
+        };
 
-            if dl_to_sent > info.round_three_allocation_remaining {
-                return Err(ErrorCode::InsufficientAllocation.into());
+        let purchase = &mut ctx.accounts.purchase;
+        let round_contribution = purchase.contributed[round_idx]
+            .checked_add(usdc_amount)
+            .ok_or(ErrorCode::MathOverflow)?;
+        let total_contribution = purchase
+            .total_contributed
+            .checked_add(usdc_amount)
+            .ok_or(ErrorCode::MathOverflow)?;
+        // The last fill of the sale may be smaller than the minimums, since nothing larger is left
+        info.check_contribution(round_contribution, total_contribution, is_trimmed)?;
+        if is_whitelisted_round && round_contribution > whitelist_cap {
+            return Err(ErrorCode::AboveWhitelistCap.into());
+        }
+
+        purchase.buyer = ctx.accounts.buyer.key();
+        purchase.contributed[round_idx] = round_contribution;
+        purchase.total_contributed = total_contribution;
+        purchase.tokens_received[round_idx] = purchase.tokens_received[round_idx]
+            .checked_add(dl_to_sent)
+            .ok_or(ErrorCode::MathOverflow)?;
+        purchase.total_tokens_received = purchase
+            .total_tokens_received
+            .checked_add(dl_to_sent)
+            .ok_or(ErrorCode::MathOverflow)?;
+        info.total_raised = info
+            .total_raised
+            .checked_add(usdc_amount)
+            .ok_or(ErrorCode::MathOverflow)?;
+        if is_native {
+            purchase.paid_lamports = purchase
+                .paid_lamports
+                .checked_add(amount_paid)
+                .ok_or(ErrorCode::MathOverflow)?;
+            info.sol_raised = info
+                .sol_raised
+                .checked_add(amount_paid)
+                .ok_or(ErrorCode::MathOverflow)?;
+        } else {
+            let payment_mint = &mut ctx.accounts.payment_mint;
+            let paid = &mut purchase.paid[payment_mint.index as usize];
+            *paid = paid.checked_add(amount_paid).ok_or(ErrorCode::MathOverflow)?;
+            payment_mint.raised = payment_mint
+                .raised
+                .checked_add(amount_paid)
+                .ok_or(ErrorCode::MathOverflow)?;
+        }
+
+        if (info.hard_cap != 0 && info.total_raised >= info.hard_cap)
+            || (is_last_round && info.rounds[round_idx].allocation_remaining == 0)
+        {
+            info.ended_at = now;
+        }
+
+        // Handing back whatever part of the input was not filled
+        let excess = input_amount - amount_paid;
+        if excess != 0 {
+            if is_native {
+                transfer_sol_from_vault(
+                    ctx.accounts.sol_vault.to_account_info(),
+                    ctx.accounts.buyer.to_account_info(),
+                    ctx.accounts.system_program.to_account_info(),
+                    ctx.bumps.sol_vault,
+                    excess,
+                )?;
+            } else {
+                transfer_token(
+                    ctx.accounts.buyer_payment_account.to_account_info(),
+                    &mut ctx.accounts.payment_vault,
+                    info.to_account_info(),
+                    ctx.bumps.presale_info,
+                    ctx.accounts.token_program.to_account_info(),
+                    excess,
+                )?;
             }
+        }
+
+        // The tokens stay in `token_vault` until they vest and are claimed
+        Ok(())
+    }
 
-            info.round_three_allocation_remaining = info
-                .round_three_allocation_remaining
-                .checked_sub(dl_to_sent)
-                .unwrap();
+    // Sends Bob whatever part of his purchased tokens has vested and was not claimed yet
+    pub fn claim(ctx: Context<Claim>) -> Result<()> {
+        let info = &ctx.accounts.presale_info;
+        let purchase = &mut ctx.accounts.purchase;
+        let vested =
+            info.vested_amount(purchase.total_tokens_received, Clock::get()?.unix_timestamp);
+        let amount_to_claim = vested.saturating_sub(purchase.claimed);
+        if amount_to_claim == 0 {
+            return Err(ErrorCode::NothingToClaim.into());
         }
+        if info.total_raised < info.soft_cap {
+            return Err(ErrorCode::SoftCapNotReached.into());
+        }
+
+        purchase.claimed = vested;
         transfer_token(
             ctx.accounts.wallet_to_deposit_to.to_account_info(),
             &mut ctx.accounts.token_vault,
             info.to_account_info(),
             ctx.bumps.presale_info,
             ctx.accounts.token_program.to_account_info(),
-            dl_to_sent,
+            amount_to_claim,
         )?;
 
         Ok(())
     }
 
-    pub fn withdraw_usdc(ctx: Context<WithdrawUSDC>) -> Result<()> {
+    // Pays Bob back exactly what he paid in SOL and in the given payment mint once the sale has
+    // ended below its soft cap. His purchase is closed once nothing is left to refund, so
+    // buyers who paid with several mints call this once per mint.
+    pub fn refund(ctx: Context<Refund>) -> Result<()> {
+        let info = &ctx.accounts.presale_info;
+        if info.stage()? != Stage::PresaleEnded {
+            return Err(ErrorCode::PresaleNotEndedYet.into());
+        }
+        if info.total_raised >= info.soft_cap {
+            return Err(ErrorCode::SoftCapReached.into());
+        }
+
+        let purchase = &mut ctx.accounts.purchase;
+        let paid = std::mem::take(&mut purchase.paid[ctx.accounts.payment_mint.index as usize]);
+        if paid != 0 {
+            transfer_token(
+                ctx.accounts.buyer_payment_account.to_account_info(),
+                &mut ctx.accounts.payment_vault,
+                info.to_account_info(),
+                ctx.bumps.presale_info,
+                ctx.accounts.token_program.to_account_info(),
+                paid,
+            )?;
+        }
+        let paid_lamports = std::mem::take(&mut purchase.paid_lamports);
+        if paid_lamports != 0 {
+            transfer_sol_from_vault(
+                ctx.accounts.sol_vault.to_account_info(),
+                ctx.accounts.buyer.to_account_info(),
+                ctx.accounts.system_program.to_account_info(),
+                ctx.bumps.sol_vault,
+                paid_lamports,
+            )?;
+        }
+
+        if purchase.paid.iter().all(|paid| *paid == 0) {
+            purchase.close(ctx.accounts.buyer.to_account_info())?;
+        }
+        Ok(())
+    }
+
+    // Sends everything raised in one payment mint to the admin and closes its vault
+    pub fn withdraw_payment(ctx: Context<WithdrawPayment>) -> Result<()> {
         let info = &mut ctx.accounts.presale_info;
-        let current_stage = Stage::from(info.stage)?;
-        if current_stage != Stage::PresaleEnded {
-            return Err(ErrorCode::PresaleNotStartedYet.into());
+        if info.stage()? != Stage::PresaleEnded {
+            return Err(ErrorCode::PresaleNotEndedYet.into());
         }
-        let usdc_to_withdraw = ctx.accounts.usdc_vault.amount;
+        if info.total_raised < info.soft_cap {
+            return Err(ErrorCode::SoftCapNotReached.into());
+        }
+        let usdc_to_withdraw = ctx.accounts.payment_vault.amount;
         transfer_token(
-            ctx.accounts.usdc_wallet.to_account_info(),
-            &mut ctx.accounts.usdc_vault,
+            ctx.accounts.admin_wallet.to_account_info(),
+            &mut ctx.accounts.payment_vault,
             info.to_account_info(),
             ctx.bumps.presale_info,
             ctx.accounts.token_program.to_account_info(),
@@ -340,7 +801,68 @@ pub mod presale {
 
         close_vault(
             ctx.accounts.admin.to_account_info(),
-            &mut ctx.accounts.usdc_vault,
+            &mut ctx.accounts.payment_vault,
+            info.to_account_info(),
+            ctx.accounts.token_program.to_account_info(),
+            signer,
+        )?;
+
+        Ok(())
+    }
+
+    // Sends the SOL raised to the admin, leaving the vault's rent-exempt minimum behind
+    pub fn withdraw_sol(ctx: Context<WithdrawSol>) -> Result<()> {
+        let info = &ctx.accounts.presale_info;
+        if info.stage()? != Stage::PresaleEnded {
+            return Err(ErrorCode::PresaleNotEndedYet.into());
+        }
+        if info.total_raised < info.soft_cap {
+            return Err(ErrorCode::SoftCapNotReached.into());
+        }
+
+        let sol_to_withdraw = ctx
+            .accounts
+            .sol_vault
+            .lamports()
+            .saturating_sub(Rent::get()?.minimum_balance(0));
+        if sol_to_withdraw != 0 {
+            transfer_sol_from_vault(
+                ctx.accounts.sol_vault.to_account_info(),
+                ctx.accounts.admin.to_account_info(),
+                ctx.accounts.system_program.to_account_info(),
+                ctx.bumps.sol_vault,
+                sol_to_withdraw,
+            )?;
+        }
+        Ok(())
+    }
+
+    // Once the sale ended below its soft cap nobody can claim, so the tokens sold are sent back
+    // to the admin along with whatever is left in the vault, which is then closed
+    pub fn recover_tokens(ctx: Context<RecoverTokens>) -> Result<()> {
+        let info = &ctx.accounts.presale_info;
+        if info.stage()? != Stage::PresaleEnded {
+            return Err(ErrorCode::PresaleNotEndedYet.into());
+        }
+        if info.total_raised >= info.soft_cap {
+            return Err(ErrorCode::SoftCapReached.into());
+        }
+
+        let tokens_to_recover = ctx.accounts.token_vault.amount;
+        transfer_token(
+            ctx.accounts.admin_token_wallet.to_account_info(),
+            &mut ctx.accounts.token_vault,
+            info.to_account_info(),
+            ctx.bumps.presale_info,
+            ctx.accounts.token_program.to_account_info(),
+            tokens_to_recover,
+        )?;
+        let info_bump = ctx.bumps.presale_info;
+        let signer: &[&[&[u8]]] = &[&[b"presale_info".as_ref(), &[info_bump]]];
+
+        close_vault(
+            ctx.accounts.admin.to_account_info(),
+            &mut ctx.accounts.token_vault,
             info.to_account_info(),
             ctx.accounts.token_program.to_account_info(),
             signer,
@@ -352,19 +874,18 @@ pub mod presale {
 #[derive(Clone, Copy, PartialEq)]
 pub enum Stage {
     PresaleNotStartedYet,
-    RoundOne,
-    RoundTwo,
-    RoundThird,
+    // Index into `PreSaleDetails::rounds` of the round not finalized yet
+    Round(usize),
     PresaleEnded,
 }
 impl Stage {
-    fn from(val: u8) -> Result<Stage> {
-        match val {
+    // `stage` counts the boundaries passed so far: the start of the first round, then the end
+    // of every round
+    fn from(val: u8, round_count: usize) -> Result<Stage> {
+        match val as usize {
             0 => Ok(Stage::PresaleNotStartedYet),
-            1 => Ok(Stage::RoundOne),
-            2 => Ok(Stage::RoundTwo),
-            3 => Ok(Stage::RoundThird),
-            4 => Ok(Stage::PresaleEnded),
+            n if n <= round_count => Ok(Stage::Round(n - 1)),
+            n if n == round_count + 1 => Ok(Stage::PresaleEnded),
             unknown_value => {
                 msg!("Unknown stage: {}", unknown_value);
                 Err(ErrorCode::StageInvalid.into())
@@ -377,16 +898,16 @@ impl Stage {
 pub struct InitializePresale<'info> {
     // Derived PDAs
     #[account(
-        init_if_needed,
+        init,
         payer = admin,
         seeds=[b"presale_info".as_ref()],
         bump,
-        space = 8 + std::mem::size_of::<PreSaleDetails>()
+        space = PreSaleDetails::SPACE
     )]
     presale_info: Account<'info, PreSaleDetails>,
 
     #[account(
-        init_if_needed,
+        init,
         payer = admin,
         seeds=[b"usdc_vault".as_ref()],
         bump,
@@ -395,6 +916,22 @@ pub struct InitializePresale<'info> {
     )]
     usdc_vault: Account<'info, TokenAccount>,
 
+    #[account(
+        mut,
+        seeds=[b"sol_vault".as_ref()],
+        bump
+    )]
+    sol_vault: SystemAccount<'info>, // Proceeds of native purchases
+
+    #[account(
+        init,
+        payer = admin,
+        seeds=[b"payment_mint".as_ref(), mint_of_token_user_send.key().as_ref()],
+        bump,
+        space = PaymentMint::SPACE
+    )]
+    payment_mint: Account<'info, PaymentMint>,
+
     mint_of_token_user_send: Account<'info, Mint>, // USDC
 
     #[account(mut)]
@@ -442,8 +979,7 @@ pub struct InitializeTokenVault<'info> {
 }
 
 #[derive(Accounts)]
-pub struct StartNextRound<'info> {
-    // Derived PDAs
+pub struct AddRound<'info> {
     #[account(
        mut,
         seeds=[b"presale_info".as_ref()],
@@ -452,25 +988,13 @@ pub struct StartNextRound<'info> {
     presale_info: Account<'info, PreSaleDetails>,
 
     #[account(
-        mut,
-        seeds=[b"token_vault".as_ref()],
-        bump
-    )]
-    token_vault: Account<'info, TokenAccount>,
-    #[account(mut)]
-    mint_of_token_program_sent: Account<'info, Mint>, // DL token
-
-    #[account(mut,
          constraint = presale_info.owner == admin.key() @ ErrorCode::UnauthorizedAdmin
         )]
-    admin: Signer<'info>, // The person who is initializing the presale
-
-    token_program: Program<'info, Token>,
+    admin: Signer<'info>,
 }
 
 #[derive(Accounts)]
-pub struct WithdrawUSDC<'info> {
-    // Derived PDAs
+pub struct AddPaymentMint<'info> {
     #[account(
        mut,
         seeds=[b"presale_info".as_ref()],
@@ -479,73 +1003,362 @@ pub struct WithdrawUSDC<'info> {
     presale_info: Account<'info, PreSaleDetails>,
 
     #[account(
-        mut,
-        seeds=[b"usdc_vault".as_ref()],
-        bump
+        init,
+        payer = admin,
+        seeds=[b"payment_mint".as_ref(), mint_of_token_user_send.key().as_ref()],
+        bump,
+        space = PaymentMint::SPACE
     )]
-    usdc_vault: Account<'info, TokenAccount>,
+    payment_mint: Account<'info, PaymentMint>,
 
-    #[account(mut)]
-    admin: Signer<'info>, // The person who is initializing the presale
-
-    #[account(mut)]
-    mint_of_token_user_send: Account<'info, Mint>, // USDC
-
-    // Wallet to deposit to
     #[account(
-        mut,
-        constraint=usdc_wallet.owner == admin.key() @ ErrorCode::UnauthorizedAdmin,
-        constraint=usdc_wallet.mint == mint_of_token_user_send.key()
+        init,
+        payer = admin,
+        seeds=[b"payment_vault".as_ref(), mint_of_token_user_send.key().as_ref()],
+        bump,
+        token::mint = mint_of_token_user_send,
+        token::authority = presale_info,
     )]
-    usdc_wallet: Account<'info, TokenAccount>, // Alice USDC wallet to withdraw funds
+    payment_vault: Account<'info, TokenAccount>,
+
+    mint_of_token_user_send: Account<'info, Mint>, // USDT, PYUSD, ...
+
+    #[account(mut,
+         constraint = presale_info.owner == admin.key() @ ErrorCode::UnauthorizedAdmin
+        )]
+    admin: Signer<'info>,
 
+    // Application level accounts
+    system_program: Program<'info, System>,
     token_program: Program<'info, Token>,
 }
 
 #[derive(Accounts)]
-pub struct BuyTokens<'info> {
-    // Derived PDAs
+pub struct SetPriceSource<'info> {
     #[account(
-        mut,
-         seeds=[b"presale_info".as_ref()],
-         bump
-     )]
+       mut,
+        seeds=[b"presale_info".as_ref()],
+        bump
+    )]
     presale_info: Account<'info, PreSaleDetails>,
 
     #[account(
-         mut,
-         seeds=[b"usdc_vault".as_ref()],
-         bump
-     )]
-    usdc_vault: Account<'info, TokenAccount>,
+         constraint = presale_info.owner == admin.key() @ ErrorCode::UnauthorizedAdmin
+        )]
+    admin: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct SetContributionLimits<'info> {
+    #[account(
+       mut,
+        seeds=[b"presale_info".as_ref()],
+        bump
+    )]
+    presale_info: Account<'info, PreSaleDetails>,
+
+    #[account(
+         constraint = presale_info.owner == admin.key() @ ErrorCode::UnauthorizedAdmin
+        )]
+    admin: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct SetRoundWhitelist<'info> {
+    #[account(
+       mut,
+        seeds=[b"presale_info".as_ref()],
+        bump
+    )]
+    presale_info: Account<'info, PreSaleDetails>,
+
+    #[account(
+         constraint = presale_info.owner == admin.key() @ ErrorCode::UnauthorizedAdmin
+        )]
+    admin: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct FinalizeRound<'info> {
+    // Derived PDAs
+    #[account(
+       mut,
+        seeds=[b"presale_info".as_ref()],
+        bump
+    )]
+    presale_info: Account<'info, PreSaleDetails>,
+
+    #[account(
+        mut,
+        seeds=[b"token_vault".as_ref()],
+        bump
+    )]
+    token_vault: Account<'info, TokenAccount>,
+    #[account(mut)]
+    mint_of_token_program_sent: Account<'info, Mint>, // DL token
+
+    // Receives the rent of the token vault once the last round is finalized
+    #[account(mut,
+         constraint = presale_info.owner == admin.key() @ ErrorCode::UnauthorizedAdmin
+        )]
+    admin: SystemAccount<'info>,
+
+    // Receives unsold tokens under `UnsoldPolicy::ReturnToAdmin`
+    #[account(
+        mut,
+        constraint = admin_token_wallet.owner == admin.key() @ ErrorCode::UnauthorizedAdmin,
+        constraint = admin_token_wallet.mint == mint_of_token_program_sent.key()
+    )]
+    admin_token_wallet: Option<Account<'info, TokenAccount>>,
+
+    token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct WithdrawSol<'info> {
+    // Derived PDAs
+    #[account(
+        seeds=[b"presale_info".as_ref()],
+        bump
+    )]
+    presale_info: Account<'info, PreSaleDetails>,
+
+    #[account(
+        mut,
+        seeds=[b"sol_vault".as_ref()],
+        bump
+    )]
+    sol_vault: SystemAccount<'info>,
+
+    #[account(mut,
+         constraint = presale_info.owner == admin.key() @ ErrorCode::UnauthorizedAdmin
+        )]
+    admin: Signer<'info>,
+
+    system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct WithdrawPayment<'info> {
+    // Derived PDAs
+    #[account(
+       mut,
+        seeds=[b"presale_info".as_ref()],
+        bump
+    )]
+    presale_info: Account<'info, PreSaleDetails>,
+
+    #[account(
+        seeds=[b"payment_mint".as_ref(), mint_of_token_user_send.key().as_ref()],
+        bump
+    )]
+    payment_mint: Account<'info, PaymentMint>,
+
+    #[account(
+        mut,
+        constraint = payment_vault.key() == payment_mint.vault @ ErrorCode::PaymentVaultMismatch
+    )]
+    payment_vault: Account<'info, TokenAccount>,
+
+    #[account(mut,
+         constraint = presale_info.owner == admin.key() @ ErrorCode::UnauthorizedAdmin
+        )]
+    admin: Signer<'info>, // The person who is initializing the presale
+
+    mint_of_token_user_send: Account<'info, Mint>, // USDC, USDT, ...
+
+    // Wallet to deposit to
+    #[account(
+        mut,
+        constraint=admin_wallet.owner == admin.key() @ ErrorCode::UnauthorizedAdmin,
+        constraint=admin_wallet.mint == mint_of_token_user_send.key()
+    )]
+    admin_wallet: Account<'info, TokenAccount>, // Alice wallet to withdraw funds
+
+    token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct RecoverTokens<'info> {
+    // Derived PDAs
+    #[account(
+        seeds=[b"presale_info".as_ref()],
+        bump
+    )]
+    presale_info: Account<'info, PreSaleDetails>,
+
+    #[account(
+        mut,
+        seeds=[b"token_vault".as_ref()],
+        bump
+    )]
+    token_vault: Account<'info, TokenAccount>,
+
+    // Receives the rent of the token vault
+    #[account(mut,
+         constraint = presale_info.owner == admin.key() @ ErrorCode::UnauthorizedAdmin
+        )]
+    admin: Signer<'info>,
+
+    mint_of_token_program_sent: Account<'info, Mint>, // DL token
+
+    #[account(
+        mut,
+        constraint = admin_token_wallet.owner == admin.key() @ ErrorCode::UnauthorizedAdmin,
+        constraint = admin_token_wallet.mint == mint_of_token_program_sent.key()
+    )]
+    admin_token_wallet: Account<'info, TokenAccount>,
+
+    token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct BuyTokens<'info> {
+    // Derived PDAs
+    #[account(
+        mut,
+         seeds=[b"presale_info".as_ref()],
+         bump
+     )]
+    presale_info: Account<'info, PreSaleDetails>,
+
+    #[account(
+        mut,
+        seeds=[b"payment_mint".as_ref(), mint_of_token_user_send.key().as_ref()],
+        bump
+    )]
+    payment_mint: Account<'info, PaymentMint>,
+
+    #[account(
+        mut,
+        constraint = payment_vault.key() == payment_mint.vault @ ErrorCode::PaymentVaultMismatch
+    )]
+    payment_vault: Account<'info, TokenAccount>,
 
     #[account(
          mut,
-         seeds=[b"token_vault".as_ref()],
+         seeds=[b"sol_vault".as_ref()],
          bump
      )]
-    token_vault: Account<'info, TokenAccount>,
+    sol_vault: SystemAccount<'info>,
 
     #[account(
         init_if_needed,
         payer = buyer,
-        associated_token::mint = mint_of_token_program_sent,
-        associated_token::authority = buyer,
+        seeds=[b"purchase".as_ref(), buyer.key().as_ref()],
+        bump,
+        space = Purchase::SPACE
     )]
-    wallet_to_deposit_to: Account<'info, TokenAccount>, // Bob's DL token wallet (will be initialized if it did not exist)
+    purchase: Account<'info, Purchase>, // Bob's contributions so far
 
     #[account(
         mut,
         associated_token::mint = mint_of_token_user_send,
         associated_token::authority = buyer,
     )]
-    pub buyer_usdc_account: Account<'info, TokenAccount>,
+    pub buyer_payment_account: Account<'info, TokenAccount>,
 
     // Users and accounts in the system
     #[account(mut)]
     buyer: Signer<'info>, // Bob
 
-    mint_of_token_user_send: Account<'info, Mint>, // USDC
+    mint_of_token_user_send: Account<'info, Mint>, // Any registered payment mint
+
+    /// CHECK: only read after matching the price feed configured for SOL or the payment mint
+    price_feed: Option<UncheckedAccount<'info>>,
+
+    // Application level accounts
+    system_program: Program<'info, System>,
+    token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct Refund<'info> {
+    // Derived PDAs
+    #[account(
+         seeds=[b"presale_info".as_ref()],
+         bump
+     )]
+    presale_info: Account<'info, PreSaleDetails>,
+
+    #[account(
+        mut,
+        seeds=[b"payment_mint".as_ref(), mint_of_token_user_send.key().as_ref()],
+        bump
+    )]
+    payment_mint: Account<'info, PaymentMint>,
+
+    #[account(
+        mut,
+        constraint = payment_vault.key() == payment_mint.vault @ ErrorCode::PaymentVaultMismatch
+    )]
+    payment_vault: Account<'info, TokenAccount>,
+
+    #[account(
+         mut,
+         seeds=[b"sol_vault".as_ref()],
+         bump
+     )]
+    sol_vault: SystemAccount<'info>,
+
+    #[account(
+        mut,
+        seeds=[b"purchase".as_ref(), buyer.key().as_ref()],
+        bump,
+    )]
+    purchase: Account<'info, Purchase>,
+
+    #[account(
+        mut,
+        associated_token::mint = mint_of_token_user_send,
+        associated_token::authority = buyer,
+    )]
+    buyer_payment_account: Account<'info, TokenAccount>,
+
+    #[account(mut)]
+    buyer: Signer<'info>, // Bob
+
+    mint_of_token_user_send: Account<'info, Mint>, // Any registered payment mint
+
+    // Application level accounts
+    system_program: Program<'info, System>,
+    token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct Claim<'info> {
+    // Derived PDAs
+    #[account(
+         seeds=[b"presale_info".as_ref()],
+         bump
+     )]
+    presale_info: Account<'info, PreSaleDetails>,
+
+    #[account(
+         mut,
+         seeds=[b"token_vault".as_ref()],
+         bump
+     )]
+    token_vault: Account<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds=[b"purchase".as_ref(), buyer.key().as_ref()],
+        bump,
+    )]
+    purchase: Account<'info, Purchase>,
+
+    #[account(
+        init_if_needed,
+        payer = buyer,
+        associated_token::mint = mint_of_token_program_sent,
+        associated_token::authority = buyer,
+    )]
+    wallet_to_deposit_to: Account<'info, TokenAccount>, // Bob's DL token wallet (will be initialized if it did not exist)
+
+    #[account(mut)]
+    buyer: Signer<'info>, // Bob
+
     mint_of_token_program_sent: Account<'info, Mint>, // DL token
 
     // Application level accounts
@@ -558,12 +1371,278 @@ pub struct BuyTokens<'info> {
 pub struct PreSaleDetails {
     stage: u8,
     owner: Pubkey,
-    round_one_price: u64,
-    round_two_price: u64,
-    round_three_price: u64,
-    round_one_allocation_remaining: u64,
-    round_two_allocation_remaining: u64,
-    round_three_allocation_remaining: u64,
+    // `Pubkey::default()` when native purchases use `fallback_sol_price`
+    price_feed: Pubkey,
+    fallback_sol_price: u64,
+    max_price_age: i64,
+    min_round_contribution: u64,
+    max_round_contribution: u64,
+    min_wallet_contribution: u64,
+    max_wallet_contribution: u64,
+    vesting: VestingSchedule,
+    // All in USDC base units, SOL purchases included. A hard cap of 0 means no cap.
+    soft_cap: u64,
+    hard_cap: u64,
+    total_raised: u64,
+    // Lamports paid in native purchases, SPL payments are counted per `PaymentMint`
+    sol_raised: u64,
+    // Decimals of USDC, which prices and contributions in every payment mint are normalized to
+    quote_decimals: u8,
+    payment_mint_count: u8,
+    // Set when the sale hits its hard cap or sells out before the last round ends
+    ended_at: i64,
+    unsold_policy: UnsoldPolicy,
+    rounds: Vec<Round>,
+}
+
+impl PreSaleDetails {
+    pub const SPACE: usize = 8
+        + 1
+        + 32
+        + 32
+        + 8
+        + 8
+        + 8 * 4
+        + VestingSchedule::SIZE
+        + 8 * 4
+        + 1
+        + 1
+        + 8
+        + 1
+        + 4
+        + constants::MAX_ROUNDS * Round::SIZE;
+
+    fn stage(&self) -> Result<Stage> {
+        Stage::from(self.stage, self.rounds.len())
+    }
+
+    // The rounds in schedule order, with what is left of each one's allocation
+    pub fn rounds(&self) -> &[Round] {
+        &self.rounds
+    }
+
+    // Between 1 and `MAX_ROUNDS` rounds, each with a price and a non-empty window, and none
+    // of them overlapping the next
+    fn validate_rounds(&self) -> Result<()> {
+        if self.rounds.len() > constants::MAX_ROUNDS {
+            return Err(ErrorCode::TooManyRounds.into());
+        }
+        if self.rounds.is_empty()
+            || self
+                .rounds
+                .iter()
+                .any(|round| round.price == 0 || round.start_ts >= round.end_ts)
+            || self
+                .rounds
+                .windows(2)
+                .any(|pair| pair[0].end_ts > pair[1].start_ts)
+        {
+            return Err(ErrorCode::RoundScheduleInvalid.into());
+        }
+        Ok(())
+    }
+
+    // Takes what is left of round `idx`'s allocation as it is finalized. Under
+    // `UnsoldPolicy::Rollover` it moves into the next round and nothing is returned, otherwise
+    // the caller burns or returns what this gives back.
+    fn take_unsold(&mut self, idx: usize) -> u64 {
+        let unsold = std::mem::take(&mut self.rounds[idx].allocation_remaining);
+        if self.unsold_policy == UnsoldPolicy::Rollover {
+            if let Some(next) = self.rounds.get_mut(idx + 1) {
+                next.allocation_remaining = next.allocation_remaining.checked_add(unsold).unwrap();
+                return 0;
+            }
+        }
+        unsold
+    }
+
+    // The index of the round open for purchases at `now`, derived from the schedule alone so
+    // that a late `finalize_round` call never keeps a round open
+    fn active_round(&self, now: i64) -> Result<usize> {
+        if now < self.rounds[0].start_ts {
+            return Err(ErrorCode::PresaleNotStartedYet.into());
+        }
+        if now >= self.sale_end() {
+            return Err(ErrorCode::PresaleEnded.into());
+        }
+        self.rounds
+            .iter()
+            .position(|round| round.start_ts <= now && now < round.end_ts)
+            .ok_or_else(|| ErrorCode::RoundNotActive.into())
+    }
+
+    // Checks a wallet's contribution to the current round and to the whole sale, both
+    // including the purchase being made. The minimums are skipped for a fill trimmed to what
+    // was left of the sale.
+    fn check_contribution(
+        &self,
+        round_contribution: u64,
+        wallet_contribution: u64,
+        is_trimmed: bool,
+    ) -> Result<()> {
+        if !is_trimmed && round_contribution < self.min_round_contribution {
+            return Err(ErrorCode::BelowRoundMinimum.into());
+        }
+        if self.max_round_contribution != 0 && round_contribution > self.max_round_contribution {
+            return Err(ErrorCode::AboveRoundMaximum.into());
+        }
+        if !is_trimmed && wallet_contribution < self.min_wallet_contribution {
+            return Err(ErrorCode::BelowWalletMinimum.into());
+        }
+        if self.max_wallet_contribution != 0 && wallet_contribution > self.max_wallet_contribution {
+            return Err(ErrorCode::AboveWalletMaximum.into());
+        }
+        Ok(())
+    }
+
+    // The end of the last round, unless the sale ended early
+    fn sale_end(&self) -> i64 {
+        if self.ended_at != 0 {
+            self.ended_at
+        } else {
+            self.rounds[self.rounds.len() - 1].end_ts
+        }
+    }
+
+    // How much of `total` has vested at `now`. The TGE share unlocks when the sale ends, the
+    // rest vests linearly over `duration_seconds` once the cliff has passed.
+    fn vested_amount(&self, total: u64, now: i64) -> u64 {
+        let tge = self.sale_end();
+        if now < tge {
+            return 0;
+        }
+
+        let at_tge = (total as u128)
+            .checked_mul(self.vesting.tge_basis_points as u128)
+            .unwrap()
+            .checked_div(10000)
+            .unwrap() as u64;
+        let vesting_start = tge.saturating_add(self.vesting.cliff_seconds);
+        if now < vesting_start {
+            return at_tge;
+        }
+
+        let elapsed = now - vesting_start;
+        if elapsed >= self.vesting.duration_seconds {
+            return total;
+        }
+        let linear = ((total - at_tge) as u128)
+            .checked_mul(elapsed as u128)
+            .unwrap()
+            .checked_div(self.vesting.duration_seconds as u128)
+            .unwrap() as u64;
+        at_tge + linear
+    }
+
+    // The time after which `finalize_round` may move on from `stage`. Once the sale has ended
+    // early, every remaining round can be finalized at once.
+    fn stage_ends_at(&self, stage: Stage) -> i64 {
+        let ends_at = match stage {
+            Stage::PresaleNotStartedYet => self.rounds[0].start_ts,
+            Stage::Round(idx) => self.rounds[idx].end_ts,
+            Stage::PresaleEnded => self.rounds[self.rounds.len() - 1].end_ts,
+        };
+        ends_at.min(self.sale_end())
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default)]
+pub struct VestingSchedule {
+    // Share of a purchase released at TGE, in basis points
+    pub tge_basis_points: u16,
+    pub cliff_seconds: i64,
+    pub duration_seconds: i64,
+}
+
+impl VestingSchedule {
+    pub const SIZE: usize = 2 + 8 + 8;
+}
+
+// What happens to a round's allocation that is still unsold when the round is finalized
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UnsoldPolicy {
+    #[default]
+    Burn,
+    // Added to the next round's allocation, and burned after the last round
+    Rollover,
+    // Sent back to the admin's token wallet
+    ReturnToAdmin,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy)]
+pub struct RoundConfig {
+    pub price: u64,
+    pub allocation: u64,
+    pub start_ts: i64,
+    pub end_ts: i64,
+    // All zeros for a public round
+    pub merkle_root: [u8; 32],
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy)]
+pub struct Round {
+    pub price: u64,
+    pub allocation: u64,
+    pub allocation_remaining: u64,
+    pub start_ts: i64,
+    pub end_ts: i64,
+    pub merkle_root: [u8; 32],
+}
+
+impl Round {
+    pub const SIZE: usize = 8 * 5 + 32;
+}
+
+impl From<RoundConfig> for Round {
+    fn from(config: RoundConfig) -> Self {
+        Round {
+            price: config.price,
+            allocation: config.allocation,
+            allocation_remaining: config.allocation,
+            start_ts: config.start_ts,
+            end_ts: config.end_ts,
+            merkle_root: config.merkle_root,
+        }
+    }
+}
+
+// What one buyer has put into the sale and the tokens owed for it, per round (indexed like
+// `PreSaleDetails::rounds`) and in total. Contributions are in USDC base units, SOL purchases
+// included. `claimed` counts the vested tokens already sent out.
+#[account]
+pub struct Purchase {
+    buyer: Pubkey,
+    contributed: [u64; constants::MAX_ROUNDS],
+    tokens_received: [u64; constants::MAX_ROUNDS],
+    total_contributed: u64,
+    total_tokens_received: u64,
+    claimed: u64,
+    // What was actually paid in each asset, for refunds. `paid` is indexed by
+    // `PaymentMint::index`.
+    paid_lamports: u64,
+    paid: [u64; constants::MAX_PAYMENT_MINTS],
+}
+
+impl Purchase {
+    pub const SPACE: usize =
+        8 + 32 + 2 * 8 * constants::MAX_ROUNDS + 8 * 4 + 8 * constants::MAX_PAYMENT_MINTS;
+}
+
+// A mint the sale accepts as payment, with the vault its proceeds are kept in
+#[account]
+pub struct PaymentMint {
+    mint: Pubkey,
+    vault: Pubkey,
+    decimals: u8,
+    index: u8,
+    // `Pubkey::default()` when the mint is valued 1:1 with USDC
+    price_feed: Pubkey,
+    max_price_age: i64,
+    raised: u64,
+}
+
+impl PaymentMint {
+    pub const SPACE: usize = 8 + 32 + 32 + 1 + 1 + 32 + 8 + 8;
 }
 
 #[error_code]
@@ -578,4 +1657,173 @@ pub enum ErrorCode {
     PresaleNotStartedYet,
     #[msg("Remaining allocation is insufficient")]
     InsufficientAllocation,
+    #[msg("Round schedule is invalid")]
+    RoundScheduleInvalid,
+    #[msg("No round is open right now")]
+    RoundNotActive,
+    #[msg("Current round has not ended yet")]
+    RoundNotEnded,
+    #[msg("Presale can have at most 10 rounds")]
+    TooManyRounds,
+    #[msg("Rounds can only be added before the presale starts")]
+    PresaleAlreadyStarted,
+    #[msg("Price source is invalid")]
+    PriceSourceInvalid,
+    #[msg("SOL price is not configured")]
+    SolPriceNotConfigured,
+    #[msg("Price feed does not match the configured one")]
+    PriceFeedMismatch,
+    #[msg("Price feed account is invalid or not trading")]
+    PriceFeedInvalid,
+    #[msg("Price feed is stale")]
+    PriceStale,
+    #[msg("Price feed confidence interval is too wide")]
+    PriceConfidenceTooWide,
+    #[msg("Contribution limits are invalid")]
+    ContributionLimitsInvalid,
+    #[msg("Contribution is below the minimum per wallet for this round")]
+    BelowRoundMinimum,
+    #[msg("Contribution exceeds the maximum per wallet for this round")]
+    AboveRoundMaximum,
+    #[msg("Contribution is below the minimum per wallet")]
+    BelowWalletMinimum,
+    #[msg("Contribution exceeds the maximum per wallet")]
+    AboveWalletMaximum,
+    #[msg("Round does not exist")]
+    RoundNotFound,
+    #[msg("Round has already ended")]
+    RoundEnded,
+    #[msg("Wallet is not whitelisted for this round")]
+    NotWhitelisted,
+    #[msg("Contribution exceeds the whitelisted cap for this round")]
+    AboveWhitelistCap,
+    #[msg("Vesting schedule is invalid")]
+    VestingScheduleInvalid,
+    #[msg("No vested tokens to claim")]
+    NothingToClaim,
+    #[msg("Presale has not ended yet")]
+    PresaleNotEndedYet,
+    #[msg("Soft cap was reached, no refunds")]
+    SoftCapReached,
+    #[msg("Soft cap was not reached")]
+    SoftCapNotReached,
+    #[msg("Hard cap must not be below the soft cap")]
+    CapsInvalid,
+    #[msg("Presale accepts at most 4 payment mints")]
+    TooManyPaymentMints,
+    #[msg("Vault does not belong to the payment mint")]
+    PaymentVaultMismatch,
+    #[msg("Returning unsold tokens needs the admin's token wallet")]
+    AdminTokenWalletMissing,
+    #[msg("Arithmetic overflow")]
+    MathOverflow,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use solana_program::native_token::LAMPORTS_PER_SOL;
+
+    fn presale(unsold_policy: UnsoldPolicy) -> PreSaleDetails {
+        let round = |allocation, start_ts| Round {
+            price: 1_000_000_000,
+            allocation,
+            allocation_remaining: allocation,
+            start_ts,
+            end_ts: start_ts + 600,
+            merkle_root: [0; 32],
+        };
+        PreSaleDetails {
+            stage: 0,
+            owner: Pubkey::new_unique(),
+            price_feed: Pubkey::default(),
+            fallback_sol_price: 0,
+            max_price_age: 0,
+            min_round_contribution: 0,
+            max_round_contribution: 0,
+            min_wallet_contribution: 0,
+            max_wallet_contribution: 0,
+            vesting: VestingSchedule::default(),
+            soft_cap: 0,
+            hard_cap: 0,
+            total_raised: 0,
+            sol_raised: 0,
+            quote_decimals: 6,
+            payment_mint_count: 1,
+            ended_at: 0,
+            unsold_policy,
+            rounds: vec![round(1_000, 0), round(2_000, 600), round(3_000, 1_200)],
+        }
+    }
+
+    #[test]
+    fn rollover_carries_unsold_tokens_into_the_last_round_and_then_out() {
+        let mut info = presale(UnsoldPolicy::Rollover);
+        info.rounds[0].allocation_remaining = 400;
+
+        assert_eq!(info.take_unsold(0), 0);
+        assert_eq!(info.rounds[0].allocation_remaining, 0);
+        assert_eq!(info.rounds[1].allocation_remaining, 2_400);
+
+        assert_eq!(info.take_unsold(1), 0);
+        assert_eq!(info.rounds[2].allocation_remaining, 5_400);
+
+        // The last round has nowhere to roll over to, `finalize_round` burns it all
+        assert_eq!(info.take_unsold(2), 5_400);
+        assert!(info
+            .rounds
+            .iter()
+            .all(|round| round.allocation_remaining == 0));
+    }
+
+    #[test]
+    fn a_trimmed_fill_skips_the_minimums_and_is_charged_rounded_up() {
+        let mut info = presale(UnsoldPolicy::Burn);
+        info.min_round_contribution = 100;
+        info.min_wallet_contribution = 100;
+        assert!(info.check_contribution(40, 40, false).is_err());
+        assert!(info.check_contribution(40, 40, true).is_ok());
+
+        // 10 USDC at $3 a SOL is 3.333333333 SOL, and the buyer pays the last lamport
+        assert_eq!(mul_div(10, LAMPORTS_PER_SOL, 3).unwrap(), 3_333_333_333);
+        assert_eq!(mul_div_ceil(10, LAMPORTS_PER_SOL, 3).unwrap(), 3_333_333_334);
+        assert!(mul_div(u64::MAX, 2, 1).is_err());
+        assert!(mul_div_ceil(1, 1, 0).is_err());
+    }
+
+    fn feed(price: i64, expo: i32) -> Vec<u8> {
+        let mut data = vec![0; 240];
+        data[0..4].copy_from_slice(&0xa1b2c3d4u32.to_le_bytes());
+        data[4..8].copy_from_slice(&2u32.to_le_bytes());
+        data[8..12].copy_from_slice(&3u32.to_le_bytes());
+        data[20..24].copy_from_slice(&expo.to_le_bytes());
+        data[208..216].copy_from_slice(&price.to_le_bytes());
+        data[224..228].copy_from_slice(&1u32.to_le_bytes());
+        data
+    }
+
+    #[test]
+    fn feeds_with_an_out_of_range_exponent_are_rejected() {
+        // $150.00000000 in 6 decimals
+        assert_eq!(
+            oracle::price(&feed(15_000_000_000, -8), 0, 60, 6).unwrap(),
+            150_000_000
+        );
+        for expo in [-40, 19, i32::MAX] {
+            assert!(oracle::load(&feed(15_000_000_000, expo)).is_err());
+        }
+        assert!(oracle::price(&feed(i64::MAX, 18), 0, 60, 6).is_err());
+        assert!(oracle::price(&feed(15_000_000_000, -8), 0, 60, u8::MAX).is_err());
+    }
+
+    #[test]
+    fn other_policies_settle_each_round_on_its_own() {
+        for policy in [UnsoldPolicy::Burn, UnsoldPolicy::ReturnToAdmin] {
+            let mut info = presale(policy);
+            assert_eq!(info.take_unsold(0), 1_000);
+            assert_eq!(info.rounds[1].allocation_remaining, 2_000);
+            assert_eq!(info.take_unsold(1), 2_000);
+            assert_eq!(info.take_unsold(2), 3_000);
+        }
+    }
 }
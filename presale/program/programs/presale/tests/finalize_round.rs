@@ -0,0 +1,278 @@
+use anchor_lang::{
+    prelude::{AccountInfo, Clock, Pubkey, Rent},
+    solana_program::{
+        entrypoint::ProgramResult, instruction::Instruction, program_pack::Pack, system_program,
+    },
+    AccountDeserialize, InstructionData, ToAccountMetas,
+};
+use anchor_spl::token::spl_token;
+use presale::{PreSaleDetails, RoundConfig, UnsoldPolicy, VestingSchedule};
+use solana_program_test::{processor, ProgramTest, ProgramTestContext};
+use solana_sdk::{
+    account::Account,
+    signature::{Keypair, Signer},
+    transaction::{Transaction, TransactionError},
+};
+
+const ROUND_LENGTH: i64 = 600;
+const ALLOCATIONS: [u64; 3] = [1_000_000_000_000, 2_000_000_000_000, 3_000_000_000_000];
+
+struct Sale {
+    admin: Keypair,
+    dl_mint: Pubkey,
+    admin_token_wallet: Pubkey,
+    start_ts: i64,
+}
+
+// `presale::entry` wants the slice to live as long as the accounts themselves. The clones share
+// the accounts' data and lamports, and leaking them for the rest of the test gives that lifetime.
+fn process_instruction(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    data: &[u8],
+) -> ProgramResult {
+    let accounts = Box::leak(accounts.to_vec().into_boxed_slice());
+    presale::entry(program_id, accounts, data)
+}
+
+fn pda(seeds: &[&[u8]]) -> Pubkey {
+    Pubkey::find_program_address(seeds, &presale::ID).0
+}
+
+fn instruction(accounts: impl ToAccountMetas, data: impl InstructionData) -> Instruction {
+    Instruction {
+        program_id: presale::ID,
+        accounts: accounts.to_account_metas(None),
+        data: data.data(),
+    }
+}
+
+fn packed_account<T: Pack>(state: T) -> Account {
+    let mut data = vec![0; T::LEN];
+    T::pack(state, &mut data).unwrap();
+    Account {
+        lamports: Rent::default().minimum_balance(T::LEN),
+        data,
+        owner: spl_token::ID,
+        ..Account::default()
+    }
+}
+
+async fn process(
+    context: &mut ProgramTestContext,
+    instruction: Instruction,
+    signers: &[&Keypair],
+) -> Result<(), TransactionError> {
+    let blockhash = context.get_new_latest_blockhash().await.unwrap();
+    let mut all_signers = vec![&context.payer];
+    all_signers.extend_from_slice(signers);
+    let transaction = Transaction::new_signed_with_payer(
+        &[instruction],
+        Some(&context.payer.pubkey()),
+        &all_signers,
+        blockhash,
+    );
+    context
+        .banks_client
+        .process_transaction(transaction)
+        .await
+        .map_err(|error| error.unwrap())
+}
+
+async fn token_balance(context: &mut ProgramTestContext, address: &Pubkey) -> u64 {
+    context
+        .banks_client
+        .get_packed_account_data::<spl_token::state::Account>(*address)
+        .await
+        .unwrap()
+        .amount
+}
+
+async fn presale_info(context: &mut ProgramTestContext) -> PreSaleDetails {
+    let account = context
+        .banks_client
+        .get_account(pda(&[b"presale_info"]))
+        .await
+        .unwrap()
+        .unwrap();
+    PreSaleDetails::try_deserialize(&mut account.data.as_slice()).unwrap()
+}
+
+async fn warp_to(context: &mut ProgramTestContext, unix_timestamp: i64) {
+    let mut clock = context.banks_client.get_sysvar::<Clock>().await.unwrap();
+    clock.unix_timestamp = unix_timestamp;
+    context.set_sysvar(&clock);
+}
+
+// Starts a three round sale under `unsold_policy`, funded with every round's allocation from
+// the admin's token wallet, with its first round open and nothing sold
+async fn setup(unsold_policy: UnsoldPolicy) -> (ProgramTestContext, Sale) {
+    let mut program_test =
+        ProgramTest::new("presale", presale::ID, processor!(process_instruction));
+    let admin = Keypair::new();
+    let usdc_mint = Pubkey::new_unique();
+    let dl_mint = Pubkey::new_unique();
+    let admin_token_wallet = Pubkey::new_unique();
+    program_test.add_account(
+        admin.pubkey(),
+        Account::new(5_000_000_000, 0, &system_program::ID),
+    );
+    for (mint, decimals) in [(usdc_mint, 6), (dl_mint, 9)] {
+        program_test.add_account(
+            mint,
+            packed_account(spl_token::state::Mint {
+                mint_authority: Some(admin.pubkey()).into(),
+                supply: ALLOCATIONS.iter().sum(),
+                decimals,
+                is_initialized: true,
+                ..Default::default()
+            }),
+        );
+    }
+    program_test.add_account(
+        admin_token_wallet,
+        packed_account(spl_token::state::Account {
+            mint: dl_mint,
+            owner: admin.pubkey(),
+            amount: ALLOCATIONS.iter().sum(),
+            state: spl_token::state::AccountState::Initialized,
+            ..Default::default()
+        }),
+    );
+    let mut context = program_test.start_with_context().await;
+
+    let start_ts = context
+        .banks_client
+        .get_sysvar::<Clock>()
+        .await
+        .unwrap()
+        .unix_timestamp;
+    let rounds = ALLOCATIONS
+        .iter()
+        .enumerate()
+        .map(|(idx, &allocation)| RoundConfig {
+            price: (idx as u64 + 1) * 1_000_000_000,
+            allocation,
+            start_ts: start_ts + idx as i64 * ROUND_LENGTH,
+            end_ts: start_ts + (idx as i64 + 1) * ROUND_LENGTH,
+            merkle_root: [0; 32],
+        })
+        .collect();
+    process(
+        &mut context,
+        instruction(
+            presale::accounts::InitializePresale {
+                presale_info: pda(&[b"presale_info"]),
+                usdc_vault: pda(&[b"usdc_vault"]),
+                sol_vault: pda(&[b"sol_vault"]),
+                payment_mint: pda(&[b"payment_mint", usdc_mint.as_ref()]),
+                mint_of_token_user_send: usdc_mint,
+                admin: admin.pubkey(),
+                system_program: system_program::ID,
+                token_program: spl_token::ID,
+            },
+            presale::instruction::Initialize {
+                rounds,
+                vesting: VestingSchedule::default(),
+                soft_cap: 0,
+                hard_cap: 0,
+                unsold_policy,
+            },
+        ),
+        &[&admin],
+    )
+    .await
+    .unwrap();
+    process(
+        &mut context,
+        instruction(
+            presale::accounts::InitializeTokenVault {
+                presale_info: pda(&[b"presale_info"]),
+                token_vault: pda(&[b"token_vault"]),
+                mint_of_token_program_sent: dl_mint,
+                wallet_of_depositor: admin_token_wallet,
+                admin: admin.pubkey(),
+                system_program: system_program::ID,
+                token_program: spl_token::ID,
+            },
+            presale::instruction::FundPda {},
+        ),
+        &[&admin],
+    )
+    .await
+    .unwrap();
+
+    let sale = Sale {
+        admin,
+        dl_mint,
+        admin_token_wallet,
+        start_ts,
+    };
+    (context, sale)
+}
+
+fn finalize_round(sale: &Sale, admin_token_wallet: Option<Pubkey>) -> Instruction {
+    instruction(
+        presale::accounts::FinalizeRound {
+            presale_info: pda(&[b"presale_info"]),
+            token_vault: pda(&[b"token_vault"]),
+            mint_of_token_program_sent: sale.dl_mint,
+            admin: sale.admin.pubkey(),
+            admin_token_wallet,
+            token_program: spl_token::ID,
+        },
+        presale::instruction::FinalizeRound {},
+    )
+}
+
+#[tokio::test]
+async fn rollover_moves_an_unsold_round_into_the_next_one() {
+    let (mut context, sale) = setup(UnsoldPolicy::Rollover).await;
+    let vault = pda(&[b"token_vault"]);
+
+    warp_to(&mut context, sale.start_ts + ROUND_LENGTH).await;
+    process(&mut context, finalize_round(&sale, None), &[])
+        .await
+        .unwrap();
+
+    let info = presale_info(&mut context).await;
+    assert_eq!(info.rounds()[0].allocation_remaining, 0);
+    assert_eq!(
+        info.rounds()[1].allocation_remaining,
+        ALLOCATIONS[0] + ALLOCATIONS[1]
+    );
+    assert_eq!(info.rounds()[2].allocation_remaining, ALLOCATIONS[2]);
+    // Nothing left the vault, the tokens are still for sale
+    assert_eq!(
+        token_balance(&mut context, &vault).await,
+        ALLOCATIONS.iter().sum::<u64>()
+    );
+}
+
+#[tokio::test]
+async fn return_to_admin_sends_an_unsold_round_back_to_the_admin() {
+    let (mut context, sale) = setup(UnsoldPolicy::ReturnToAdmin).await;
+    let vault = pda(&[b"token_vault"]);
+    assert_eq!(token_balance(&mut context, &sale.admin_token_wallet).await, 0);
+
+    warp_to(&mut context, sale.start_ts + ROUND_LENGTH).await;
+    process(
+        &mut context,
+        finalize_round(&sale, Some(sale.admin_token_wallet)),
+        &[],
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(
+        token_balance(&mut context, &sale.admin_token_wallet).await,
+        ALLOCATIONS[0]
+    );
+    assert_eq!(
+        token_balance(&mut context, &vault).await,
+        ALLOCATIONS[1] + ALLOCATIONS[2]
+    );
+    let info = presale_info(&mut context).await;
+    assert_eq!(info.rounds()[0].allocation_remaining, 0);
+    assert_eq!(info.rounds()[1].allocation_remaining, ALLOCATIONS[1]);
+}
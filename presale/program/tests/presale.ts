@@ -9,17 +9,32 @@ import {
 } from "@solana/spl-token";
 import { Presale } from "../target/types/presale";
 import { assert } from "chai";
+import { createHash } from "crypto";
 
 interface PDAParameters {
   usdcVault: anchor.web3.PublicKey;
   dlVault: anchor.web3.PublicKey;
+  solVault: anchor.web3.PublicKey;
   presalePDA: anchor.web3.PublicKey;
-  firstAllocation: anchor.BN;
-  secondAllocation: anchor.BN;
-  thirdAllocation: anchor.BN;
-  roundOnePrice: anchor.BN;
-  roundTwoPrice: anchor.BN;
-  roundThreePrice: anchor.BN;
+  rounds: RoundConfig[];
+  vesting: VestingSchedule;
+  softCap: anchor.BN;
+  hardCap: anchor.BN;
+  unsoldPolicy: object;
+}
+
+interface VestingSchedule {
+  tgeBasisPoints: number;
+  cliffSeconds: anchor.BN;
+  durationSeconds: anchor.BN;
+}
+
+interface RoundConfig {
+  price: anchor.BN;
+  allocation: anchor.BN;
+  startTs: anchor.BN;
+  endTs: anchor.BN;
+  merkleRoot: number[];
 }
 
 describe("presale", () => {
@@ -39,18 +54,29 @@ describe("presale", () => {
   let bobUSDCWallet: anchor.web3.PublicKey;
   let bob: anchor.web3.Keypair;
   let pda: PDAParameters;
+  // Loaded from tests/fixtures/sol_usd_price.json by the local validator
+  const solUsdFeed = new PublicKey(
+    "4bYJkKcrSjn8ryeFQ344BZDgGetFkAxXV2CnuBRghaAo"
+  );
 
   const getPdaParams = async (
     connection: anchor.web3.Connection,
     mint: anchor.web3.PublicKey
   ): Promise<PDAParameters> => {
-    const firstAllocation = new anchor.BN(1000000000000);
-    const secondAllocation = new anchor.BN(2000000000000);
-    const thirdAllocation = new anchor.BN(3000000000000);
-
-    const roundOnePrice = new anchor.BN(1000000000);
-    const roundTwoPrice = new anchor.BN(2000000000);
-    const roundThreePrice = new anchor.BN(3000000000);
+    // Round one opens right away, the later rounds follow back to back
+    const now = Math.floor(Date.now() / 1000);
+    const round = (
+      price: number,
+      allocation: number,
+      start: number,
+      end: number
+    ): RoundConfig => ({
+      price: new anchor.BN(price),
+      allocation: new anchor.BN(allocation),
+      startTs: new anchor.BN(start),
+      endTs: new anchor.BN(end),
+      merkleRoot: Array(32).fill(0),
+    });
 
     let [presalePDA] = PublicKey.findProgramAddressSync(
       [Buffer.from("presale_info")],
@@ -67,16 +93,32 @@ describe("presale", () => {
       program.programId
     );
 
+    let [solVault] = PublicKey.findProgramAddressSync(
+      [Buffer.from("sol_vault")],
+      program.programId
+    );
+
     return {
-      firstAllocation: firstAllocation,
-      secondAllocation: secondAllocation,
-      thirdAllocation: thirdAllocation,
       presalePDA: presalePDA,
       usdcVault: usdcVault,
       dlVault: dlVault,
-      roundOnePrice: roundOnePrice,
-      roundTwoPrice: roundTwoPrice,
-      roundThreePrice: roundThreePrice,
+      solVault: solVault,
+      rounds: [
+        round(1000000000, 1000000000000, now - 60, now + 600),
+        round(2000000000, 2000000000000, now + 600, now + 1200),
+        round(3000000000, 3000000000000, now + 1200, now + 1800),
+      ],
+      // 20% at TGE, the rest over 30 days after a 7 day cliff
+      vesting: {
+        tgeBasisPoints: 2000,
+        cliffSeconds: new anchor.BN(7 * 24 * 60 * 60),
+        durationSeconds: new anchor.BN(30 * 24 * 60 * 60),
+      },
+      // The sale succeeds at 10 USDC and sells out at 15 USDC
+      softCap: new anchor.BN(10000000000),
+      hardCap: new anchor.BN(15000000000),
+      // Unsold tokens go back to the admin instead of being burned
+      unsoldPolicy: { returnToAdmin: {} },
     };
   };
 
@@ -157,6 +199,26 @@ describe("presale", () => {
     return [user, userAssociatedTokenAccount?.address];
   };
 
+  const purchaseAddress = (buyer: anchor.web3.PublicKey) =>
+    PublicKey.findProgramAddressSync(
+      [Buffer.from("purchase"), buyer.toBuffer()],
+      program.programId
+    )[0];
+
+  const paymentMintAddress = (mint: anchor.web3.PublicKey) =>
+    PublicKey.findProgramAddressSync(
+      [Buffer.from("payment_mint"), mint.toBuffer()],
+      program.programId
+    )[0];
+
+  // Whitelist leaves and nodes, hashed the way the program does
+  const sha256 = (...parts: Buffer[]) =>
+    createHash("sha256").update(Buffer.concat(parts)).digest();
+  const whitelistLeaf = (wallet: anchor.web3.PublicKey, cap: anchor.BN) =>
+    sha256(wallet.toBuffer(), cap.toArrayLike(Buffer, "le", 8));
+  const whitelistNode = (a: Buffer, b: Buffer) =>
+    Buffer.compare(a, b) <= 0 ? sha256(a, b) : sha256(b, a);
+
   const readAccount = async (
     accountPublicKey: anchor.web3.PublicKey,
     provider: anchor.Provider
@@ -192,16 +254,17 @@ describe("presale", () => {
 
     const tx = await program.methods
       .initialize(
-        pda.firstAllocation,
-        pda.secondAllocation,
-        pda.thirdAllocation,
-        pda.roundOnePrice,
-        pda.roundTwoPrice,
-        pda.roundThreePrice
+        pda.rounds,
+        pda.vesting,
+        pda.softCap,
+        pda.hardCap,
+        pda.unsoldPolicy
       )
       .accounts({
         presaleInfo: pda.presalePDA,
         usdcVault: pda.usdcVault,
+        solVault: pda.solVault,
+        paymentMint: paymentMintAddress(usdcAddress),
         admin: admin.publicKey,
         mintOfTokenUserSend: usdcAddress,
         systemProgram: anchor.web3.SystemProgram.programId,
@@ -228,6 +291,38 @@ describe("presale", () => {
     );
   });
 
+  it("The presale cannot be initialized a second time", async () => {
+    try {
+      await program.methods
+        .initialize(
+          pda.rounds,
+          pda.vesting,
+          pda.softCap,
+          pda.hardCap,
+          pda.unsoldPolicy
+        )
+        .accounts({
+          presaleInfo: pda.presalePDA,
+          usdcVault: pda.usdcVault,
+          solVault: pda.solVault,
+          paymentMint: paymentMintAddress(usdcAddress),
+          admin: bob.publicKey,
+          mintOfTokenUserSend: usdcAddress,
+          systemProgram: anchor.web3.SystemProgram.programId,
+          tokenProgram: spl.TOKEN_PROGRAM_ID,
+        })
+        .signers([bob])
+        .rpc();
+      return assert.fail("The presale is already initialized");
+    } catch (e) {
+      // The system program refuses to create an account that is already in use
+      assert.include(e.message, "custom program error: 0x0");
+    }
+
+    const info = await program.account.preSaleDetails.fetch(pda.presalePDA);
+    assert.ok(info.owner.equals(admin.publicKey));
+  });
+
   it("Deposit DL tokens", async () => {
     const [, adminBalancePre] = await readAccount(adminDLWallet, provider);
 
@@ -260,17 +355,17 @@ describe("presale", () => {
     assert.equal(dlVaultBalancePost, "6000000000000");
   });
 
-  it("Starting Round One", async () => {
+  it("Finalizing moves the presale into round one once it has started", async () => {
     const tx = await program.methods
-      .startNextRound()
+      .finalizeRound()
       .accounts({
         presaleInfo: pda.presalePDA,
         tokenVault: pda.dlVault,
         admin: admin.publicKey,
         mintOfTokenProgramSent: dlAddress,
+        adminTokenWallet: null,
         tokenProgram: spl.TOKEN_PROGRAM_ID,
       })
-      .signers([admin])
       .rpc();
     console.log(`Round One has been started successfully`, tx);
 
@@ -279,337 +374,526 @@ describe("presale", () => {
 
     assert.equal(dlVaultBalancePost, "6000000000000");
 
-    console.log(
-      "Presale Stage",
-      (
-        await program.account.preSaleDetails.fetch(pda.presalePDA)
-      ).stage.toString()
+    const stage = (await program.account.preSaleDetails.fetch(pda.presalePDA))
+      .stage;
+    console.log("Presale Stage", stage.toString());
+    assert.equal(stage, 1);
+  });
+
+  it("Round one cannot be finalized before it ends", async () => {
+    try {
+      await program.methods
+        .finalizeRound()
+        .accounts({
+          presaleInfo: pda.presalePDA,
+          tokenVault: pda.dlVault,
+          admin: admin.publicKey,
+          mintOfTokenProgramSent: dlAddress,
+          adminTokenWallet: null,
+          tokenProgram: spl.TOKEN_PROGRAM_ID,
+        })
+        .rpc();
+      return assert.fail("Round one is still open");
+    } catch (e) {
+      assert.include(e.message, "RoundNotEnded");
+    }
+
+    // Nothing was burned
+    const [, dlVaultBalancePost] = await readAccount(pda.dlVault, provider);
+    assert.equal(dlVaultBalancePost, "6000000000000");
+  });
+
+  it("Rounds cannot be appended once round one is open", async () => {
+    const now = Math.floor(Date.now() / 1000);
+    try {
+      await program.methods
+        .addRound({
+          price: new anchor.BN(4000000000),
+          allocation: new anchor.BN(1000000000000),
+          startTs: new anchor.BN(now + 1800),
+          endTs: new anchor.BN(now + 2400),
+          merkleRoot: Array(32).fill(0),
+        })
+        .accounts({
+          presaleInfo: pda.presalePDA,
+          admin: admin.publicKey,
+        })
+        .signers([admin])
+        .rpc();
+      return assert.fail("The presale has already started");
+    } catch (e) {
+      assert.include(e.message, "PresaleAlreadyStarted");
+    }
+
+    const info = await program.account.preSaleDetails.fetch(pda.presalePDA);
+    assert.equal(info.rounds.length, 3);
+  });
+
+  it("Carol buys with USDT, a registered 6 decimal payment mint", async () => {
+    const payer = provider.wallet as anchor.Wallet;
+    const usdtAddress = await createMint(
+      provider.connection,
+      payer.payer,
+      payer.publicKey,
+      payer.publicKey,
+      6
+    );
+    const [paymentVault] = PublicKey.findProgramAddressSync(
+      [Buffer.from("payment_vault"), usdtAddress.toBuffer()],
+      program.programId
+    );
+
+    // Pegged 1:1 with USDC, so no price feed
+    await program.methods
+      .addPaymentMint(null, new anchor.BN(0))
+      .accounts({
+        presaleInfo: pda.presalePDA,
+        paymentMint: paymentMintAddress(usdtAddress),
+        paymentVault,
+        mintOfTokenUserSend: usdtAddress,
+        admin: admin.publicKey,
+        systemProgram: anchor.web3.SystemProgram.programId,
+        tokenProgram: spl.TOKEN_PROGRAM_ID,
+      })
+      .signers([admin])
+      .rpc();
+
+    // 1 USDT is worth 1 USDC, which buys 1 DL in round one
+    const [carol, carolUSDTWallet] = await createUserAndAssociatedWallet(
+      provider.connection,
+      usdtAddress
+    );
+    await program.methods
+      .buyTokens(new anchor.BN(1000000), false, new anchor.BN(0), [])
+      .accounts({
+        presaleInfo: pda.presalePDA,
+        paymentMint: paymentMintAddress(usdtAddress),
+        paymentVault,
+        solVault: pda.solVault,
+        purchase: purchaseAddress(carol.publicKey),
+        buyerPaymentAccount: carolUSDTWallet,
+        buyer: carol.publicKey,
+        mintOfTokenUserSend: usdtAddress,
+        priceFeed: null,
+        tokenProgram: spl.TOKEN_PROGRAM_ID,
+        systemProgram: anchor.web3.SystemProgram.programId,
+      })
+      .signers([carol])
+      .rpc();
+
+    // Contributions are counted in USDC, payments in the mint they were made in
+    const purchase = await program.account.purchase.fetch(
+      purchaseAddress(carol.publicKey)
+    );
+    assert.equal(purchase.contributed[0].toString(), "1000000000");
+    assert.equal(purchase.tokensReceived[0].toString(), "1000000000");
+    assert.equal(purchase.paid[1].toString(), "1000000");
+    const [, vaultBalance] = await readAccount(paymentVault, provider);
+    assert.equal(vaultBalance, "1000000");
+  });
+
+  it("Admin sets a fallback SOL price while no feed is configured", async () => {
+    await program.methods
+      .setPriceSource(null, new anchor.BN(168000000000), new anchor.BN(0))
+      .accounts({
+        presaleInfo: pda.presalePDA,
+        admin: admin.publicKey,
+      })
+      .signers([admin])
+      .rpc();
+
+    const info = await program.account.preSaleDetails.fetch(pda.presalePDA);
+    assert.isTrue(info.priceFeed.equals(PublicKey.default));
+    assert.equal(info.fallbackSolPrice.toString(), "168000000000");
+  });
+
+  it("Bob Buying Tokens with SOL at the feed price", async function () {
+    // The crafted feed only exists on a local validator
+    if (!(await provider.connection.getAccountInfo(solUsdFeed))) {
+      this.skip();
+    }
+
+    // The fixture has a fixed publish time, so allow any age here
+    await program.methods
+      .setPriceSource(
+        solUsdFeed,
+        new anchor.BN(0),
+        new anchor.BN(10 * 365 * 24 * 60 * 60)
+      )
+      .accounts({
+        presaleInfo: pda.presalePDA,
+        admin: admin.publicKey,
+      })
+      .signers([admin])
+      .rpc();
+
+    // 0.01 SOL at $150 is 1.5 USDC, which buys 1.5 DL in round one. The tokens stay in the
+    // vault until they vest.
+    await program.methods
+      .buyTokens(new anchor.BN(10000000), true, new anchor.BN(0), [])
+      .accounts({
+        presaleInfo: pda.presalePDA,
+        paymentMint: paymentMintAddress(usdcAddress),
+        paymentVault: pda.usdcVault,
+        solVault: pda.solVault,
+        buyerPaymentAccount: bobUSDCWallet,
+        buyer: bob.publicKey,
+        mintOfTokenUserSend: usdcAddress,
+        purchase: purchaseAddress(bob.publicKey),
+        priceFeed: solUsdFeed,
+        tokenProgram: spl.TOKEN_PROGRAM_ID,
+        systemProgram: anchor.web3.SystemProgram.programId,
+      })
+      .signers([bob])
+      .rpc();
+
+    const purchase = await program.account.purchase.fetch(
+      purchaseAddress(bob.publicKey)
+    );
+    assert.equal(purchase.tokensReceived[0].toString(), "1500000000");
+  });
+
+  it("Bob's USDC purchases are held to the per-wallet limits", async () => {
+    // 1 to 20 USDC per round, at most 30 USDC overall
+    await program.methods
+      .setContributionLimits(
+        new anchor.BN(1000000000),
+        new anchor.BN(20000000000),
+        new anchor.BN(0),
+        new anchor.BN(30000000000)
+      )
+      .accounts({
+        presaleInfo: pda.presalePDA,
+        admin: admin.publicKey,
+      })
+      .signers([admin])
+      .rpc();
+
+    const purchase = purchaseAddress(bob.publicKey);
+    const buy = (amount: number) =>
+      program.methods
+        .buyTokens(new anchor.BN(amount), false, new anchor.BN(0), [])
+        .accounts({
+          presaleInfo: pda.presalePDA,
+          paymentMint: paymentMintAddress(usdcAddress),
+          paymentVault: pda.usdcVault,
+          solVault: pda.solVault,
+          purchase,
+          buyerPaymentAccount: bobUSDCWallet,
+          buyer: bob.publicKey,
+          mintOfTokenUserSend: usdcAddress,
+          priceFeed: null,
+          tokenProgram: spl.TOKEN_PROGRAM_ID,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .signers([bob])
+        .rpc();
+
+    try {
+      await buy(500000000);
+      return assert.fail("Half a USDC is below the round minimum");
+    } catch (e) {
+      assert.include(e.message, "BelowRoundMinimum");
+    }
+
+    const before = (await program.account.purchase.fetchNullable(purchase))
+      ?.contributed[0] ?? new anchor.BN(0);
+    await buy(10000000000);
+    const after = await program.account.purchase.fetch(purchase);
+    assert.equal(
+      after.contributed[0].sub(before).toString(),
+      "10000000000"
     );
+    assert.isTrue(after.buyer.equals(bob.publicKey));
+
+    try {
+      await buy(15000000000);
+      return assert.fail("25 USDC is above the round maximum");
+    } catch (e) {
+      assert.include(e.message, "AboveRoundMaximum");
+    }
   });
 
-  // it("Bob Buying Tokens from Round One", async () => {
-  //   const [, bobUSDCBalancePre] = await readAccount(bobUSDCWallet, provider);
-
-  //   assert.equal(bobUSDCBalancePre, "7000000000000");
-
-  //   // Create a token account for Bob.
-  //   const bobDLWallet = await spl.getAssociatedTokenAddress(
-  //     dlAddress,
-  //     bob.publicKey,
-  //     false,
-  //     spl.TOKEN_PROGRAM_ID,
-  //     spl.ASSOCIATED_TOKEN_PROGRAM_ID
-  //   );
-  //   console.log("Bob Associated Account", bobDLWallet);
-
-  //   const tx = await program.methods
-  //     .buyTokens(new anchor.BN(10000000000), false)
-  //     .accounts({
-  //       presaleInfo: pda.presalePDA,
-  //       tokenVault: pda.dlVault,
-  //       usdcVault: pda.usdcVault,
-  //       walletToDepositTo: bobDLWallet,
-  //       buyerUsdcAccount: bobUSDCWallet,
-  //       buyer: bob.publicKey,
-  //       mintOfTokenProgramSent: dlAddress,
-  //       mintOfTokenUserSend: usdcAddress,
-  //       tokenProgram: spl.TOKEN_PROGRAM_ID,
-  //       systemProgram: anchor.web3.SystemProgram.programId,
-  //       associatedTokenProgram: spl.ASSOCIATED_TOKEN_PROGRAM_ID,
-  //     })
-  //     .signers([bob])
-  //     .rpc();
-  //   console.log(`Round One has been started successfully`, tx);
-
-  //   const [, dlBobBalancePost] = await readAccount(bobDLWallet, provider);
-  //   console.log("Bob DL Balance: " + dlBobBalancePost);
-
-  //   assert.equal(dlBobBalancePost, "10000000000");
-
-  //   const [, dlVaultBalancePost] = await readAccount(pda.dlVault, provider);
-  //   console.log("Vault DL Balance: " + dlVaultBalancePost);
-
-  //   assert.equal(dlVaultBalancePost, "5990000000000");
-
-  //   const [, usdcVaultBalancePost] = await readAccount(pda.usdcVault, provider);
-  //   console.log("Vault USDC Balance: " + usdcVaultBalancePost);
-
-  //   assert.equal(usdcVaultBalancePost, "10000000000");
-  // });
-
-  // it("Starting Round Two", async () => {
-  //   const tx = await program.methods
-  //     .startNextRound()
-  //     .accounts({
-  //       presaleInfo: pda.presalePDA,
-  //       tokenVault: pda.dlVault,
-  //       admin: admin.publicKey,
-  //       mintOfTokenProgramSent: dlAddress,
-  //       tokenProgram: spl.TOKEN_PROGRAM_ID,
-  //     })
-  //     .signers([admin])
-  //     .rpc();
-
-  //   console.log(`Round two has been started successfully`, tx);
-
-  //   const [, dlVaultBalancePost] = await readAccount(pda.dlVault, provider);
-  //   console.log("Vault Balance: " + dlVaultBalancePost);
-
-  //   assert.equal(dlVaultBalancePost, "5000000000000");
-
-  //   console.log(
-  //     "Presale Stage",
-  //     (
-  //       await program.account.preSaleDetails.fetch(pda.presalePDA)
-  //     ).stage.toString()
-  //   );
-  // });
-
-  // it("Bob Buying Tokens from Round Two", async () => {
-  //   const [, bobUSDCBalancePre] = await readAccount(bobUSDCWallet, provider);
-
-  //   assert.equal(bobUSDCBalancePre, "6990000000000");
-
-  //   // Create a token account for Bob.
-  //   const bobDLWallet = await spl.getAssociatedTokenAddress(
-  //     dlAddress,
-  //     bob.publicKey,
-  //     false,
-  //     spl.TOKEN_PROGRAM_ID,
-  //     spl.ASSOCIATED_TOKEN_PROGRAM_ID
-  //   );
-
-  //   console.log("Bob Associated Account", bobDLWallet);
-  //   const tx = await program.methods
-  //     .buyTokens(new anchor.BN(10000000000), false)
-  //     .accounts({
-  //       presaleInfo: pda.presalePDA,
-  //       tokenVault: pda.dlVault,
-  //       usdcVault: pda.usdcVault,
-  //       walletToDepositTo: bobDLWallet,
-  //       buyerUsdcAccount: bobUSDCWallet,
-  //       buyer: bob.publicKey,
-  //       mintOfTokenProgramSent: dlAddress,
-  //       mintOfTokenUserSend: usdcAddress,
-  //       tokenProgram: spl.TOKEN_PROGRAM_ID,
-  //       systemProgram: anchor.web3.SystemProgram.programId,
-  //       associatedTokenProgram: spl.ASSOCIATED_TOKEN_PROGRAM_ID,
-  //     })
-  //     .signers([bob])
-  //     .rpc();
-
-  //   console.log(`Round Two has been started successfully`, tx);
-
-  //   const [, dlBobBalancePost] = await readAccount(bobDLWallet, provider);
-  //   console.log("Bob DL Balance: " + dlBobBalancePost);
-
-  //   assert.equal(dlBobBalancePost, "15000000000");
-
-  //   const [, dlVaultBalancePost] = await readAccount(pda.dlVault, provider);
-  //   console.log("Vault DL Balance: " + dlVaultBalancePost);
-
-  //   assert.equal(dlVaultBalancePost, "4995000000000");
-
-  //   const [, usdcVaultBalancePost] = await readAccount(pda.usdcVault, provider);
-  //   console.log("Vault USDC Balance: " + usdcVaultBalancePost);
-
-  //   assert.equal(usdcVaultBalancePost, "20000000000");
-  // });
-
-  // it("Starting Round Three", async () => {
-  //   const tx = await program.methods
-  //     .startNextRound()
-  //     .accounts({
-  //       presaleInfo: pda.presalePDA,
-  //       tokenVault: pda.dlVault,
-  //       admin: admin.publicKey,
-  //       mintOfTokenProgramSent: dlAddress,
-  //       tokenProgram: spl.TOKEN_PROGRAM_ID,
-  //     })
-  //     .signers([admin])
-  //     .rpc();
-
-  //   console.log(`Round three has been started successfully`, tx);
-
-  //   const [, dlVaultBalancePost] = await readAccount(pda.dlVault, provider);
-  //   console.log("Vault Balance: " + dlVaultBalancePost);
-
-  //   assert.equal(dlVaultBalancePost, "3000000000000");
-
-  //   console.log(
-  //     "Presale Stage",
-  //     (
-  //       await program.account.preSaleDetails.fetch(pda.presalePDA)
-  //     ).stage.toString()
-  //   );
-  // });
-
-  // it("Bob Buying Tokens from Round Three", async () => {
-  //   const [, bobUSDCBalancePre] = await readAccount(bobUSDCWallet, provider);
-
-  //   assert.equal(bobUSDCBalancePre, "6980000000000");
-
-  //   // Create a token account for Bob.
-  //   const bobDLWallet = await spl.getAssociatedTokenAddress(
-  //     dlAddress,
-  //     bob.publicKey,
-  //     false,
-  //     spl.TOKEN_PROGRAM_ID,
-  //     spl.ASSOCIATED_TOKEN_PROGRAM_ID
-  //   );
-  //   console.log("Bob Associated Account", bobDLWallet);
-  //   const tx = await program.methods
-  //     .buyTokens(new anchor.BN(10000000000), false)
-  //     .accounts({
-  //       presaleInfo: pda.presalePDA,
-  //       tokenVault: pda.dlVault,
-  //       usdcVault: pda.usdcVault,
-  //       walletToDepositTo: bobDLWallet,
-  //       buyerUsdcAccount: bobUSDCWallet,
-  //       buyer: bob.publicKey,
-  //       mintOfTokenProgramSent: dlAddress,
-  //       mintOfTokenUserSend: usdcAddress,
-  //       tokenProgram: spl.TOKEN_PROGRAM_ID,
-  //       systemProgram: anchor.web3.SystemProgram.programId,
-  //       associatedTokenProgram: spl.ASSOCIATED_TOKEN_PROGRAM_ID,
-  //     })
-  //     .signers([bob])
-  //     .rpc();
-
-  //   console.log(`Round Two has been started successfully`, tx);
-
-  //   const [, dlBobBalancePost] = await readAccount(bobDLWallet, provider);
-  //   console.log("Bob DL Balance: " + dlBobBalancePost);
-
-  //   assert.equal(dlBobBalancePost, "18333333333");
-
-  //   const [, dlVaultBalancePost] = await readAccount(pda.dlVault, provider);
-  //   console.log("Vault DL Balance: " + dlVaultBalancePost);
-
-  //   assert.equal(dlVaultBalancePost, "2996666666667");
-
-  //   const [, usdcVaultBalancePost] = await readAccount(pda.usdcVault, provider);
-  //   console.log("Vault USDC Balance: " + usdcVaultBalancePost);
-
-  //   assert.equal(usdcVaultBalancePost, "30000000000");
-  // });
-
-  // it("Bob Buying Tokens from Round Three with SOL", async () => {
-
-  //   // Create a token account for Bob.
-  //   const bobDLWallet = await spl.getAssociatedTokenAddress(
-  //     dlAddress,
-  //     bob.publicKey,
-  //     false,
-  //     spl.TOKEN_PROGRAM_ID,
-  //     spl.ASSOCIATED_TOKEN_PROGRAM_ID
-  //   );
-  //   console.log("Bob Associated Account", bobDLWallet);
-  //   //buying With 2 Sol
-  //   const tx = await program.methods
-  //     .buyTokens(new anchor.BN(2000000000), true)
-  //     .accounts({
-  //       presaleInfo: pda.presalePDA,
-  //       tokenVault: pda.dlVault,
-  //       usdcVault: pda.usdcVault,
-  //       walletToDepositTo: bobDLWallet,
-  //       buyerUsdcAccount: bobUSDCWallet,
-  //       buyer: bob.publicKey,
-  //       mintOfTokenProgramSent: dlAddress,
-  //       mintOfTokenUserSend: usdcAddress,
-  //       tokenProgram: spl.TOKEN_PROGRAM_ID,
-  //       systemProgram: anchor.web3.SystemProgram.programId,
-  //       associatedTokenProgram: spl.ASSOCIATED_TOKEN_PROGRAM_ID,
-  //     })
-  //     .signers([bob])
-  //     .rpc();
-
-  //   console.log(`Round Two has been started successfully`, tx);
-
-  //   const [, dlBobBalancePost] = await readAccount(bobDLWallet, provider);
-  //   console.log("Bob DL Balance: " + dlBobBalancePost);
-
-  //   assert.equal(dlBobBalancePost, "130333333333");
-
-  //   const [, dlVaultBalancePost] = await readAccount(pda.dlVault, provider);
-  //   console.log("Vault DL Balance: " + dlVaultBalancePost);
-
-  //   assert.equal(dlVaultBalancePost, "2884666666667");
-
-  //   const [, usdcVaultBalancePost] = await readAccount(pda.usdcVault, provider);
-  //   console.log("Vault USDC Balance: " + usdcVaultBalancePost);
-
-  //   assert.equal(usdcVaultBalancePost, "30000000000");
-  // });
-
-  // it("Ending Presale", async () => {
-  //   const tx = await program.methods
-  //     .startNextRound()
-  //     .accounts({
-  //       presaleInfo: pda.presalePDA,
-  //       tokenVault: pda.dlVault,
-  //       admin: admin.publicKey,
-  //       mintOfTokenProgramSent: dlAddress,
-  //       tokenProgram: spl.TOKEN_PROGRAM_ID,
-  //     })
-  //     .signers([admin])
-  //     .rpc();
-
-  //   console.log(`Presale has been ended`, tx);
-
-  //   try {
-  //     const [info, balance] = await readAccount(pda.dlVault, provider);
-  //     console.log("===========>", info, balance);
-  //     return assert.fail("Account should be closed");
-  //   } catch (e) {
-  //     assert.equal(
-  //       e.message,
-  //       "Cannot read properties of null (reading 'data')"
-  //     );
-  //   }
-
-  //   console.log(
-  //     "Presale Stage",
-  //     (
-  //       await program.account.preSaleDetails.fetch(pda.presalePDA)
-  //     ).stage.toString()
-  //   );
-  // });
-
-  // it("Withdraw Funds from Presale", async () => {
-  //   const adminUSDCWallet = await getOrCreateAssociatedTokenAccount(
-  //     connection,
-  //     admin,
-  //     usdcAddress,
-  //     admin.publicKey
-  //   );
-
-  //   const [, adminUSDCBalancePre] = await readAccount(
-  //     adminUSDCWallet.address,
-  //     provider
-  //   );
-
-  //   assert.equal(adminUSDCBalancePre, "0");
-
-  //   const tx = await program.methods
-  //     .withdrawUsdc()
-  //     .accounts({
-  //       presaleInfo: pda.presalePDA,
-  //       usdcVault: pda.usdcVault,
-  //       admin: admin.publicKey,
-  //       usdcWallet: adminUSDCWallet.address,
-  //       mintOfTokenUserSend: usdcAddress,
-  //       tokenProgram: spl.TOKEN_PROGRAM_ID,
-  //     })
-  //     .signers([admin])
-  //     .rpc();
-
-  //   console.log(`USDC has been withdrawn successfully`, tx);
-  //   const [, adminUSDCBalancePost] = await readAccount(
-  //     adminUSDCWallet.address,
-  //     provider
-  //   );
-
-  //   assert.equal(adminUSDCBalancePost, "30000000000");
-  // });
+  it("Whitelisted rounds only sell to wallets in the Merkle tree", async () => {
+    // Bob may put up to 13 USDC into round one, next to another investor
+    const bobCap = new anchor.BN(13000000000);
+    const bobLeaf = whitelistLeaf(bob.publicKey, bobCap);
+    const otherLeaf = whitelistLeaf(
+      Keypair.generate().publicKey,
+      new anchor.BN(5000000000)
+    );
+    const root = whitelistNode(bobLeaf, otherLeaf);
+
+    const setWhitelist = (merkleRoot: number[]) =>
+      program.methods
+        .setRoundWhitelist(0, merkleRoot)
+        .accounts({
+          presaleInfo: pda.presalePDA,
+          admin: admin.publicKey,
+        })
+        .signers([admin])
+        .rpc();
+    await setWhitelist([...root]);
+
+    const buy = (amount: number, cap: anchor.BN) =>
+      program.methods
+        .buyTokens(new anchor.BN(amount), false, cap, [[...otherLeaf]])
+        .accounts({
+          presaleInfo: pda.presalePDA,
+          paymentMint: paymentMintAddress(usdcAddress),
+          paymentVault: pda.usdcVault,
+          solVault: pda.solVault,
+          purchase: purchaseAddress(bob.publicKey),
+          buyerPaymentAccount: bobUSDCWallet,
+          buyer: bob.publicKey,
+          mintOfTokenUserSend: usdcAddress,
+          priceFeed: null,
+          tokenProgram: spl.TOKEN_PROGRAM_ID,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .signers([bob])
+        .rpc();
+
+    // Claiming a bigger cap than the one in the tree fails the proof
+    try {
+      await buy(1000000000, new anchor.BN(20000000000));
+      return assert.fail("Bob's leaf has a 13 USDC cap");
+    } catch (e) {
+      assert.include(e.message, "NotWhitelisted");
+    }
+
+    await buy(1000000000, bobCap);
+
+    // Bob has already put at least 11 USDC into round one
+    try {
+      await buy(5000000000, bobCap);
+      return assert.fail("Bob's cap is 13 USDC");
+    } catch (e) {
+      assert.include(e.message, "AboveWhitelistCap");
+    }
+
+    // Rotating back to an empty root reopens the round to everyone
+    await setWhitelist(Array(32).fill(0));
+    const info = await program.account.preSaleDetails.fetch(pda.presalePDA);
+    assert.deepEqual(info.rounds[0].merkleRoot, Array(32).fill(0));
+  });
+
+  it("Nothing can be claimed before TGE", async () => {
+    try {
+      await program.methods
+        .claim()
+        .accounts({
+          presaleInfo: pda.presalePDA,
+          tokenVault: pda.dlVault,
+          purchase: purchaseAddress(bob.publicKey),
+          walletToDepositTo: await spl.getAssociatedTokenAddress(
+            dlAddress,
+            bob.publicKey
+          ),
+          buyer: bob.publicKey,
+          mintOfTokenProgramSent: dlAddress,
+          systemProgram: anchor.web3.SystemProgram.programId,
+          tokenProgram: spl.TOKEN_PROGRAM_ID,
+          associatedTokenProgram: spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+        })
+        .signers([bob])
+        .rpc();
+      return assert.fail("TGE is at the end of round three");
+    } catch (e) {
+      assert.include(e.message, "NothingToClaim");
+    }
+  });
+
+  it("Refunds only open once the sale has ended", async () => {
+    try {
+      await program.methods
+        .refund()
+        .accounts({
+          presaleInfo: pda.presalePDA,
+          paymentMint: paymentMintAddress(usdcAddress),
+          paymentVault: pda.usdcVault,
+          solVault: pda.solVault,
+          purchase: purchaseAddress(bob.publicKey),
+          buyerPaymentAccount: bobUSDCWallet,
+          buyer: bob.publicKey,
+          mintOfTokenUserSend: usdcAddress,
+          systemProgram: anchor.web3.SystemProgram.programId,
+          tokenProgram: spl.TOKEN_PROGRAM_ID,
+        })
+        .signers([bob])
+        .rpc();
+      return assert.fail("Round one is still open");
+    } catch (e) {
+      assert.include(e.message, "PresaleNotEndedYet");
+    }
+
+    // The SOL vault stays rent exempt
+    assert.isAbove(await provider.connection.getBalance(pda.solVault), 0);
+  });
+
+  it("The purchase that reaches the hard cap is partially filled and ends the sale", async () => {
+    const infoPre = await program.account.preSaleDetails.fetch(pda.presalePDA);
+    const remaining = pda.hardCap.sub(infoPre.totalRaised);
+    const [, bobUSDCBalancePre] = await readAccount(bobUSDCWallet, provider);
+
+    // Asking for 5 USDC worth when less than that is left under the cap
+    await program.methods
+      .buyTokens(new anchor.BN(5000000000), false, new anchor.BN(0), [])
+      .accounts({
+        presaleInfo: pda.presalePDA,
+        paymentMint: paymentMintAddress(usdcAddress),
+        paymentVault: pda.usdcVault,
+        solVault: pda.solVault,
+        purchase: purchaseAddress(bob.publicKey),
+        buyerPaymentAccount: bobUSDCWallet,
+        buyer: bob.publicKey,
+        mintOfTokenUserSend: usdcAddress,
+        priceFeed: null,
+        tokenProgram: spl.TOKEN_PROGRAM_ID,
+        systemProgram: anchor.web3.SystemProgram.programId,
+      })
+      .signers([bob])
+      .rpc();
+
+    // Only the remainder was kept, the excess went straight back to Bob
+    const [, bobUSDCBalancePost] = await readAccount(bobUSDCWallet, provider);
+    const paid = new anchor.BN(bobUSDCBalancePre).sub(
+      new anchor.BN(bobUSDCBalancePost)
+    );
+    assert.equal(paid.toString(), remaining.toString());
+    const info = await program.account.preSaleDetails.fetch(pda.presalePDA);
+    assert.equal(info.totalRaised.toString(), pda.hardCap.toString());
+    assert.isFalse(info.endedAt.isZero());
+
+    // Every remaining round can be finalized right away, and their unsold tokens go back to
+    // the admin
+    const finalize = (adminTokenWallet: anchor.web3.PublicKey | null) =>
+      program.methods
+        .finalizeRound()
+        .accounts({
+          presaleInfo: pda.presalePDA,
+          tokenVault: pda.dlVault,
+          admin: admin.publicKey,
+          mintOfTokenProgramSent: dlAddress,
+          adminTokenWallet,
+          tokenProgram: spl.TOKEN_PROGRAM_ID,
+        })
+        .rpc();
+    try {
+      await finalize(null);
+      return assert.fail("The admin's token wallet is needed");
+    } catch (e) {
+      assert.include(e.message, "AdminTokenWalletMissing");
+    }
+
+    const unsold = info.rounds.reduce(
+      (sum, round) => sum.add(round.allocationRemaining),
+      new anchor.BN(0)
+    );
+    const [, dlVaultBalancePre] = await readAccount(pda.dlVault, provider);
+    const [, adminDLBalancePre] = await readAccount(adminDLWallet, provider);
+    await finalize(adminDLWallet);
+    const ended = await program.account.preSaleDetails.fetch(pda.presalePDA);
+    assert.equal(ended.stage, ended.rounds.length + 1);
+
+    // Only the tokens sold are left in the vault
+    const [, dlVaultBalance] = await readAccount(pda.dlVault, provider);
+    const [, adminDLBalance] = await readAccount(adminDLWallet, provider);
+    assert.equal(
+      dlVaultBalance,
+      new anchor.BN(dlVaultBalancePre).sub(unsold).toString()
+    );
+    assert.equal(
+      adminDLBalance,
+      new anchor.BN(adminDLBalancePre).add(unsold).toString()
+    );
+  });
+
+  it("Bob claims his TGE share once the sale has ended", async () => {
+    const bobDLWallet = await spl.getAssociatedTokenAddress(
+      dlAddress,
+      bob.publicKey
+    );
+    await program.methods
+      .claim()
+      .accounts({
+        presaleInfo: pda.presalePDA,
+        tokenVault: pda.dlVault,
+        purchase: purchaseAddress(bob.publicKey),
+        walletToDepositTo: bobDLWallet,
+        buyer: bob.publicKey,
+        mintOfTokenProgramSent: dlAddress,
+        systemProgram: anchor.web3.SystemProgram.programId,
+        tokenProgram: spl.TOKEN_PROGRAM_ID,
+        associatedTokenProgram: spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+      })
+      .signers([bob])
+      .rpc();
+
+    // 20% at TGE, the rest only after the cliff
+    const purchase = await program.account.purchase.fetch(
+      purchaseAddress(bob.publicKey)
+    );
+    const [, dlBobBalance] = await readAccount(bobDLWallet, provider);
+    assert.equal(
+      dlBobBalance,
+      purchase.totalTokensReceived.muln(2000).divn(10000).toString()
+    );
+  });
+
+  it("Admin withdraws the SOL raised", async () => {
+    const info = await program.account.preSaleDetails.fetch(pda.presalePDA);
+    const adminBalancePre = await provider.connection.getBalance(
+      admin.publicKey
+    );
+    const rentExemptMinimum =
+      await provider.connection.getMinimumBalanceForRentExemption(0);
+
+    await program.methods
+      .withdrawSol()
+      .accounts({
+        presaleInfo: pda.presalePDA,
+        solVault: pda.solVault,
+        admin: admin.publicKey,
+        systemProgram: anchor.web3.SystemProgram.programId,
+      })
+      .signers([admin])
+      .rpc();
+
+    // Exactly the lamports paid by buyers, the vault keeps its rent
+    const adminBalancePost = await provider.connection.getBalance(
+      admin.publicKey
+    );
+    assert.equal(adminBalancePost - adminBalancePre, info.solRaised.toNumber());
+    assert.equal(
+      await provider.connection.getBalance(pda.solVault),
+      rentExemptMinimum
+    );
+  });
+
+  it("Sold tokens are only recovered by the admin when the soft cap was missed", async () => {
+    const [, dlVaultBalancePre] = await readAccount(pda.dlVault, provider);
+
+    try {
+      await program.methods
+        .recoverTokens()
+        .accounts({
+          presaleInfo: pda.presalePDA,
+          tokenVault: pda.dlVault,
+          admin: admin.publicKey,
+          mintOfTokenProgramSent: dlAddress,
+          adminTokenWallet: adminDLWallet,
+          tokenProgram: spl.TOKEN_PROGRAM_ID,
+        })
+        .signers([admin])
+        .rpc();
+      return assert.fail("The soft cap was reached, buyers claim their tokens");
+    } catch (e) {
+      assert.include(e.message, "SoftCapReached");
+    }
+
+    // What buyers are owed stays in the vault
+    const [, dlVaultBalancePost] = await readAccount(pda.dlVault, provider);
+    assert.equal(dlVaultBalancePost, dlVaultBalancePre);
+  });
 });
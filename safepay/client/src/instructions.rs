@@ -0,0 +1,469 @@
+use anchor_lang::{
+    prelude::Pubkey,
+    solana_program::{instruction::Instruction, system_program, sysvar},
+    InstructionData, ToAccountMetas,
+};
+use anchor_spl::associated_token;
+use safepay::{accounts, instruction};
+
+use crate::pda::{config_address, program_data_address, BatchKeys, GrantKeys};
+
+fn build(accounts: impl ToAccountMetas, data: impl InstructionData) -> Instruction {
+    Instruction {
+        program_id: safepay::ID,
+        accounts: accounts.to_account_metas(None),
+        data: data.data(),
+    }
+}
+
+// Alice funds a grant from her associated wallet. `keys.idx` must be her counter's `next_idx`.
+pub fn initialize_new_grant(
+    keys: &GrantKeys,
+    amount: u64,
+    attester: Option<Pubkey>,
+) -> Instruction {
+    build(
+        accounts::InitializeNewGrant {
+            grant_counter: keys.counter(),
+            application_state: keys.state(),
+            escrow_wallet_state: keys.escrow_wallet(),
+            sender_index: keys.sender_index(),
+            receiver_index: keys.receiver_index(),
+            config: config_address(),
+            user_sending: keys.user_sending,
+            user_receiving: keys.user_receiving,
+            mint_of_token_being_sent: keys.mint,
+            wallet_to_withdraw_from: keys.sender_wallet(),
+            system_program: system_program::ID,
+            token_program: keys.token_program,
+        },
+        instruction::InitializeNewGrant { amount, attester },
+    )
+}
+
+pub fn accept_grant(keys: &GrantKeys) -> Instruction {
+    build(
+        accounts::AcceptGrant {
+            application_state: keys.state(),
+            user_sending: keys.user_sending,
+            user_receiving: keys.user_receiving,
+            mint_of_token_being_sent: keys.mint,
+        },
+        instruction::AcceptGrant {
+            _application_idx: keys.idx,
+        },
+    )
+}
+
+// Once Bob accepted, or to reduce an attested grant, Bob co-signs with `receiver_signs`
+pub fn amend_grant(
+    keys: &GrantKeys,
+    amount: u64,
+    is_top_up: bool,
+    receiver_signs: bool,
+) -> Instruction {
+    let mut ix = build(
+        accounts::AmendGrant {
+            application_state: keys.state(),
+            escrow_wallet_state: keys.escrow_wallet(),
+            sender_wallet: keys.sender_wallet(),
+            user_sending: keys.user_sending,
+            user_receiving: keys.user_receiving,
+            mint_of_token_being_sent: keys.mint,
+            token_program: keys.token_program,
+        },
+        instruction::AmendGrant {
+            application_idx: keys.idx,
+            amount,
+            is_top_up,
+        },
+    );
+    if receiver_signs {
+        for meta in &mut ix.accounts {
+            if meta.pubkey == keys.user_receiving {
+                meta.is_signer = true;
+            }
+        }
+    }
+    ix
+}
+
+// `fee_wallet` is the fee recipient's wallet, required whenever the grant was created with a fee
+pub fn complete_grant(keys: &GrantKeys, fee_wallet: Option<Pubkey>) -> Instruction {
+    build(
+        accounts::CompleteGrant {
+            application_state: keys.state(),
+            escrow_wallet_state: keys.escrow_wallet(),
+            sender_index: keys.sender_index(),
+            receiver_index: keys.receiver_index(),
+            wallet_to_deposit_to: keys.receiver_wallet(),
+            fee_wallet,
+            user_sending: keys.user_sending,
+            user_receiving: keys.user_receiving,
+            mint_of_token_being_sent: keys.mint,
+            system_program: system_program::ID,
+            token_program: keys.token_program,
+            associated_token_program: associated_token::ID,
+        },
+        instruction::CompleteGrant {
+            application_idx: keys.idx,
+        },
+    )
+}
+
+// `authority` is Alice pulling back or Bob declining
+pub fn pull_back(keys: &GrantKeys, authority: &Pubkey) -> Instruction {
+    build(
+        accounts::PullBackFunds {
+            application_state: keys.state(),
+            escrow_wallet_state: keys.escrow_wallet(),
+            sender_index: keys.sender_index(),
+            receiver_index: keys.receiver_index(),
+            authority: *authority,
+            user_sending: keys.user_sending,
+            user_receiving: keys.user_receiving,
+            mint_of_token_being_sent: keys.mint,
+            refund_wallet: keys.sender_wallet(),
+            system_program: system_program::ID,
+            token_program: keys.token_program,
+            associated_token_program: associated_token::ID,
+        },
+        instruction::PullBack {
+            application_idx: keys.idx,
+        },
+    )
+}
+
+// Must follow the attester's ed25519 instruction in the same transaction
+pub fn settle_with_attestation(
+    keys: &GrantKeys,
+    payer: &Pubkey,
+    outcome: u8,
+    fee_wallet: Option<Pubkey>,
+) -> Instruction {
+    build(
+        accounts::SettleWithAttestation {
+            application_state: keys.state(),
+            escrow_wallet_state: keys.escrow_wallet(),
+            sender_index: keys.sender_index(),
+            receiver_index: keys.receiver_index(),
+            wallet_to_deposit_to: keys.receiver_wallet(),
+            refund_wallet: keys.sender_wallet(),
+            fee_wallet,
+            payer: *payer,
+            user_sending: keys.user_sending,
+            user_receiving: keys.user_receiving,
+            mint_of_token_being_sent: keys.mint,
+            instructions: sysvar::instructions::ID,
+            system_program: system_program::ID,
+            token_program: keys.token_program,
+            associated_token_program: associated_token::ID,
+        },
+        instruction::SettleWithAttestation {
+            application_idx: keys.idx,
+            outcome,
+        },
+    )
+}
+
+// The attester signs the state address followed by the outcome byte
+pub fn attestation_message(keys: &GrantKeys, outcome: u8) -> Vec<u8> {
+    let mut message = keys.state().to_bytes().to_vec();
+    message.push(outcome);
+    message
+}
+
+pub fn initialize_new_swap(
+    keys: &GrantKeys,
+    mint_expected: &Pubkey,
+    amount: u64,
+    amount_expected: u64,
+) -> Instruction {
+    build(
+        accounts::InitializeNewSwap {
+            grant_counter: keys.counter(),
+            application_state: keys.state(),
+            escrow_wallet_state: keys.escrow_wallet(),
+            sender_index: keys.sender_index(),
+            receiver_index: keys.receiver_index(),
+            user_sending: keys.user_sending,
+            user_receiving: keys.user_receiving,
+            mint_of_token_being_sent: keys.mint,
+            mint_of_token_expected: *mint_expected,
+            wallet_to_withdraw_from: keys.sender_wallet(),
+            system_program: system_program::ID,
+            token_program: keys.token_program,
+        },
+        instruction::InitializeNewSwap {
+            amount,
+            amount_expected,
+        },
+    )
+}
+
+// `expected_token_program` owns `mint_expected`, it need not be the one of `keys.mint`
+pub fn accept_swap(
+    keys: &GrantKeys,
+    mint_expected: &Pubkey,
+    expected_token_program: &Pubkey,
+) -> Instruction {
+    let expected_wallet_of = |owner: &Pubkey| {
+        associated_token::get_associated_token_address_with_program_id(
+            owner,
+            mint_expected,
+            expected_token_program,
+        )
+    };
+    build(
+        accounts::AcceptSwap {
+            application_state: keys.state(),
+            escrow_wallet_state: keys.escrow_wallet(),
+            sender_index: keys.sender_index(),
+            receiver_index: keys.receiver_index(),
+            wallet_to_deposit_to: keys.receiver_wallet(),
+            sender_wallet_to_deposit_to: expected_wallet_of(&keys.user_sending),
+            wallet_to_withdraw_from: expected_wallet_of(&keys.user_receiving),
+            user_sending: keys.user_sending,
+            user_receiving: keys.user_receiving,
+            mint_of_token_being_sent: keys.mint,
+            mint_of_token_expected: *mint_expected,
+            system_program: system_program::ID,
+            token_program: keys.token_program,
+            expected_token_program: *expected_token_program,
+            associated_token_program: associated_token::ID,
+        },
+        instruction::AcceptSwap {
+            application_idx: keys.idx,
+        },
+    )
+}
+
+pub fn cancel_swap(keys: &GrantKeys) -> Instruction {
+    build(
+        accounts::CancelSwap {
+            application_state: keys.state(),
+            escrow_wallet_state: keys.escrow_wallet(),
+            sender_index: keys.sender_index(),
+            receiver_index: keys.receiver_index(),
+            user_sending: keys.user_sending,
+            user_receiving: keys.user_receiving,
+            mint_of_token_being_sent: keys.mint,
+            refund_wallet: keys.sender_wallet(),
+            system_program: system_program::ID,
+            token_program: keys.token_program,
+        },
+        instruction::CancelSwap {
+            application_idx: keys.idx,
+        },
+    )
+}
+
+pub fn initialize_new_batch_grant(
+    keys: &BatchKeys,
+    num_recipients: u32,
+    amount: u64,
+    merkle_root: [u8; 32],
+    expires_at: i64,
+) -> Instruction {
+    build(
+        accounts::InitializeNewBatchGrant {
+            batch_state: keys.state(),
+            escrow_wallet_state: keys.escrow_wallet(),
+            user_sending: keys.user_sending,
+            mint_of_token_being_sent: keys.mint,
+            wallet_to_withdraw_from: keys.sender_wallet(),
+            system_program: system_program::ID,
+            token_program: keys.token_program,
+        },
+        instruction::InitializeNewBatchGrant {
+            batch_idx: keys.batch_idx,
+            num_recipients,
+            amount,
+            merkle_root,
+            expires_at,
+        },
+    )
+}
+
+pub fn claim_batch_grant(
+    keys: &BatchKeys,
+    recipient: &Pubkey,
+    index: u32,
+    amount: u64,
+    proof: Vec<[u8; 32]>,
+) -> Instruction {
+    build(
+        accounts::ClaimBatchGrant {
+            batch_state: keys.state(),
+            escrow_wallet_state: keys.escrow_wallet(),
+            wallet_to_deposit_to: keys.recipient_wallet(recipient),
+            user_sending: keys.user_sending,
+            user_receiving: *recipient,
+            mint_of_token_being_sent: keys.mint,
+            system_program: system_program::ID,
+            token_program: keys.token_program,
+            associated_token_program: associated_token::ID,
+        },
+        instruction::ClaimBatchGrant {
+            _batch_idx: keys.batch_idx,
+            index,
+            amount,
+            proof,
+        },
+    )
+}
+
+// Alice takes back what was not claimed once the batch expired
+pub fn reclaim_batch_grant(keys: &BatchKeys) -> Instruction {
+    build(
+        accounts::ReclaimBatchGrant {
+            batch_state: keys.state(),
+            escrow_wallet_state: keys.escrow_wallet(),
+            refund_wallet: keys.sender_wallet(),
+            user_sending: keys.user_sending,
+            mint_of_token_being_sent: keys.mint,
+            token_program: keys.token_program,
+        },
+        instruction::ReclaimBatchGrant {
+            _batch_idx: keys.batch_idx,
+        },
+    )
+}
+
+pub fn initialize_subscription(
+    keys: &GrantKeys,
+    amount_per_period: u64,
+    period_seconds: i64,
+    periods: u32,
+) -> Instruction {
+    build(
+        accounts::InitializeSubscription {
+            grant_counter: keys.counter(),
+            application_state: keys.state(),
+            sender_index: keys.sender_index(),
+            receiver_index: keys.receiver_index(),
+            user_sending: keys.user_sending,
+            user_receiving: keys.user_receiving,
+            mint_of_token_being_sent: keys.mint,
+            wallet_to_withdraw_from: keys.sender_wallet(),
+            system_program: system_program::ID,
+            token_program: keys.token_program,
+        },
+        instruction::InitializeSubscription {
+            amount_per_period,
+            period_seconds,
+            periods,
+        },
+    )
+}
+
+// `payer` is Bob or any cranker, it pays for Bob's wallet if it does not exist yet
+pub fn charge_subscription(keys: &GrantKeys, payer: &Pubkey) -> Instruction {
+    build(
+        accounts::ChargeSubscription {
+            application_state: keys.state(),
+            sender_index: keys.sender_index(),
+            receiver_index: keys.receiver_index(),
+            wallet_to_withdraw_from: keys.sender_wallet(),
+            wallet_to_deposit_to: keys.receiver_wallet(),
+            payer: *payer,
+            user_sending: keys.user_sending,
+            user_receiving: keys.user_receiving,
+            mint_of_token_being_sent: keys.mint,
+            system_program: system_program::ID,
+            token_program: keys.token_program,
+            associated_token_program: associated_token::ID,
+        },
+        instruction::ChargeSubscription {
+            _application_idx: keys.idx,
+        },
+    )
+}
+
+pub fn cancel_subscription(keys: &GrantKeys) -> Instruction {
+    build(
+        accounts::CancelSubscription {
+            application_state: keys.state(),
+            sender_index: keys.sender_index(),
+            receiver_index: keys.receiver_index(),
+            wallet_to_withdraw_from: keys.sender_wallet(),
+            user_sending: keys.user_sending,
+            user_receiving: keys.user_receiving,
+            mint_of_token_being_sent: keys.mint,
+            token_program: keys.token_program,
+        },
+        instruction::CancelSubscription {
+            _application_idx: keys.idx,
+        },
+    )
+}
+
+// Anyone can send the base rent of an empty index back to `payer`, who created it
+pub fn close_grant_index(grant_index: &Pubkey, payer: &Pubkey) -> Instruction {
+    build(
+        accounts::CloseGrantIndex {
+            grant_index: *grant_index,
+            payer: *payer,
+        },
+        instruction::CloseGrantIndex {},
+    )
+}
+
+// `operator` must be the program's upgrade authority
+pub fn initialize_config(
+    operator: &Pubkey,
+    fee_recipient: Pubkey,
+    fee_basis_points: u16,
+) -> Instruction {
+    build(
+        accounts::InitializeConfig {
+            config: config_address(),
+            operator: *operator,
+            program: safepay::ID,
+            program_data: program_data_address(),
+            system_program: system_program::ID,
+        },
+        instruction::InitializeConfig {
+            fee_recipient,
+            fee_basis_points,
+        },
+    )
+}
+
+fn update_config_accounts(operator: &Pubkey) -> accounts::UpdateConfig {
+    accounts::UpdateConfig {
+        config: config_address(),
+        operator: *operator,
+    }
+}
+
+// Signed by the current `operator`, `new_operator` takes over from then on
+pub fn update_config(
+    operator: &Pubkey,
+    new_operator: Pubkey,
+    fee_recipient: Pubkey,
+    fee_basis_points: u16,
+) -> Instruction {
+    build(
+        update_config_accounts(operator),
+        instruction::UpdateConfig {
+            operator: new_operator,
+            fee_recipient,
+            fee_basis_points,
+        },
+    )
+}
+
+pub fn add_fee_waiver(operator: &Pubkey, mint: Pubkey) -> Instruction {
+    build(
+        update_config_accounts(operator),
+        instruction::AddFeeWaiver { mint },
+    )
+}
+
+pub fn remove_fee_waiver(operator: &Pubkey, mint: Pubkey) -> Instruction {
+    build(
+        update_config_accounts(operator),
+        instruction::RemoveFeeWaiver { mint },
+    )
+}
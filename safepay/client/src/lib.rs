@@ -0,0 +1,10 @@
+//! Rust client for the `safepay` program: PDA derivation, instruction builders and
+//! account decoding, so integrations do not need the TypeScript IDL.
+
+pub mod instructions;
+pub mod pda;
+pub mod state;
+
+pub use pda::{BatchKeys, GrantKeys};
+pub use safepay::{Stage, ID};
+pub use state::{decode_counter, decode_details, decode_grant_index, GrantState};
@@ -0,0 +1,160 @@
+use anchor_lang::{prelude::Pubkey, solana_program::bpf_loader_upgradeable};
+use anchor_spl::associated_token::get_associated_token_address_with_program_id;
+
+fn find(seeds: &[&[u8]]) -> Pubkey {
+    Pubkey::find_program_address(seeds, &safepay::ID).0
+}
+
+pub fn config_address() -> Pubkey {
+    find(&[b"config"])
+}
+
+// The upgradeable loader's data account of the program, its upgrade authority creates the config
+pub fn program_data_address() -> Pubkey {
+    Pubkey::find_program_address(&[safepay::ID.as_ref()], &bpf_loader_upgradeable::ID).0
+}
+
+pub fn counter_address(user_sending: &Pubkey) -> Pubkey {
+    find(&[b"counter", user_sending.as_ref()])
+}
+
+pub fn sender_index_address(user_sending: &Pubkey) -> Pubkey {
+    find(&[b"sender_index", user_sending.as_ref()])
+}
+
+pub fn receiver_index_address(user_receiving: &Pubkey) -> Pubkey {
+    find(&[b"receiver_index", user_receiving.as_ref()])
+}
+
+// Everything that identifies a grant, swap or subscription between Alice and Bob. `idx` is
+// the sender's `GrantCounter::next_idx` at the time the instance was created.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GrantKeys {
+    pub user_sending: Pubkey,
+    pub user_receiving: Pubkey,
+    pub mint: Pubkey,
+    pub idx: u64,
+    // SPL Token or Token-2022, whichever owns `mint`
+    pub token_program: Pubkey,
+}
+
+impl GrantKeys {
+    fn find(&self, prefix: &[u8]) -> Pubkey {
+        find(&[
+            prefix,
+            self.user_sending.as_ref(),
+            self.user_receiving.as_ref(),
+            self.mint.as_ref(),
+            &self.idx.to_le_bytes(),
+        ])
+    }
+
+    pub fn state(&self) -> Pubkey {
+        self.find(b"state")
+    }
+
+    pub fn escrow_wallet(&self) -> Pubkey {
+        self.find(b"wallet")
+    }
+
+    pub fn counter(&self) -> Pubkey {
+        counter_address(&self.user_sending)
+    }
+
+    pub fn sender_index(&self) -> Pubkey {
+        sender_index_address(&self.user_sending)
+    }
+
+    pub fn receiver_index(&self) -> Pubkey {
+        receiver_index_address(&self.user_receiving)
+    }
+
+    // Alice's associated wallet for `mint`
+    pub fn sender_wallet(&self) -> Pubkey {
+        self.wallet_of(&self.user_sending, &self.mint)
+    }
+
+    // Bob's associated wallet for `mint`
+    pub fn receiver_wallet(&self) -> Pubkey {
+        self.wallet_of(&self.user_receiving, &self.mint)
+    }
+
+    pub fn wallet_of(&self, owner: &Pubkey, mint: &Pubkey) -> Pubkey {
+        get_associated_token_address_with_program_id(owner, mint, &self.token_program)
+    }
+}
+
+// A batch grant is keyed by Alice, the mint and a batch index she picks herself
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BatchKeys {
+    pub user_sending: Pubkey,
+    pub mint: Pubkey,
+    pub batch_idx: u64,
+    pub token_program: Pubkey,
+}
+
+impl BatchKeys {
+    fn find(&self, prefix: &[u8]) -> Pubkey {
+        find(&[
+            prefix,
+            self.user_sending.as_ref(),
+            self.mint.as_ref(),
+            &self.batch_idx.to_le_bytes(),
+        ])
+    }
+
+    pub fn state(&self) -> Pubkey {
+        self.find(b"batch_state")
+    }
+
+    pub fn escrow_wallet(&self) -> Pubkey {
+        self.find(b"batch_wallet")
+    }
+
+    pub fn sender_wallet(&self) -> Pubkey {
+        get_associated_token_address_with_program_id(
+            &self.user_sending,
+            &self.mint,
+            &self.token_program,
+        )
+    }
+
+    pub fn recipient_wallet(&self, recipient: &Pubkey) -> Pubkey {
+        get_associated_token_address_with_program_id(recipient, &self.mint, &self.token_program)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anchor_spl::token::spl_token;
+
+    #[test]
+    fn grant_pdas_use_the_program_seeds() {
+        let keys = GrantKeys {
+            user_sending: Pubkey::new_unique(),
+            user_receiving: Pubkey::new_unique(),
+            mint: Pubkey::new_unique(),
+            idx: 7,
+            token_program: spl_token::ID,
+        };
+        let (state, _) = Pubkey::find_program_address(
+            &[
+                b"state",
+                keys.user_sending.as_ref(),
+                keys.user_receiving.as_ref(),
+                keys.mint.as_ref(),
+                &7u64.to_le_bytes(),
+            ],
+            &safepay::ID,
+        );
+
+        assert_eq!(keys.state(), state);
+        assert_ne!(keys.escrow_wallet(), keys.state());
+        assert_ne!(
+            keys.state(),
+            GrantKeys { idx: 8, ..keys }.state(),
+            "every idx gets its own state account"
+        );
+    }
+}
@@ -0,0 +1,97 @@
+use anchor_lang::AccountDeserialize;
+use safepay::{Details, GrantCounter, GrantIndex, Stage};
+
+// A `Details` account with its raw `stage` byte decoded
+#[derive(Clone)]
+pub struct GrantState {
+    pub details: Details,
+    pub stage: Stage,
+}
+
+// Decodes the data of a grant or subscription state account, checking its discriminator
+pub fn decode_details(mut data: &[u8]) -> anchor_lang::Result<GrantState> {
+    let details = Details::try_deserialize(&mut data)?;
+    let stage = Stage::from(details.stage)?;
+    Ok(GrantState { details, stage })
+}
+
+// Decodes Alice's counter account, whose `next_idx` is the index of her next grant
+pub fn decode_counter(mut data: &[u8]) -> anchor_lang::Result<GrantCounter> {
+    GrantCounter::try_deserialize(&mut data)
+}
+
+// Decodes a sender or receiver index, listing the state accounts of the wallet's open grants
+pub fn decode_grant_index(mut data: &[u8]) -> anchor_lang::Result<GrantIndex> {
+    GrantIndex::try_deserialize(&mut data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anchor_lang::{prelude::Pubkey, AccountSerialize};
+
+    fn details(stage: u8) -> Details {
+        Details {
+            idx: 3,
+            user_sending: Pubkey::new_unique(),
+            user_receiving: Pubkey::new_unique(),
+            mint_of_token_being_sent: Pubkey::new_unique(),
+            escrow_wallet: Pubkey::new_unique(),
+            amount_tokens: 20_000_000,
+            stage,
+            attester: Pubkey::default(),
+            period_seconds: 0,
+            last_charge_ts: 0,
+            periods_remaining: 0,
+            amendments: 0,
+            fee_basis_points: 0,
+            fee_recipient: Pubkey::default(),
+        }
+    }
+
+    #[test]
+    fn decodes_the_stage() {
+        let mut data = Vec::new();
+        details(Stage::Accepted.to_code())
+            .try_serialize(&mut data)
+            .unwrap();
+
+        let state = decode_details(&data).unwrap();
+        assert_eq!(state.stage, Stage::Accepted);
+        assert_eq!(state.details.amount_tokens, 20_000_000);
+    }
+
+    #[test]
+    fn rejects_unknown_stages_and_other_accounts() {
+        let mut data = Vec::new();
+        details(42).try_serialize(&mut data).unwrap();
+        assert!(decode_details(&data).is_err());
+
+        data[0] ^= 0xff;
+        assert!(decode_details(&data).is_err());
+    }
+
+    #[test]
+    fn decodes_counters_and_indexes() {
+        let mut data = Vec::new();
+        GrantCounter { next_idx: 4 }
+            .try_serialize(&mut data)
+            .unwrap();
+        assert_eq!(decode_counter(&data).unwrap().next_idx, 4);
+        assert!(decode_grant_index(&data).is_err());
+
+        let owner = Pubkey::new_unique();
+        let open_grants = vec![Pubkey::new_unique(), Pubkey::new_unique()];
+        let mut data = Vec::new();
+        GrantIndex {
+            owner,
+            payer: owner,
+            open_grants: open_grants.clone(),
+        }
+        .try_serialize(&mut data)
+        .unwrap();
+        let index = decode_grant_index(&data).unwrap();
+        assert_eq!(index.owner, owner);
+        assert_eq!(index.open_grants, open_grants);
+    }
+}
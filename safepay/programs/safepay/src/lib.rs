@@ -1,15 +1,31 @@
 use anchor_lang::prelude::*;
+use anchor_lang::solana_program::{
+    ed25519_program, keccak,
+    sysvar::instructions::{load_current_index_checked, load_instruction_at_checked},
+};
+use anchor_lang::system_program::{transfer, Transfer};
 use anchor_spl::{
     associated_token::AssociatedToken,
-    token::{close_account, transfer, CloseAccount, Mint, Token, TokenAccount, Transfer},
+    token_2022::spl_token_2022::{
+        self,
+        extension::{
+            transfer_fee::{TransferFeeAmount, TransferFeeConfig},
+            BaseStateWithExtensions, StateWithExtensions,
+        },
+    },
+    token_interface::{
+        approve, close_account, harvest_withheld_tokens_to_mint, revoke, transfer_checked, Approve,
+        CloseAccount, HarvestWithheldTokensToMint, Mint, Revoke, TokenAccount, TokenInterface,
+        TransferChecked,
+    },
 };
 declare_id!("GahQHYEwx2KVdK2zy3sY8CPak8y63XesQLHN9Z7EhgsS");
 
 fn transfer_escrow_out<'info>(
     user_sending: AccountInfo<'info>,
     user_receiving: AccountInfo<'info>,
-    mint_of_token_being_sent: AccountInfo<'info>,
-    escrow_wallet: &mut Account<'info, TokenAccount>,
+    mint_of_token_being_sent: &InterfaceAccount<'info, Mint>,
+    escrow_wallet: &mut InterfaceAccount<'info, TokenAccount>,
     application_idx: u64,
     state: AccountInfo<'info>,
     state_bump: u8,
@@ -29,17 +45,139 @@ fn transfer_escrow_out<'info>(
         &[bump_application_state],
     ]];
 
-    transfer(
+    release_escrow(
+        user_sending,
+        escrow_wallet,
+        mint_of_token_being_sent,
+        state,
+        token_program,
+        destination_wallet,
+        amount,
+        signer,
+    )
+}
+
+#[allow(clippy::too_many_arguments)]
+fn transfer_batch_escrow_out<'info>(
+    user_sending: AccountInfo<'info>,
+    escrow_wallet: &mut InterfaceAccount<'info, TokenAccount>,
+    mint_of_token_being_sent: &InterfaceAccount<'info, Mint>,
+    state: &Account<'info, BatchDetails>,
+    state_bump: u8,
+    token_program: AccountInfo<'info>,
+    destination_wallet: AccountInfo<'info>,
+    amount: u64,
+) -> Result<()> {
+    let batch_idx_bytes: [u8; 8] = state.idx.to_le_bytes();
+    let signer: &[&[&[u8]]] = &[&[
+        b"batch_state".as_ref(),
+        state.user_sending.as_ref(),
+        state.mint_of_token_being_sent.as_ref(),
+        batch_idx_bytes.as_ref(),
+        &[state_bump],
+    ]];
+
+    release_escrow(
+        user_sending,
+        escrow_wallet,
+        mint_of_token_being_sent,
+        state.to_account_info(),
+        token_program,
+        destination_wallet,
+        amount,
+        signer,
+    )
+}
+
+// Pulls one period of a subscription from Alice's wallet, where the `state` PDA is the delegate
+fn transfer_subscription_charge<'info>(
+    application_state: &Account<'info, Details>,
+    state_bump: u8,
+    mint_of_token_being_sent: &InterfaceAccount<'info, Mint>,
+    token_program: AccountInfo<'info>,
+    source_wallet: AccountInfo<'info>,
+    destination_wallet: AccountInfo<'info>,
+) -> Result<()> {
+    let application_idx_bytes: [u8; 8] = application_state.idx.to_le_bytes();
+    let signer: &[&[&[u8]]] = &[&[
+        b"state".as_ref(),
+        application_state.user_sending.as_ref(),
+        application_state.user_receiving.as_ref(),
+        application_state.mint_of_token_being_sent.as_ref(),
+        application_idx_bytes.as_ref(),
+        &[state_bump],
+    ]];
+
+    transfer_checked(
+        CpiContext::new_with_signer(
+            token_program,
+            TransferChecked {
+                from: source_wallet,
+                mint: mint_of_token_being_sent.to_account_info(),
+                to: destination_wallet,
+                authority: application_state.to_account_info(),
+            },
+            signer,
+        ),
+        application_state.amount_tokens,
+        mint_of_token_being_sent.decimals,
+    )
+}
+
+// Moves `amount` from Alice's wallet into a freshly created escrow and returns what the
+// escrow actually received, which is less than `amount` when the mint charges a transfer fee.
+fn deposit_into_escrow<'info>(
+    source_wallet: AccountInfo<'info>,
+    escrow_wallet: &mut InterfaceAccount<'info, TokenAccount>,
+    mint_of_token_being_sent: &InterfaceAccount<'info, Mint>,
+    authority: AccountInfo<'info>,
+    token_program: AccountInfo<'info>,
+    amount: u64,
+) -> Result<u64> {
+    transfer_checked(
+        CpiContext::new(
+            token_program,
+            TransferChecked {
+                from: source_wallet,
+                mint: mint_of_token_being_sent.to_account_info(),
+                to: escrow_wallet.to_account_info(),
+                authority,
+            },
+        ),
+        amount,
+        mint_of_token_being_sent.decimals,
+    )?;
+
+    escrow_wallet.reload()?;
+    Ok(escrow_wallet.amount)
+}
+
+// Moves `amount` out of an escrow owned by the `state` PDA and closes the escrow,
+// refunding its rent to Alice, once it is empty.
+#[allow(clippy::too_many_arguments)]
+fn release_escrow<'info>(
+    user_sending: AccountInfo<'info>,
+    escrow_wallet: &mut InterfaceAccount<'info, TokenAccount>,
+    mint_of_token_being_sent: &InterfaceAccount<'info, Mint>,
+    state: AccountInfo<'info>,
+    token_program: AccountInfo<'info>,
+    destination_wallet: AccountInfo<'info>,
+    amount: u64,
+    signer: &[&[&[u8]]],
+) -> Result<()> {
+    transfer_checked(
         CpiContext::new_with_signer(
             token_program.to_account_info(),
-            Transfer {
+            TransferChecked {
                 from: escrow_wallet.to_account_info(),
+                mint: mint_of_token_being_sent.to_account_info(),
                 to: destination_wallet,
                 authority: state.to_account_info(),
             },
             signer,
         ),
         amount,
+        mint_of_token_being_sent.decimals,
     )?;
 
     // Use the `reload()` function on an account to reload it's state. Since we performed the
@@ -50,6 +188,21 @@ fn transfer_escrow_out<'info>(
     };
 
     if should_close {
+        // Token-2022 refuses to close an account that still holds withheld transfer fees,
+        // anyone can sweep them to the mint where the fee authority collects them.
+        if has_withheld_transfer_fees(&escrow_wallet.to_account_info())? {
+            harvest_withheld_tokens_to_mint(
+                CpiContext::new(
+                    token_program.to_account_info(),
+                    HarvestWithheldTokensToMint {
+                        token_program_id: token_program.to_account_info(),
+                        mint: mint_of_token_being_sent.to_account_info(),
+                    },
+                ),
+                vec![escrow_wallet.to_account_info()],
+            )?;
+        }
+
         let ca = CloseAccount {
             account: escrow_wallet.to_account_info(),
             destination: user_sending.to_account_info(),
@@ -66,6 +219,154 @@ fn transfer_escrow_out<'info>(
     Ok(())
 }
 
+// Transfer fee the mint charges on a transfer that should deliver `amount`, zero for mints
+// without the transfer fee extension.
+fn inverse_transfer_fee(mint: &AccountInfo, amount: u64) -> Result<u64> {
+    if *mint.owner != spl_token_2022::ID {
+        return Ok(0);
+    }
+    let data = mint.try_borrow_data()?;
+    let mint = StateWithExtensions::<spl_token_2022::state::Mint>::unpack(&data)?;
+    let Ok(transfer_fee_config) = mint.get_extension::<TransferFeeConfig>() else {
+        return Ok(0);
+    };
+    transfer_fee_config
+        .calculate_inverse_epoch_fee(Clock::get()?.epoch, amount)
+        .ok_or_else(|| ErrorCode::BatchDepositInsufficient.into())
+}
+
+fn has_withheld_transfer_fees(token_account: &AccountInfo) -> Result<bool> {
+    if *token_account.owner != spl_token_2022::ID {
+        return Ok(false);
+    }
+    let data = token_account.try_borrow_data()?;
+    let account = StateWithExtensions::<spl_token_2022::state::Account>::unpack(&data)?;
+    Ok(account
+        .get_extension::<TransferFeeAmount>()
+        .map(|fee| u64::from(fee.withheld_amount) > 0)
+        .unwrap_or(false))
+}
+
+// Looks for an ed25519 program instruction in the current transaction carrying a single
+// signature by `attester` over `message`. The signature itself is checked by the
+// ed25519 precompile, which fails the whole transaction if it does not verify.
+fn verify_attestation(instructions: &AccountInfo, attester: &Pubkey, message: &[u8]) -> Result<()> {
+    let current_index = load_current_index_checked(instructions)?;
+    for index in 0..current_index {
+        let ix = load_instruction_at_checked(index as usize, instructions)?;
+        if ix.program_id != ed25519_program::ID {
+            continue;
+        }
+
+        // [num_signatures, padding, signature_offset, signature_instruction_index,
+        //  public_key_offset, public_key_instruction_index, message_data_offset,
+        //  message_data_size, message_instruction_index]
+        let data = &ix.data;
+        if data.len() < 16 || data[0] != 1 {
+            continue;
+        }
+        let read_u16 = |at: usize| u16::from_le_bytes([data[at], data[at + 1]]);
+        let same_instruction =
+            read_u16(4) == u16::MAX && read_u16(8) == u16::MAX && read_u16(14) == u16::MAX;
+        if !same_instruction {
+            continue;
+        }
+
+        let public_key_offset = read_u16(6) as usize;
+        let message_offset = read_u16(10) as usize;
+        let message_size = read_u16(12) as usize;
+        let public_key = data.get(public_key_offset..public_key_offset + 32);
+        let signed_message = data.get(message_offset..message_offset + message_size);
+        if public_key == Some(attester.as_ref()) && signed_message == Some(message) {
+            return Ok(());
+        }
+    }
+
+    msg!("No attestation by {} found in the transaction", attester);
+    Err(ErrorCode::AttestationMissing.into())
+}
+
+// Adds `grant` to a wallet's index, growing the account by one entry whose rent `payer` covers.
+// Settling a grant loads the whole index, so it is capped to stay within the heap and compute
+// limits. Bob can decline grants to make room in his.
+fn add_to_grant_index<'info>(
+    index: &mut Account<'info, GrantIndex>,
+    owner: Pubkey,
+    grant: Pubkey,
+    payer: AccountInfo<'info>,
+    system_program: AccountInfo<'info>,
+) -> Result<()> {
+    if index.open_grants.len() >= MAX_OPEN_GRANTS {
+        return Err(ErrorCode::GrantIndexFull.into());
+    }
+
+    let payer_key = payer.key();
+    let index_info = index.to_account_info();
+    let new_len = GrantIndex::space(index.open_grants.len() + 1);
+    let rent = Rent::get()?
+        .minimum_balance(new_len)
+        .saturating_sub(index_info.lamports());
+    if rent > 0 {
+        transfer(
+            CpiContext::new(
+                system_program,
+                Transfer {
+                    from: payer,
+                    to: index_info.clone(),
+                },
+            ),
+            rent,
+        )?;
+    }
+    index_info.realloc(new_len, false)?;
+
+    // Whoever creates the index gets its base rent back once it is closed
+    if index.payer == Pubkey::default() {
+        index.payer = payer_key;
+    }
+    index.owner = owner;
+    index.open_grants.push(grant);
+    Ok(())
+}
+
+// Removes `grant` from a wallet's index and refunds the rent it freed to Alice, who paid for the
+// entry. Once nothing is left in it the index is closed if Alice also created it, otherwise it
+// stays empty until its payer closes it with `close_grant_index`.
+fn remove_from_grant_index<'info>(
+    index: &mut Account<'info, GrantIndex>,
+    grant: Pubkey,
+    user_sending: AccountInfo<'info>,
+) -> Result<()> {
+    index.open_grants.retain(|open_grant| *open_grant != grant);
+    if index.open_grants.is_empty() && index.payer == user_sending.key() {
+        return index.close(user_sending);
+    }
+
+    let index_info = index.to_account_info();
+    let new_len = GrantIndex::space(index.open_grants.len());
+    index_info.realloc(new_len, false)?;
+    let refund = index_info
+        .lamports()
+        .saturating_sub(Rent::get()?.minimum_balance(new_len));
+    **index_info.try_borrow_mut_lamports()? -= refund;
+    **user_sending.try_borrow_mut_lamports()? += refund;
+    Ok(())
+}
+
+// Leaves are `keccak(index || recipient || amount)` and pairs are hashed in sorted order,
+// so the proof does not need to carry left/right flags.
+fn verify_batch_proof(proof: &[[u8; 32]], root: [u8; 32], leaf: [u8; 32]) -> bool {
+    let mut computed = leaf;
+    for node in proof {
+        computed = if computed <= *node {
+            keccak::hashv(&[&computed, node]).0
+        } else {
+            keccak::hashv(&[node, &computed]).0
+        };
+    }
+    computed == root
+}
+
 #[program]
 pub mod safepay {
 
@@ -73,9 +374,26 @@ pub mod safepay {
 
     pub fn initialize_new_grant(
         ctx: Context<InitializeNewGrant>,
-        application_idx: u64,
         amount: u64,
+        attester: Option<Pubkey>,
     ) -> Result<()> {
+        let application_idx = ctx.accounts.grant_counter.take_next_idx()?;
+        let application_state_key = ctx.accounts.application_state.key();
+        add_to_grant_index(
+            &mut ctx.accounts.sender_index,
+            ctx.accounts.user_sending.key(),
+            application_state_key,
+            ctx.accounts.user_sending.to_account_info(),
+            ctx.accounts.system_program.to_account_info(),
+        )?;
+        add_to_grant_index(
+            &mut ctx.accounts.receiver_index,
+            ctx.accounts.user_receiving.key(),
+            application_state_key,
+            ctx.accounts.user_sending.to_account_info(),
+            ctx.accounts.system_program.to_account_info(),
+        )?;
+
         let details = &mut ctx.accounts.application_state;
         details.idx = application_idx;
         details.amount_tokens = amount;
@@ -83,26 +401,38 @@ pub mod safepay {
         details.user_receiving = ctx.accounts.user_receiving.key().clone();
         details.mint_of_token_being_sent = ctx.accounts.mint_of_token_being_sent.key().clone();
         details.escrow_wallet = ctx.accounts.escrow_wallet_state.key().clone();
+        details.attester = attester.unwrap_or_default();
+        (details.fee_basis_points, details.fee_recipient) = Config::fee_terms(
+            &ctx.accounts.config,
+            &ctx.accounts.mint_of_token_being_sent.key(),
+        )?;
 
         msg!("Initialized new Safe Transfer instance for {}", amount);
 
-        transfer(
-            CpiContext::new(
-                ctx.accounts.token_program.to_account_info(),
-                Transfer {
-                    from: ctx.accounts.wallet_to_withdraw_from.to_account_info(),
-                    to: ctx.accounts.escrow_wallet_state.to_account_info(),
-                    authority: ctx.accounts.user_sending.to_account_info(),
-                },
-            ),
+        // With a transfer fee the escrow holds less than `amount`, only that can be released
+        details.amount_tokens = deposit_into_escrow(
+            ctx.accounts.wallet_to_withdraw_from.to_account_info(),
+            &mut ctx.accounts.escrow_wallet_state,
+            &ctx.accounts.mint_of_token_being_sent,
+            ctx.accounts.user_sending.to_account_info(),
+            ctx.accounts.token_program.to_account_info(),
             amount,
         )?;
         details.stage = Stage::FundsDeposited.to_code();
 
+        emit!(GrantCreated {
+            user_sending: details.user_sending,
+            user_receiving: details.user_receiving,
+            mint_of_token_being_sent: details.mint_of_token_being_sent,
+            application_idx: details.idx,
+            amount: details.amount_tokens,
+            stage: details.stage,
+        });
+
         Ok(())
     }
 
-    pub fn complete_grant(ctx: Context<CompleteGrant>, application_idx: u64) -> Result<()> {
+    pub fn accept_grant(ctx: Context<AcceptGrant>, _application_idx: u64) -> Result<()> {
         if Stage::from(ctx.accounts.application_state.stage)? != Stage::FundsDeposited {
             msg!(
                 "Stage is invalid, state stage is {}",
@@ -111,28 +441,190 @@ pub mod safepay {
             return Err(ErrorCode::StageInvalid.into());
         }
 
+        let state = &mut ctx.accounts.application_state;
+        state.stage = Stage::Accepted.to_code();
+
+        emit!(GrantAccepted {
+            user_sending: state.user_sending,
+            user_receiving: state.user_receiving,
+            mint_of_token_being_sent: state.mint_of_token_being_sent,
+            application_idx: state.idx,
+            amount: state.amount_tokens,
+            stage: state.stage,
+        });
+        Ok(())
+    }
+
+    pub fn amend_grant(
+        ctx: Context<AmendGrant>,
+        application_idx: u64,
+        amount: u64,
+        is_top_up: bool,
+    ) -> Result<()> {
+        let current_stage = Stage::from(ctx.accounts.application_state.stage)?;
+        if current_stage != Stage::FundsDeposited && current_stage != Stage::Accepted {
+            msg!(
+                "Stage is invalid, state stage is {}",
+                ctx.accounts.application_state.stage
+            );
+            return Err(ErrorCode::StageInvalid.into());
+        }
+        // Bob agreed to the original terms, so he has to agree to the new ones too
+        if current_stage == Stage::Accepted && !ctx.accounts.user_receiving.is_signer {
+            return Err(ErrorCode::ReceiverSignatureRequired.into());
+        }
+        // Taking funds out of an attested grant would get around the attester
+        if !is_top_up
+            && ctx.accounts.application_state.attester != Pubkey::default()
+            && !ctx.accounts.user_receiving.is_signer
+        {
+            return Err(ErrorCode::ReceiverSignatureRequired.into());
+        }
+        if amount == 0 {
+            return Err(ErrorCode::AmendmentInvalid.into());
+        }
+
+        if is_top_up {
+            ctx.accounts.application_state.amount_tokens = deposit_into_escrow(
+                ctx.accounts.sender_wallet.to_account_info(),
+                &mut ctx.accounts.escrow_wallet_state,
+                &ctx.accounts.mint_of_token_being_sent,
+                ctx.accounts.user_sending.to_account_info(),
+                ctx.accounts.token_program.to_account_info(),
+                amount,
+            )?;
+        } else {
+            // Cancelling the whole grant is what `pull_back` is for
+            if amount >= ctx.accounts.application_state.amount_tokens {
+                return Err(ErrorCode::AmendmentInvalid.into());
+            }
+            transfer_escrow_out(
+                ctx.accounts.user_sending.to_account_info(),
+                ctx.accounts.user_receiving.to_account_info(),
+                &ctx.accounts.mint_of_token_being_sent,
+                &mut ctx.accounts.escrow_wallet_state,
+                application_idx,
+                ctx.accounts.application_state.to_account_info(),
+                ctx.bumps.application_state,
+                ctx.accounts.token_program.to_account_info(),
+                ctx.accounts.sender_wallet.to_account_info(),
+                amount,
+            )?;
+            ctx.accounts.application_state.amount_tokens -= amount;
+        }
+
+        let state = &mut ctx.accounts.application_state;
+        state.amendments = state
+            .amendments
+            .checked_add(1)
+            .ok_or(ErrorCode::AmendmentInvalid)?;
+
+        emit!(GrantAmended {
+            user_sending: state.user_sending,
+            user_receiving: state.user_receiving,
+            mint_of_token_being_sent: state.mint_of_token_being_sent,
+            application_idx: state.idx,
+            amount: state.amount_tokens,
+            amendments: state.amendments,
+            stage: state.stage,
+        });
+
+        Ok(())
+    }
+
+    pub fn complete_grant(ctx: Context<CompleteGrant>, application_idx: u64) -> Result<()> {
+        let current_stage = Stage::from(ctx.accounts.application_state.stage)?;
+        if current_stage != Stage::FundsDeposited && current_stage != Stage::Accepted {
+            msg!(
+                "Stage is invalid, state stage is {}",
+                ctx.accounts.application_state.stage
+            );
+            return Err(ErrorCode::StageInvalid.into());
+        }
+        // Only the attester's verdict releases an attested grant, see `settle_with_attestation`
+        if ctx.accounts.application_state.attester != Pubkey::default() {
+            return Err(ErrorCode::AttestationRequired.into());
+        }
+
+        let amount = ctx.accounts.application_state.amount_tokens;
+        let fee = ctx.accounts.application_state.fee_for(amount)?;
+        if fee > 0 {
+            let fee_wallet = match &ctx.accounts.fee_wallet {
+                Some(fee_wallet) => fee_wallet.to_account_info(),
+                None => return Err(ErrorCode::FeeWalletMissing.into()),
+            };
+            transfer_escrow_out(
+                ctx.accounts.user_sending.to_account_info(),
+                ctx.accounts.user_receiving.to_account_info(),
+                &ctx.accounts.mint_of_token_being_sent,
+                &mut ctx.accounts.escrow_wallet_state,
+                application_idx,
+                ctx.accounts.application_state.to_account_info(),
+                ctx.bumps.application_state,
+                ctx.accounts.token_program.to_account_info(),
+                fee_wallet,
+                fee,
+            )?;
+        }
+
         transfer_escrow_out(
             ctx.accounts.user_sending.to_account_info(),
             ctx.accounts.user_receiving.to_account_info(),
-            ctx.accounts.mint_of_token_being_sent.to_account_info(),
+            &ctx.accounts.mint_of_token_being_sent,
             &mut ctx.accounts.escrow_wallet_state,
             application_idx,
             ctx.accounts.application_state.to_account_info(),
             ctx.bumps.application_state,
             ctx.accounts.token_program.to_account_info(),
             ctx.accounts.wallet_to_deposit_to.to_account_info(),
-            ctx.accounts.application_state.amount_tokens,
+            amount - fee,
+        )?;
+
+        let application_state_key = ctx.accounts.application_state.key();
+        remove_from_grant_index(
+            &mut ctx.accounts.sender_index,
+            application_state_key,
+            ctx.accounts.user_sending.to_account_info(),
+        )?;
+        remove_from_grant_index(
+            &mut ctx.accounts.receiver_index,
+            application_state_key,
+            ctx.accounts.user_sending.to_account_info(),
         )?;
 
         let state = &mut ctx.accounts.application_state;
         state.stage = Stage::EscrowComplete.to_code();
+
+        // The state account is closed on exit, so the event is the record of the outcome
+        emit!(GrantCompleted {
+            user_sending: state.user_sending,
+            user_receiving: state.user_receiving,
+            mint_of_token_being_sent: state.mint_of_token_being_sent,
+            application_idx: state.idx,
+            amount: state.amount_tokens,
+            fee,
+            stage: state.stage,
+        });
         Ok(())
     }
 
     pub fn pull_back(ctx: Context<PullBackFunds>, application_idx: u64) -> Result<()> {
         let current_stage = Stage::from(ctx.accounts.application_state.stage)?;
-        let is_valid_stage =
-            current_stage == Stage::FundsDeposited || current_stage == Stage::PullBackComplete;
+        let authority = ctx.accounts.authority.key();
+        let is_valid_stage = if authority == ctx.accounts.user_sending.key() {
+            // Only the attester's verdict refunds Alice from an attested grant
+            if ctx.accounts.application_state.attester != Pubkey::default() {
+                return Err(ErrorCode::AttestationRequired.into());
+            }
+            // Alice can only cancel until Bob has accepted the grant
+            current_stage == Stage::FundsDeposited
+        } else if authority == ctx.accounts.user_receiving.key() {
+            // Bob can decline the grant whether or not he accepted it before
+            current_stage == Stage::FundsDeposited || current_stage == Stage::Accepted
+        } else {
+            msg!("{} is neither the sender nor the receiver", authority);
+            return Err(ErrorCode::UnauthorizedSigner.into());
+        };
         if !is_valid_stage {
             msg!(
                 "Stage is invalid, state stage is {}",
@@ -144,7 +636,7 @@ pub mod safepay {
         transfer_escrow_out(
             ctx.accounts.user_sending.to_account_info(),
             ctx.accounts.user_receiving.to_account_info(),
-            ctx.accounts.mint_of_token_being_sent.to_account_info(),
+            &ctx.accounts.mint_of_token_being_sent,
             &mut ctx.accounts.escrow_wallet_state,
             application_idx,
             ctx.accounts.application_state.to_account_info(),
@@ -154,173 +646,1760 @@ pub mod safepay {
             ctx.accounts.application_state.amount_tokens,
         )?;
 
+        let application_state_key = ctx.accounts.application_state.key();
+        remove_from_grant_index(
+            &mut ctx.accounts.sender_index,
+            application_state_key,
+            ctx.accounts.user_sending.to_account_info(),
+        )?;
+        remove_from_grant_index(
+            &mut ctx.accounts.receiver_index,
+            application_state_key,
+            ctx.accounts.user_sending.to_account_info(),
+        )?;
+
         let state = &mut ctx.accounts.application_state;
         state.stage = Stage::PullBackComplete.to_code();
 
+        emit!(GrantPulledBack {
+            user_sending: state.user_sending,
+            user_receiving: state.user_receiving,
+            mint_of_token_being_sent: state.mint_of_token_being_sent,
+            application_idx: state.idx,
+            amount: state.amount_tokens,
+            stage: state.stage,
+            authority,
+        });
+
         Ok(())
     }
-}
-
-#[derive(Clone, Copy, PartialEq)]
-pub enum Stage {
-    // Safe Pay withdrew funds from Alice and deposited them into the escrow wallet
-    FundsDeposited,
 
-    // {from FundsDeposited} Bob withdrew the funds from the escrow. We are done.
-    EscrowComplete,
+    pub fn settle_with_attestation(
+        ctx: Context<SettleWithAttestation>,
+        application_idx: u64,
+        outcome: u8,
+    ) -> Result<()> {
+        let current_stage = Stage::from(ctx.accounts.application_state.stage)?;
+        if current_stage != Stage::FundsDeposited && current_stage != Stage::Accepted {
+            msg!(
+                "Stage is invalid, state stage is {}",
+                ctx.accounts.application_state.stage
+            );
+            return Err(ErrorCode::StageInvalid.into());
+        }
 
-    // {from FundsDeposited} Alice pulled back the funds
-    PullBackComplete,
-}
-impl Stage {
-    fn to_code(&self) -> u8 {
-        match self {
-            Stage::FundsDeposited => 1,
-            Stage::EscrowComplete => 2,
-            Stage::PullBackComplete => 3,
+        let attester = ctx.accounts.application_state.attester;
+        if attester == Pubkey::default() {
+            return Err(ErrorCode::AttesterNotSet.into());
         }
-    }
+        if outcome != ATTESTATION_RELEASE && outcome != ATTESTATION_REFUND {
+            return Err(ErrorCode::AttestationOutcomeInvalid.into());
+        }
+        let mut message = ctx.accounts.application_state.key().to_bytes().to_vec();
+        message.push(outcome);
+        verify_attestation(&ctx.accounts.instructions, &attester, &message)?;
 
-    fn from(val: u8) -> Result<Stage> {
-        match val {
-            1 => Ok(Stage::FundsDeposited),
-            2 => Ok(Stage::EscrowComplete),
-            3 => Ok(Stage::PullBackComplete),
-            unknown_value => {
-                msg!("Unknown stage: {}", unknown_value);
-                Err(ErrorCode::StageInvalid.into())
-            }
+        let amount = ctx.accounts.application_state.amount_tokens;
+        let fee = if outcome == ATTESTATION_RELEASE {
+            ctx.accounts.application_state.fee_for(amount)?
+        } else {
+            0
+        };
+        if fee > 0 {
+            let fee_wallet = match &ctx.accounts.fee_wallet {
+                Some(fee_wallet) => fee_wallet.to_account_info(),
+                None => return Err(ErrorCode::FeeWalletMissing.into()),
+            };
+            transfer_escrow_out(
+                ctx.accounts.user_sending.to_account_info(),
+                ctx.accounts.user_receiving.to_account_info(),
+                &ctx.accounts.mint_of_token_being_sent,
+                &mut ctx.accounts.escrow_wallet_state,
+                application_idx,
+                ctx.accounts.application_state.to_account_info(),
+                ctx.bumps.application_state,
+                ctx.accounts.token_program.to_account_info(),
+                fee_wallet,
+                fee,
+            )?;
         }
-    }
-}
 
-#[derive(Accounts)]
-#[instruction(application_idx: u64)]
-pub struct InitializeNewGrant<'info> {
-    // Derived PDAs
-    #[account(
-        init,
-        payer = user_sending,
-        seeds=[b"state".as_ref(), user_sending.key().as_ref(), user_receiving.key.as_ref(), mint_of_token_being_sent.key().as_ref(), application_idx.to_le_bytes().as_ref()],
-        bump,
-        space = 8 + std::mem::size_of::<Details>()
-    )]
-    application_state: Account<'info, Details>,
+        let destination_wallet = if outcome == ATTESTATION_RELEASE {
+            ctx.accounts.wallet_to_deposit_to.to_account_info()
+        } else {
+            ctx.accounts.refund_wallet.to_account_info()
+        };
+        transfer_escrow_out(
+            ctx.accounts.user_sending.to_account_info(),
+            ctx.accounts.user_receiving.to_account_info(),
+            &ctx.accounts.mint_of_token_being_sent,
+            &mut ctx.accounts.escrow_wallet_state,
+            application_idx,
+            ctx.accounts.application_state.to_account_info(),
+            ctx.bumps.application_state,
+            ctx.accounts.token_program.to_account_info(),
+            destination_wallet,
+            amount - fee,
+        )?;
 
-    #[account(
-        init,
-        payer = user_sending,
-        seeds=[b"wallet".as_ref(), user_sending.key().as_ref(), user_receiving.key.as_ref(), mint_of_token_being_sent.key().as_ref(), application_idx.to_le_bytes().as_ref()],
-        bump,
-        token::mint = mint_of_token_being_sent,
-        token::authority = application_state,
-    )]
-    escrow_wallet_state: Account<'info, TokenAccount>,
+        let application_state_key = ctx.accounts.application_state.key();
+        remove_from_grant_index(
+            &mut ctx.accounts.sender_index,
+            application_state_key,
+            ctx.accounts.user_sending.to_account_info(),
+        )?;
+        remove_from_grant_index(
+            &mut ctx.accounts.receiver_index,
+            application_state_key,
+            ctx.accounts.user_sending.to_account_info(),
+        )?;
 
-    // Users and accounts in the system
-    #[account(mut)]
-    user_sending: Signer<'info>, // Alice
-    /// CHECK: This field holds the account information for the receiving user.
-    user_receiving: AccountInfo<'info>, // Bob
-    mint_of_token_being_sent: Account<'info, Mint>, // USDC
+        let state = &mut ctx.accounts.application_state;
+        if outcome == ATTESTATION_RELEASE {
+            state.stage = Stage::EscrowComplete.to_code();
+            emit!(GrantCompleted {
+                user_sending: state.user_sending,
+                user_receiving: state.user_receiving,
+                mint_of_token_being_sent: state.mint_of_token_being_sent,
+                application_idx: state.idx,
+                amount: state.amount_tokens,
+                fee,
+                stage: state.stage,
+            });
+        } else {
+            state.stage = Stage::PullBackComplete.to_code();
+            emit!(GrantPulledBack {
+                user_sending: state.user_sending,
+                user_receiving: state.user_receiving,
+                mint_of_token_being_sent: state.mint_of_token_being_sent,
+                application_idx: state.idx,
+                amount: state.amount_tokens,
+                stage: state.stage,
+                authority: attester,
+            });
+        }
 
-    // Alice's USDC wallet that has already approved the escrow wallet
-    #[account(
-            mut,
-            constraint=wallet_to_withdraw_from.owner == user_sending.key(),
-            constraint=wallet_to_withdraw_from.mint == mint_of_token_being_sent.key()
-        )]
-    wallet_to_withdraw_from: Account<'info, TokenAccount>,
+        Ok(())
+    }
 
-    // Application level accounts
-    system_program: Program<'info, System>,
-    token_program: Program<'info, Token>,
+    pub fn initialize_new_swap(
+        ctx: Context<InitializeNewSwap>,
+        amount: u64,
+        amount_expected: u64,
+    ) -> Result<()> {
+        let application_idx = ctx.accounts.grant_counter.take_next_idx()?;
+        let application_state_key = ctx.accounts.application_state.key();
+        add_to_grant_index(
+            &mut ctx.accounts.sender_index,
+            ctx.accounts.user_sending.key(),
+            application_state_key,
+            ctx.accounts.user_sending.to_account_info(),
+            ctx.accounts.system_program.to_account_info(),
+        )?;
+        add_to_grant_index(
+            &mut ctx.accounts.receiver_index,
+            ctx.accounts.user_receiving.key(),
+            application_state_key,
+            ctx.accounts.user_sending.to_account_info(),
+            ctx.accounts.system_program.to_account_info(),
+        )?;
+
+        let details = &mut ctx.accounts.application_state;
+        details.idx = application_idx;
+        details.amount_tokens = amount;
+        details.amount_tokens_expected = amount_expected;
+        details.user_sending = ctx.accounts.user_sending.key();
+        details.user_receiving = ctx.accounts.user_receiving.key();
+        details.mint_of_token_being_sent = ctx.accounts.mint_of_token_being_sent.key();
+        details.mint_of_token_expected = ctx.accounts.mint_of_token_expected.key();
+        details.escrow_wallet = ctx.accounts.escrow_wallet_state.key();
+
+        msg!(
+            "Initialized new Swap instance for {} in exchange of {}",
+            amount,
+            amount_expected
+        );
+
+        // With a transfer fee the escrow holds less than `amount`, only that can be released
+        details.amount_tokens = deposit_into_escrow(
+            ctx.accounts.wallet_to_withdraw_from.to_account_info(),
+            &mut ctx.accounts.escrow_wallet_state,
+            &ctx.accounts.mint_of_token_being_sent,
+            ctx.accounts.user_sending.to_account_info(),
+            ctx.accounts.token_program.to_account_info(),
+            amount,
+        )?;
+        details.stage = Stage::FundsDeposited.to_code();
+
+        emit!(SwapCreated {
+            user_sending: details.user_sending,
+            user_receiving: details.user_receiving,
+            mint_of_token_being_sent: details.mint_of_token_being_sent,
+            mint_of_token_expected: details.mint_of_token_expected,
+            application_idx: details.idx,
+            amount: details.amount_tokens,
+            amount_expected: details.amount_tokens_expected,
+            stage: details.stage,
+        });
+
+        Ok(())
+    }
+
+    pub fn accept_swap(ctx: Context<AcceptSwap>, application_idx: u64) -> Result<()> {
+        if Stage::from(ctx.accounts.application_state.stage)? != Stage::FundsDeposited {
+            msg!(
+                "Stage is invalid, state stage is {}",
+                ctx.accounts.application_state.stage
+            );
+            return Err(ErrorCode::StageInvalid.into());
+        }
+
+        // Bob's leg: the expected tokens go straight from Bob to Alice
+        transfer_checked(
+            CpiContext::new(
+                ctx.accounts.expected_token_program.to_account_info(),
+                TransferChecked {
+                    from: ctx.accounts.wallet_to_withdraw_from.to_account_info(),
+                    mint: ctx.accounts.mint_of_token_expected.to_account_info(),
+                    to: ctx.accounts.sender_wallet_to_deposit_to.to_account_info(),
+                    authority: ctx.accounts.user_receiving.to_account_info(),
+                },
+            ),
+            ctx.accounts.application_state.amount_tokens_expected,
+            ctx.accounts.mint_of_token_expected.decimals,
+        )?;
+
+        // Alice's leg: the escrowed tokens are released to Bob
+        transfer_escrow_out(
+            ctx.accounts.user_sending.to_account_info(),
+            ctx.accounts.user_receiving.to_account_info(),
+            &ctx.accounts.mint_of_token_being_sent,
+            &mut ctx.accounts.escrow_wallet_state,
+            application_idx,
+            ctx.accounts.application_state.to_account_info(),
+            ctx.bumps.application_state,
+            ctx.accounts.token_program.to_account_info(),
+            ctx.accounts.wallet_to_deposit_to.to_account_info(),
+            ctx.accounts.application_state.amount_tokens,
+        )?;
+
+        let application_state_key = ctx.accounts.application_state.key();
+        remove_from_grant_index(
+            &mut ctx.accounts.sender_index,
+            application_state_key,
+            ctx.accounts.user_sending.to_account_info(),
+        )?;
+        remove_from_grant_index(
+            &mut ctx.accounts.receiver_index,
+            application_state_key,
+            ctx.accounts.user_sending.to_account_info(),
+        )?;
+
+        let state = &mut ctx.accounts.application_state;
+        state.stage = Stage::EscrowComplete.to_code();
+
+        emit!(SwapCompleted {
+            user_sending: state.user_sending,
+            user_receiving: state.user_receiving,
+            mint_of_token_being_sent: state.mint_of_token_being_sent,
+            mint_of_token_expected: state.mint_of_token_expected,
+            application_idx: state.idx,
+            amount: state.amount_tokens,
+            amount_expected: state.amount_tokens_expected,
+            stage: state.stage,
+        });
+        Ok(())
+    }
+
+    pub fn cancel_swap(ctx: Context<CancelSwap>, application_idx: u64) -> Result<()> {
+        if Stage::from(ctx.accounts.application_state.stage)? != Stage::FundsDeposited {
+            msg!(
+                "Stage is invalid, state stage is {}",
+                ctx.accounts.application_state.stage
+            );
+            return Err(ErrorCode::StageInvalid.into());
+        }
+
+        transfer_escrow_out(
+            ctx.accounts.user_sending.to_account_info(),
+            ctx.accounts.user_receiving.to_account_info(),
+            &ctx.accounts.mint_of_token_being_sent,
+            &mut ctx.accounts.escrow_wallet_state,
+            application_idx,
+            ctx.accounts.application_state.to_account_info(),
+            ctx.bumps.application_state,
+            ctx.accounts.token_program.to_account_info(),
+            ctx.accounts.refund_wallet.to_account_info(),
+            ctx.accounts.application_state.amount_tokens,
+        )?;
+
+        let application_state_key = ctx.accounts.application_state.key();
+        remove_from_grant_index(
+            &mut ctx.accounts.sender_index,
+            application_state_key,
+            ctx.accounts.user_sending.to_account_info(),
+        )?;
+        remove_from_grant_index(
+            &mut ctx.accounts.receiver_index,
+            application_state_key,
+            ctx.accounts.user_sending.to_account_info(),
+        )?;
+
+        let state = &mut ctx.accounts.application_state;
+        state.stage = Stage::PullBackComplete.to_code();
+
+        emit!(SwapCancelled {
+            user_sending: state.user_sending,
+            user_receiving: state.user_receiving,
+            mint_of_token_being_sent: state.mint_of_token_being_sent,
+            mint_of_token_expected: state.mint_of_token_expected,
+            application_idx: state.idx,
+            amount: state.amount_tokens,
+            amount_expected: state.amount_tokens_expected,
+            stage: state.stage,
+        });
+
+        Ok(())
+    }
+
+    pub fn initialize_new_batch_grant(
+        ctx: Context<InitializeNewBatchGrant>,
+        batch_idx: u64,
+        num_recipients: u32,
+        amount: u64,
+        merkle_root: [u8; 32],
+        expires_at: i64,
+    ) -> Result<()> {
+        if num_recipients == 0 || num_recipients > MAX_BATCH_RECIPIENTS {
+            return Err(ErrorCode::BatchRecipientsInvalid.into());
+        }
+        if expires_at <= Clock::get()?.unix_timestamp {
+            return Err(ErrorCode::BatchExpiryInvalid.into());
+        }
+
+        let details = &mut ctx.accounts.batch_state;
+        details.idx = batch_idx;
+        details.amount_tokens = amount;
+        details.amount_claimed = 0;
+        details.num_recipients = num_recipients;
+        details.merkle_root = merkle_root;
+        details.claimed_bitmap = vec![0; BatchDetails::bitmap_len(num_recipients)];
+        details.expires_at = expires_at;
+        details.user_sending = ctx.accounts.user_sending.key();
+        details.mint_of_token_being_sent = ctx.accounts.mint_of_token_being_sent.key();
+        details.escrow_wallet = ctx.accounts.escrow_wallet_state.key();
+
+        msg!(
+            "Initialized new Batch Safe Transfer instance for {} across {} recipients",
+            amount,
+            num_recipients
+        );
+
+        // The leaves add up to `amount`, so Alice also pays the transfer fee of the deposit
+        // for the escrow to end up with all of it
+        let transfer_fee = inverse_transfer_fee(
+            &ctx.accounts.mint_of_token_being_sent.to_account_info(),
+            amount,
+        )?;
+        let deposit = amount
+            .checked_add(transfer_fee)
+            .ok_or(ErrorCode::BatchDepositInsufficient)?;
+        let received = deposit_into_escrow(
+            ctx.accounts.wallet_to_withdraw_from.to_account_info(),
+            &mut ctx.accounts.escrow_wallet_state,
+            &ctx.accounts.mint_of_token_being_sent,
+            ctx.accounts.user_sending.to_account_info(),
+            ctx.accounts.token_program.to_account_info(),
+            deposit,
+        )?;
+        if received < amount {
+            return Err(ErrorCode::BatchDepositInsufficient.into());
+        }
+        details.stage = Stage::FundsDeposited.to_code();
+
+        emit!(BatchGrantCreated {
+            user_sending: details.user_sending,
+            mint_of_token_being_sent: details.mint_of_token_being_sent,
+            batch_idx: details.idx,
+            amount: details.amount_tokens,
+            num_recipients: details.num_recipients,
+            stage: details.stage,
+        });
+
+        Ok(())
+    }
+
+    pub fn claim_batch_grant(
+        ctx: Context<ClaimBatchGrant>,
+        _batch_idx: u64,
+        index: u32,
+        amount: u64,
+        proof: Vec<[u8; 32]>,
+    ) -> Result<()> {
+        let details = &ctx.accounts.batch_state;
+        if Stage::from(details.stage)? != Stage::FundsDeposited {
+            msg!("Stage is invalid, state stage is {}", details.stage);
+            return Err(ErrorCode::StageInvalid.into());
+        }
+        if index >= details.num_recipients {
+            return Err(ErrorCode::BatchRecipientIndexInvalid.into());
+        }
+        if details.is_claimed(index) {
+            return Err(ErrorCode::BatchAlreadyClaimed.into());
+        }
+
+        let leaf = keccak::hashv(&[
+            &index.to_le_bytes(),
+            ctx.accounts.user_receiving.key.as_ref(),
+            &amount.to_le_bytes(),
+        ])
+        .0;
+        if !verify_batch_proof(&proof, details.merkle_root, leaf) {
+            return Err(ErrorCode::BatchProofInvalid.into());
+        }
+
+        let amount_claimed = details
+            .amount_claimed
+            .checked_add(amount)
+            .ok_or(ErrorCode::BatchAmountExceeded)?;
+        if amount_claimed > details.amount_tokens {
+            return Err(ErrorCode::BatchAmountExceeded.into());
+        }
+
+        transfer_batch_escrow_out(
+            ctx.accounts.user_sending.to_account_info(),
+            &mut ctx.accounts.escrow_wallet_state,
+            &ctx.accounts.mint_of_token_being_sent,
+            &ctx.accounts.batch_state,
+            ctx.bumps.batch_state,
+            ctx.accounts.token_program.to_account_info(),
+            ctx.accounts.wallet_to_deposit_to.to_account_info(),
+            amount,
+        )?;
+
+        let state = &mut ctx.accounts.batch_state;
+        state.set_claimed(index);
+        state.amount_claimed = amount_claimed;
+        // Rounding of the transfer fee can leave dust behind, Alice reclaims it once expired
+        let is_fully_claimed = state.amount_claimed == state.amount_tokens
+            && ctx.accounts.escrow_wallet_state.amount == 0;
+        if is_fully_claimed {
+            state.stage = Stage::EscrowComplete.to_code();
+        }
+
+        emit!(BatchGrantClaimed {
+            user_sending: state.user_sending,
+            user_receiving: ctx.accounts.user_receiving.key(),
+            mint_of_token_being_sent: state.mint_of_token_being_sent,
+            batch_idx: state.idx,
+            index,
+            amount,
+            stage: state.stage,
+        });
+
+        if is_fully_claimed {
+            // Alice paid for the batch state, so she gets its rent back
+            state.close(ctx.accounts.user_sending.to_account_info())?;
+        }
+
+        Ok(())
+    }
+
+    // Once the batch expired Alice takes back whatever was not claimed and closes it
+    pub fn reclaim_batch_grant(ctx: Context<ReclaimBatchGrant>, _batch_idx: u64) -> Result<()> {
+        let details = &ctx.accounts.batch_state;
+        if Stage::from(details.stage)? != Stage::FundsDeposited {
+            msg!("Stage is invalid, state stage is {}", details.stage);
+            return Err(ErrorCode::StageInvalid.into());
+        }
+        if Clock::get()?.unix_timestamp < details.expires_at {
+            return Err(ErrorCode::BatchNotExpired.into());
+        }
+
+        let amount = ctx.accounts.escrow_wallet_state.amount;
+        transfer_batch_escrow_out(
+            ctx.accounts.user_sending.to_account_info(),
+            &mut ctx.accounts.escrow_wallet_state,
+            &ctx.accounts.mint_of_token_being_sent,
+            &ctx.accounts.batch_state,
+            ctx.bumps.batch_state,
+            ctx.accounts.token_program.to_account_info(),
+            ctx.accounts.refund_wallet.to_account_info(),
+            amount,
+        )?;
+
+        let state = &mut ctx.accounts.batch_state;
+        state.stage = Stage::PullBackComplete.to_code();
+
+        // The state account is closed on exit, so the event is the record of the outcome
+        emit!(BatchGrantReclaimed {
+            user_sending: state.user_sending,
+            mint_of_token_being_sent: state.mint_of_token_being_sent,
+            batch_idx: state.idx,
+            amount,
+            stage: state.stage,
+        });
+
+        Ok(())
+    }
+
+    pub fn initialize_subscription(
+        ctx: Context<InitializeSubscription>,
+        amount_per_period: u64,
+        period_seconds: i64,
+        periods: u32,
+    ) -> Result<()> {
+        if amount_per_period == 0 || period_seconds <= 0 || periods == 0 {
+            return Err(ErrorCode::SubscriptionTermsInvalid.into());
+        }
+        let allowance = amount_per_period
+            .checked_mul(periods as u64)
+            .ok_or(ErrorCode::SubscriptionTermsInvalid)?;
+
+        let application_idx = ctx.accounts.grant_counter.take_next_idx()?;
+        let application_state_key = ctx.accounts.application_state.key();
+        add_to_grant_index(
+            &mut ctx.accounts.sender_index,
+            ctx.accounts.user_sending.key(),
+            application_state_key,
+            ctx.accounts.user_sending.to_account_info(),
+            ctx.accounts.system_program.to_account_info(),
+        )?;
+        add_to_grant_index(
+            &mut ctx.accounts.receiver_index,
+            ctx.accounts.user_receiving.key(),
+            application_state_key,
+            ctx.accounts.user_sending.to_account_info(),
+            ctx.accounts.system_program.to_account_info(),
+        )?;
+
+        let details = &mut ctx.accounts.application_state;
+        details.idx = application_idx;
+        details.amount_tokens = amount_per_period;
+        details.user_sending = ctx.accounts.user_sending.key();
+        details.user_receiving = ctx.accounts.user_receiving.key();
+        details.mint_of_token_being_sent = ctx.accounts.mint_of_token_being_sent.key();
+        // There is no escrow, the tokens stay in Alice's wallet until each charge
+        details.escrow_wallet = ctx.accounts.wallet_to_withdraw_from.key();
+        details.period_seconds = period_seconds;
+        details.periods_remaining = periods;
+        details.last_charge_ts = 0;
+
+        msg!(
+            "Initialized new Subscription instance for {} every {} seconds, {} times",
+            amount_per_period,
+            period_seconds,
+            periods
+        );
+
+        approve(
+            CpiContext::new(
+                ctx.accounts.token_program.to_account_info(),
+                Approve {
+                    to: ctx.accounts.wallet_to_withdraw_from.to_account_info(),
+                    delegate: details.to_account_info(),
+                    authority: ctx.accounts.user_sending.to_account_info(),
+                },
+            ),
+            allowance,
+        )?;
+        details.stage = Stage::SubscriptionActive.to_code();
+
+        emit!(SubscriptionCreated {
+            user_sending: details.user_sending,
+            user_receiving: details.user_receiving,
+            mint_of_token_being_sent: details.mint_of_token_being_sent,
+            application_idx: details.idx,
+            amount: details.amount_tokens,
+            period_seconds,
+            periods_remaining: periods,
+            stage: details.stage,
+        });
+
+        Ok(())
+    }
+
+    pub fn charge_subscription(
+        ctx: Context<ChargeSubscription>,
+        _application_idx: u64,
+    ) -> Result<()> {
+        let details = &ctx.accounts.application_state;
+        if Stage::from(details.stage)? != Stage::SubscriptionActive {
+            msg!("Stage is invalid, state stage is {}", details.stage);
+            return Err(ErrorCode::StageInvalid.into());
+        }
+        let now = Clock::get()?.unix_timestamp;
+        let next_charge_ts = details
+            .last_charge_ts
+            .checked_add(details.period_seconds)
+            .ok_or(ErrorCode::SubscriptionTermsInvalid)?;
+        if details.last_charge_ts != 0 && now < next_charge_ts {
+            msg!("Next charge is due at {}, now is {}", next_charge_ts, now);
+            return Err(ErrorCode::SubscriptionChargeTooEarly.into());
+        }
+
+        transfer_subscription_charge(
+            &ctx.accounts.application_state,
+            ctx.bumps.application_state,
+            &ctx.accounts.mint_of_token_being_sent,
+            ctx.accounts.token_program.to_account_info(),
+            ctx.accounts.wallet_to_withdraw_from.to_account_info(),
+            ctx.accounts.wallet_to_deposit_to.to_account_info(),
+        )?;
+
+        let state = &mut ctx.accounts.application_state;
+        state.last_charge_ts = now;
+        state.periods_remaining -= 1;
+        let is_last_period = state.periods_remaining == 0;
+        if is_last_period {
+            state.stage = Stage::EscrowComplete.to_code();
+        }
+
+        emit!(SubscriptionCharged {
+            user_sending: state.user_sending,
+            user_receiving: state.user_receiving,
+            mint_of_token_being_sent: state.mint_of_token_being_sent,
+            application_idx: state.idx,
+            amount: state.amount_tokens,
+            periods_remaining: state.periods_remaining,
+            stage: state.stage,
+        });
+
+        if is_last_period {
+            let application_state_key = state.key();
+            remove_from_grant_index(
+                &mut ctx.accounts.sender_index,
+                application_state_key,
+                ctx.accounts.user_sending.to_account_info(),
+            )?;
+            remove_from_grant_index(
+                &mut ctx.accounts.receiver_index,
+                application_state_key,
+                ctx.accounts.user_sending.to_account_info(),
+            )?;
+            // Alice paid for the state account, so she gets its rent back
+            ctx.accounts
+                .application_state
+                .close(ctx.accounts.user_sending.to_account_info())?;
+        }
+
+        Ok(())
+    }
+
+    pub fn cancel_subscription(
+        ctx: Context<CancelSubscription>,
+        _application_idx: u64,
+    ) -> Result<()> {
+        if Stage::from(ctx.accounts.application_state.stage)? != Stage::SubscriptionActive {
+            msg!(
+                "Stage is invalid, state stage is {}",
+                ctx.accounts.application_state.stage
+            );
+            return Err(ErrorCode::StageInvalid.into());
+        }
+
+        // Alice may have revoked the approval or approved someone else since, that is left alone
+        let application_state_key = ctx.accounts.application_state.key();
+        if ctx.accounts.wallet_to_withdraw_from.delegate == Some(application_state_key).into() {
+            revoke(CpiContext::new(
+                ctx.accounts.token_program.to_account_info(),
+                Revoke {
+                    source: ctx.accounts.wallet_to_withdraw_from.to_account_info(),
+                    authority: ctx.accounts.user_sending.to_account_info(),
+                },
+            ))?;
+        }
+
+        remove_from_grant_index(
+            &mut ctx.accounts.sender_index,
+            application_state_key,
+            ctx.accounts.user_sending.to_account_info(),
+        )?;
+        remove_from_grant_index(
+            &mut ctx.accounts.receiver_index,
+            application_state_key,
+            ctx.accounts.user_sending.to_account_info(),
+        )?;
+
+        let state = &mut ctx.accounts.application_state;
+        state.stage = Stage::PullBackComplete.to_code();
+
+        emit!(SubscriptionCancelled {
+            user_sending: state.user_sending,
+            user_receiving: state.user_receiving,
+            mint_of_token_being_sent: state.mint_of_token_being_sent,
+            application_idx: state.idx,
+            amount: state.amount_tokens,
+            periods_remaining: state.periods_remaining,
+            stage: state.stage,
+        });
+
+        Ok(())
+    }
+
+    // Returns the base rent of an index emptied by someone other than its payer
+    pub fn close_grant_index(_ctx: Context<CloseGrantIndex>) -> Result<()> {
+        Ok(())
+    }
+
+    pub fn initialize_config(
+        ctx: Context<InitializeConfig>,
+        fee_recipient: Pubkey,
+        fee_basis_points: u16,
+    ) -> Result<()> {
+        if fee_basis_points > MAX_FEE_BASIS_POINTS {
+            return Err(ErrorCode::FeeInvalid.into());
+        }
+
+        let config = &mut ctx.accounts.config;
+        config.operator = ctx.accounts.operator.key();
+        config.fee_recipient = fee_recipient;
+        config.fee_basis_points = fee_basis_points;
+        config.fee_waived_mints = Vec::new();
+
+        msg!(
+            "Initialized config with a fee of {} bps for {}",
+            fee_basis_points,
+            fee_recipient
+        );
+        Ok(())
+    }
+
+    pub fn update_config(
+        ctx: Context<UpdateConfig>,
+        operator: Pubkey,
+        fee_recipient: Pubkey,
+        fee_basis_points: u16,
+    ) -> Result<()> {
+        if fee_basis_points > MAX_FEE_BASIS_POINTS {
+            return Err(ErrorCode::FeeInvalid.into());
+        }
+
+        let config = &mut ctx.accounts.config;
+        config.operator = operator;
+        config.fee_recipient = fee_recipient;
+        config.fee_basis_points = fee_basis_points;
+        Ok(())
+    }
+
+    pub fn add_fee_waiver(ctx: Context<UpdateConfig>, mint: Pubkey) -> Result<()> {
+        let config = &mut ctx.accounts.config;
+        if config.fee_waived_mints.contains(&mint) {
+            return Ok(());
+        }
+        if config.fee_waived_mints.len() >= MAX_FEE_WAIVED_MINTS {
+            return Err(ErrorCode::FeeWaiverListFull.into());
+        }
+        config.fee_waived_mints.push(mint);
+        Ok(())
+    }
+
+    pub fn remove_fee_waiver(ctx: Context<UpdateConfig>, mint: Pubkey) -> Result<()> {
+        let config = &mut ctx.accounts.config;
+        config
+            .fee_waived_mints
+            .retain(|waived_mint| *waived_mint != mint);
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Stage {
+    // Safe Pay withdrew funds from Alice and deposited them into the escrow wallet
+    FundsDeposited,
+
+    // {from FundsDeposited} Bob acknowledged the terms, Alice can no longer pull back
+    Accepted,
+
+    // {from FundsDeposited, Accepted} Bob withdrew the funds from the escrow. We are done.
+    EscrowComplete,
+
+    // {from FundsDeposited} Alice pulled back the funds
+    // {from FundsDeposited, Accepted} Bob declined the funds
+    // {from SubscriptionActive} Alice cancelled the subscription
+    PullBackComplete,
+
+    // Alice approved the state account as delegate, Bob is charged every period.
+    // Moves to EscrowComplete after the last period.
+    SubscriptionActive,
+}
+impl Stage {
+    pub fn to_code(&self) -> u8 {
+        match self {
+            Stage::FundsDeposited => 1,
+            Stage::EscrowComplete => 2,
+            Stage::PullBackComplete => 3,
+            Stage::Accepted => 4,
+            Stage::SubscriptionActive => 5,
+        }
+    }
+
+    pub fn from(val: u8) -> Result<Stage> {
+        match val {
+            1 => Ok(Stage::FundsDeposited),
+            2 => Ok(Stage::EscrowComplete),
+            3 => Ok(Stage::PullBackComplete),
+            4 => Ok(Stage::Accepted),
+            5 => Ok(Stage::SubscriptionActive),
+            unknown_value => {
+                msg!("Unknown stage: {}", unknown_value);
+                Err(ErrorCode::StageInvalid.into())
+            }
+        }
+    }
+}
+
+#[derive(Accounts)]
+pub struct InitializeNewGrant<'info> {
+    // Derived PDAs
+    #[account(
+        init_if_needed,
+        payer = user_sending,
+        seeds=[b"counter".as_ref(), user_sending.key().as_ref()],
+        bump,
+        space = 8 + std::mem::size_of::<GrantCounter>()
+    )]
+    grant_counter: Account<'info, GrantCounter>,
+
+    #[account(
+        init,
+        payer = user_sending,
+        seeds=[b"state".as_ref(), user_sending.key().as_ref(), user_receiving.key.as_ref(), mint_of_token_being_sent.key().as_ref(), grant_counter.next_idx.to_le_bytes().as_ref()],
+        bump,
+        space = 8 + std::mem::size_of::<Details>()
+    )]
+    application_state: Account<'info, Details>,
+
+    #[account(
+        init,
+        payer = user_sending,
+        seeds=[b"wallet".as_ref(), user_sending.key().as_ref(), user_receiving.key.as_ref(), mint_of_token_being_sent.key().as_ref(), grant_counter.next_idx.to_le_bytes().as_ref()],
+        bump,
+        token::mint = mint_of_token_being_sent,
+        token::authority = application_state,
+        token::token_program = token_program,
+    )]
+    escrow_wallet_state: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        init_if_needed,
+        payer = user_sending,
+        seeds=[b"sender_index".as_ref(), user_sending.key().as_ref()],
+        bump,
+        space = GrantIndex::space_of(sender_index)
+    )]
+    sender_index: Account<'info, GrantIndex>,
+
+    #[account(
+        init_if_needed,
+        payer = user_sending,
+        seeds=[b"receiver_index".as_ref(), user_receiving.key.as_ref()],
+        bump,
+        space = GrantIndex::space_of(receiver_index)
+    )]
+    receiver_index: Account<'info, GrantIndex>,
+
+    /// CHECK: The config PDA, the grant is fee-less as long as it is not initialized.
+    #[account(
+        seeds=[b"config".as_ref()],
+        bump,
+    )]
+    config: UncheckedAccount<'info>,
+
+    // Users and accounts in the system
+    #[account(mut)]
+    user_sending: Signer<'info>, // Alice
+    /// CHECK: This field holds the account information for the receiving user.
+    user_receiving: AccountInfo<'info>, // Bob
+    mint_of_token_being_sent: InterfaceAccount<'info, Mint>, // USDC
+
+    // Alice's USDC wallet that has already approved the escrow wallet
+    #[account(
+            mut,
+            constraint=wallet_to_withdraw_from.owner == user_sending.key() @ ErrorCode::WalletToWithdrawFromInvalid,
+            constraint=wallet_to_withdraw_from.mint == mint_of_token_being_sent.key() @ ErrorCode::WalletToWithdrawFromInvalid
+        )]
+    wallet_to_withdraw_from: InterfaceAccount<'info, TokenAccount>,
+
+    // Application level accounts
+    system_program: Program<'info, System>,
+    token_program: Interface<'info, TokenInterface>,
+    // rent: Sysvar<'info, Rent>,
+}
+
+#[derive(Accounts)]
+#[instruction(application_idx: u64)]
+pub struct AcceptGrant<'info> {
+    // Derived PDAs
+    #[account(
+        mut,
+        seeds=[b"state".as_ref(), user_sending.key().as_ref(), user_receiving.key.as_ref(), mint_of_token_being_sent.key().as_ref(), application_idx.to_le_bytes().as_ref()],
+        bump,
+        constraint=application_state.idx == application_idx @ ErrorCode::InvalidStateIdx
+    )]
+    application_state: Account<'info, Details>,
+
+    // Users and accounts in the system
+    /// CHECK: This field holds the account information for the sender.
+    user_sending: AccountInfo<'info>, // Alice
+    user_receiving: Signer<'info>,                           // Bob
+    mint_of_token_being_sent: InterfaceAccount<'info, Mint>, // USDC
+}
+
+#[derive(Accounts)]
+#[instruction(application_idx: u64)]
+pub struct AmendGrant<'info> {
+    // Derived PDAs
+    #[account(
+        mut,
+        seeds=[b"state".as_ref(), user_sending.key().as_ref(), user_receiving.key.as_ref(), mint_of_token_being_sent.key().as_ref(), application_idx.to_le_bytes().as_ref()],
+        bump,
+        constraint=application_state.idx == application_idx @ ErrorCode::InvalidStateIdx
+    )]
+    application_state: Account<'info, Details>,
+
+    #[account(
+        mut,
+        seeds=[b"wallet".as_ref(), user_sending.key().as_ref(), user_receiving.key.as_ref(), mint_of_token_being_sent.key().as_ref(), application_idx.to_le_bytes().as_ref()],
+        bump,
+    )]
+    escrow_wallet_state: InterfaceAccount<'info, TokenAccount>,
+
+    // Alice's USDC wallet, the top-up comes from it and a reduction goes back to it
+    #[account(
+        mut,
+        constraint=sender_wallet.owner == user_sending.key() @ ErrorCode::WalletToWithdrawFromInvalid,
+        constraint=sender_wallet.mint == mint_of_token_being_sent.key() @ ErrorCode::WalletToWithdrawFromInvalid
+    )]
+    sender_wallet: InterfaceAccount<'info, TokenAccount>,
+
+    // Users and accounts in the system
+    #[account(mut)]
+    user_sending: Signer<'info>, // Alice
+    /// CHECK: Bob only has to sign once he accepted the grant, checked in the handler.
+    user_receiving: AccountInfo<'info>, // Bob
+    mint_of_token_being_sent: InterfaceAccount<'info, Mint>, // USDC
+
+    // Application level accounts
+    token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+#[instruction(application_idx: u64)]
+pub struct CompleteGrant<'info> {
+    // Derived PDAs
+    #[account(
+        mut,
+        seeds=[b"state".as_ref(), user_sending.key().as_ref(), user_receiving.key.as_ref(), mint_of_token_being_sent.key().as_ref(), application_idx.to_le_bytes().as_ref()],
+        bump,
+        close = user_sending,
+        constraint=application_state.idx == application_idx @ ErrorCode::InvalidStateIdx
+        )]
+    application_state: Account<'info, Details>,
+
+    #[account(
+        mut,
+        seeds=[b"wallet".as_ref(), user_sending.key().as_ref(), user_receiving.key.as_ref(), mint_of_token_being_sent.key().as_ref(), application_idx.to_le_bytes().as_ref()],
+        bump,
+    )]
+    escrow_wallet_state: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds=[b"sender_index".as_ref(), user_sending.key().as_ref()],
+        bump,
+    )]
+    sender_index: Account<'info, GrantIndex>,
+
+    #[account(
+        mut,
+        seeds=[b"receiver_index".as_ref(), user_receiving.key.as_ref()],
+        bump,
+    )]
+    receiver_index: Account<'info, GrantIndex>,
+
+    #[account(
+        init_if_needed,
+        payer = user_receiving,
+        associated_token::mint = mint_of_token_being_sent,
+        associated_token::authority = user_receiving,
+        associated_token::token_program = token_program,
+    )]
+    wallet_to_deposit_to: InterfaceAccount<'info, TokenAccount>, // Bob's USDC wallet (will be initialized if it did not exist)
+
+    // Only needed when the grant was created with a fee
+    #[account(
+        mut,
+        constraint=fee_wallet.owner == application_state.fee_recipient @ ErrorCode::FeeWalletInvalid,
+        constraint=fee_wallet.mint == mint_of_token_being_sent.key() @ ErrorCode::FeeWalletInvalid
+    )]
+    fee_wallet: Option<InterfaceAccount<'info, TokenAccount>>,
+
+    // Users and accounts in the system
+    /// CHECK: This field holds the account information for the sender.
+    #[account(mut)]
+    user_sending: AccountInfo<'info>, // Alice
+    #[account(mut)]
+    user_receiving: Signer<'info>, // Bob
+    #[account(mut)]
+    mint_of_token_being_sent: InterfaceAccount<'info, Mint>, // USDC
+
+    // Application level accounts
+    system_program: Program<'info, System>,
+    token_program: Interface<'info, TokenInterface>,
+    associated_token_program: Program<'info, AssociatedToken>,
+    // rent: Sysvar<'info, Rent>,
+}
+
+#[derive(Accounts)]
+#[instruction(application_idx: u64)]
+pub struct PullBackFunds<'info> {
+    // Derived PDAs
+    #[account(
+        mut,
+        seeds=[b"state".as_ref(), user_sending.key().as_ref(), user_receiving.key.as_ref(), mint_of_token_being_sent.key().as_ref(), application_idx.to_le_bytes().as_ref()],
+        bump,
+        close = user_sending,
+        constraint=application_state.idx == application_idx @ ErrorCode::InvalidStateIdx
+    )]
+    application_state: Account<'info, Details>,
+
+    #[account(
+    mut,
+    seeds=[b"wallet".as_ref(), user_sending.key().as_ref(), user_receiving.key.as_ref(), mint_of_token_being_sent.key().as_ref(), application_idx.to_le_bytes().as_ref()],
+    bump,
+)]
+    escrow_wallet_state: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds=[b"sender_index".as_ref(), user_sending.key().as_ref()],
+        bump,
+    )]
+    sender_index: Account<'info, GrantIndex>,
+
+    #[account(
+        mut,
+        seeds=[b"receiver_index".as_ref(), user_receiving.key.as_ref()],
+        bump,
+    )]
+    receiver_index: Account<'info, GrantIndex>,
+
+    // Users and accounts in the system
+    // Either Alice cancelling or Bob declining
+    authority: Signer<'info>,
+    /// CHECK: This field holds the account information for the sender.
+    #[account(mut)]
+    user_sending: AccountInfo<'info>, // Alice
+    /// CHECK: This field holds the account information for the receiving user.
+    user_receiving: AccountInfo<'info>, // Bob
+    #[account(mut)]
+    mint_of_token_being_sent: InterfaceAccount<'info, Mint>, // USDC
+
+    // Wallet to deposit to
+    #[account(
+        mut,
+        constraint=refund_wallet.owner == user_sending.key() @ ErrorCode::RefundWalletInvalid,
+        constraint=refund_wallet.mint == mint_of_token_being_sent.key() @ ErrorCode::RefundWalletInvalid
+    )]
+    refund_wallet: InterfaceAccount<'info, TokenAccount>,
+
+    // Application level accounts
+    system_program: Program<'info, System>,
+    token_program: Interface<'info, TokenInterface>,
+    associated_token_program: Program<'info, AssociatedToken>,
     // rent: Sysvar<'info, Rent>,
 }
 
 #[derive(Accounts)]
 #[instruction(application_idx: u64)]
-pub struct CompleteGrant<'info> {
+pub struct SettleWithAttestation<'info> {
+    // Derived PDAs
+    #[account(
+        mut,
+        seeds=[b"state".as_ref(), user_sending.key().as_ref(), user_receiving.key.as_ref(), mint_of_token_being_sent.key().as_ref(), application_idx.to_le_bytes().as_ref()],
+        bump,
+        close = user_sending,
+        constraint=application_state.idx == application_idx @ ErrorCode::InvalidStateIdx
+    )]
+    application_state: Account<'info, Details>,
+
+    #[account(
+        mut,
+        seeds=[b"wallet".as_ref(), user_sending.key().as_ref(), user_receiving.key.as_ref(), mint_of_token_being_sent.key().as_ref(), application_idx.to_le_bytes().as_ref()],
+        bump,
+    )]
+    escrow_wallet_state: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds=[b"sender_index".as_ref(), user_sending.key().as_ref()],
+        bump,
+    )]
+    sender_index: Account<'info, GrantIndex>,
+
+    #[account(
+        mut,
+        seeds=[b"receiver_index".as_ref(), user_receiving.key.as_ref()],
+        bump,
+    )]
+    receiver_index: Account<'info, GrantIndex>,
+
+    #[account(
+        init_if_needed,
+        payer = payer,
+        associated_token::mint = mint_of_token_being_sent,
+        associated_token::authority = user_receiving,
+        associated_token::token_program = token_program,
+    )]
+    wallet_to_deposit_to: InterfaceAccount<'info, TokenAccount>, // Bob's USDC wallet (will be initialized if it did not exist)
+
+    // Wallet to refund to
+    #[account(
+        mut,
+        constraint=refund_wallet.owner == user_sending.key() @ ErrorCode::RefundWalletInvalid,
+        constraint=refund_wallet.mint == mint_of_token_being_sent.key() @ ErrorCode::RefundWalletInvalid
+    )]
+    refund_wallet: InterfaceAccount<'info, TokenAccount>,
+
+    // Only needed when the grant was created with a fee
+    #[account(
+        mut,
+        constraint=fee_wallet.owner == application_state.fee_recipient @ ErrorCode::FeeWalletInvalid,
+        constraint=fee_wallet.mint == mint_of_token_being_sent.key() @ ErrorCode::FeeWalletInvalid
+    )]
+    fee_wallet: Option<InterfaceAccount<'info, TokenAccount>>,
+
+    // Users and accounts in the system
+    // Anyone can relay the attestation, it only pays for the transaction
+    #[account(mut)]
+    payer: Signer<'info>,
+    /// CHECK: This field holds the account information for the sender.
+    #[account(mut)]
+    user_sending: AccountInfo<'info>, // Alice
+    /// CHECK: This field holds the account information for the receiving user.
+    user_receiving: AccountInfo<'info>, // Bob
+    #[account(mut)]
+    mint_of_token_being_sent: InterfaceAccount<'info, Mint>, // USDC
+
+    /// CHECK: This field holds the instructions sysvar, checked by address.
+    #[account(address = anchor_lang::solana_program::sysvar::instructions::ID)]
+    instructions: AccountInfo<'info>,
+
+    // Application level accounts
+    system_program: Program<'info, System>,
+    token_program: Interface<'info, TokenInterface>,
+    associated_token_program: Program<'info, AssociatedToken>,
+}
+
+#[derive(Accounts)]
+pub struct InitializeNewSwap<'info> {
+    // Derived PDAs
+    #[account(
+        init_if_needed,
+        payer = user_sending,
+        seeds=[b"counter".as_ref(), user_sending.key().as_ref()],
+        bump,
+        space = 8 + std::mem::size_of::<GrantCounter>()
+    )]
+    grant_counter: Account<'info, GrantCounter>,
+
+    #[account(
+        init,
+        payer = user_sending,
+        seeds=[b"state".as_ref(), user_sending.key().as_ref(), user_receiving.key.as_ref(), mint_of_token_being_sent.key().as_ref(), grant_counter.next_idx.to_le_bytes().as_ref()],
+        bump,
+        space = 8 + std::mem::size_of::<SwapDetails>()
+    )]
+    application_state: Account<'info, SwapDetails>,
+
+    #[account(
+        init,
+        payer = user_sending,
+        seeds=[b"wallet".as_ref(), user_sending.key().as_ref(), user_receiving.key.as_ref(), mint_of_token_being_sent.key().as_ref(), grant_counter.next_idx.to_le_bytes().as_ref()],
+        bump,
+        token::mint = mint_of_token_being_sent,
+        token::authority = application_state,
+        token::token_program = token_program,
+    )]
+    escrow_wallet_state: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        init_if_needed,
+        payer = user_sending,
+        seeds=[b"sender_index".as_ref(), user_sending.key().as_ref()],
+        bump,
+        space = GrantIndex::space_of(sender_index)
+    )]
+    sender_index: Account<'info, GrantIndex>,
+
+    #[account(
+        init_if_needed,
+        payer = user_sending,
+        seeds=[b"receiver_index".as_ref(), user_receiving.key.as_ref()],
+        bump,
+        space = GrantIndex::space_of(receiver_index)
+    )]
+    receiver_index: Account<'info, GrantIndex>,
+
+    // Users and accounts in the system
+    #[account(mut)]
+    user_sending: Signer<'info>, // Alice
+    /// CHECK: This field holds the account information for the receiving user.
+    user_receiving: AccountInfo<'info>, // Bob
+    mint_of_token_being_sent: InterfaceAccount<'info, Mint>, // Token X
+    mint_of_token_expected: InterfaceAccount<'info, Mint>,   // Token Y
+
+    // Alice's wallet of token X
+    #[account(
+            mut,
+            constraint=wallet_to_withdraw_from.owner == user_sending.key() @ ErrorCode::WalletToWithdrawFromInvalid,
+            constraint=wallet_to_withdraw_from.mint == mint_of_token_being_sent.key() @ ErrorCode::WalletToWithdrawFromInvalid
+        )]
+    wallet_to_withdraw_from: InterfaceAccount<'info, TokenAccount>,
+
+    // Application level accounts
+    system_program: Program<'info, System>,
+    token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+#[instruction(application_idx: u64)]
+pub struct AcceptSwap<'info> {
+    // Derived PDAs
+    #[account(
+        mut,
+        seeds=[b"state".as_ref(), user_sending.key().as_ref(), user_receiving.key.as_ref(), mint_of_token_being_sent.key().as_ref(), application_idx.to_le_bytes().as_ref()],
+        bump,
+        close = user_sending,
+        constraint=application_state.idx == application_idx @ ErrorCode::InvalidStateIdx,
+        constraint=application_state.mint_of_token_expected == mint_of_token_expected.key()
+    )]
+    application_state: Account<'info, SwapDetails>,
+
+    #[account(
+        mut,
+        seeds=[b"wallet".as_ref(), user_sending.key().as_ref(), user_receiving.key.as_ref(), mint_of_token_being_sent.key().as_ref(), application_idx.to_le_bytes().as_ref()],
+        bump,
+    )]
+    escrow_wallet_state: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds=[b"sender_index".as_ref(), user_sending.key().as_ref()],
+        bump,
+    )]
+    sender_index: Account<'info, GrantIndex>,
+
+    #[account(
+        mut,
+        seeds=[b"receiver_index".as_ref(), user_receiving.key.as_ref()],
+        bump,
+    )]
+    receiver_index: Account<'info, GrantIndex>,
+
+    #[account(
+        init_if_needed,
+        payer = user_receiving,
+        associated_token::mint = mint_of_token_being_sent,
+        associated_token::authority = user_receiving,
+        associated_token::token_program = token_program,
+    )]
+    wallet_to_deposit_to: InterfaceAccount<'info, TokenAccount>, // Bob's token X wallet (will be initialized if it did not exist)
+
+    #[account(
+        init_if_needed,
+        payer = user_receiving,
+        associated_token::mint = mint_of_token_expected,
+        associated_token::authority = user_sending,
+        associated_token::token_program = expected_token_program,
+    )]
+    sender_wallet_to_deposit_to: InterfaceAccount<'info, TokenAccount>, // Alice's token Y wallet (will be initialized if it did not exist)
+
+    // Bob's wallet of token Y
+    #[account(
+        mut,
+        constraint=wallet_to_withdraw_from.owner == user_receiving.key() @ ErrorCode::WalletToWithdrawFromInvalid,
+        constraint=wallet_to_withdraw_from.mint == mint_of_token_expected.key() @ ErrorCode::WalletToWithdrawFromInvalid
+    )]
+    wallet_to_withdraw_from: InterfaceAccount<'info, TokenAccount>,
+
+    // Users and accounts in the system
+    /// CHECK: This field holds the account information for the sender.
+    #[account(mut)]
+    user_sending: AccountInfo<'info>, // Alice
+    #[account(mut)]
+    user_receiving: Signer<'info>, // Bob
+    #[account(mut)]
+    mint_of_token_being_sent: InterfaceAccount<'info, Mint>, // Token X
+    #[account(mint::token_program = expected_token_program)]
+    mint_of_token_expected: InterfaceAccount<'info, Mint>, // Token Y
+
+    // Application level accounts
+    system_program: Program<'info, System>,
+    token_program: Interface<'info, TokenInterface>,
+    // Token Y can live under the other token program than token X
+    expected_token_program: Interface<'info, TokenInterface>,
+    associated_token_program: Program<'info, AssociatedToken>,
+}
+
+#[derive(Accounts)]
+#[instruction(application_idx: u64)]
+pub struct CancelSwap<'info> {
+    // Derived PDAs
+    #[account(
+        mut,
+        seeds=[b"state".as_ref(), user_sending.key().as_ref(), user_receiving.key.as_ref(), mint_of_token_being_sent.key().as_ref(), application_idx.to_le_bytes().as_ref()],
+        bump,
+        close = user_sending,
+        constraint=application_state.idx == application_idx @ ErrorCode::InvalidStateIdx
+    )]
+    application_state: Account<'info, SwapDetails>,
+
+    #[account(
+        mut,
+        seeds=[b"wallet".as_ref(), user_sending.key().as_ref(), user_receiving.key.as_ref(), mint_of_token_being_sent.key().as_ref(), application_idx.to_le_bytes().as_ref()],
+        bump,
+    )]
+    escrow_wallet_state: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds=[b"sender_index".as_ref(), user_sending.key().as_ref()],
+        bump,
+    )]
+    sender_index: Account<'info, GrantIndex>,
+
+    #[account(
+        mut,
+        seeds=[b"receiver_index".as_ref(), user_receiving.key.as_ref()],
+        bump,
+    )]
+    receiver_index: Account<'info, GrantIndex>,
+
+    // Users and accounts in the system
+    #[account(mut)]
+    user_sending: Signer<'info>, // Alice
+    /// CHECK: This field holds the account information for the receiving user.
+    user_receiving: AccountInfo<'info>, // Bob
+    #[account(mut)]
+    mint_of_token_being_sent: InterfaceAccount<'info, Mint>, // Token X
+
+    // Wallet to deposit to
+    #[account(
+        mut,
+        constraint=refund_wallet.owner == user_sending.key() @ ErrorCode::RefundWalletInvalid,
+        constraint=refund_wallet.mint == mint_of_token_being_sent.key() @ ErrorCode::RefundWalletInvalid
+    )]
+    refund_wallet: InterfaceAccount<'info, TokenAccount>,
+
+    // Application level accounts
+    system_program: Program<'info, System>,
+    token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+#[instruction(batch_idx: u64, num_recipients: u32)]
+pub struct InitializeNewBatchGrant<'info> {
+    // Derived PDAs
+    #[account(
+        init,
+        payer = user_sending,
+        seeds=[b"batch_state".as_ref(), user_sending.key().as_ref(), mint_of_token_being_sent.key().as_ref(), batch_idx.to_le_bytes().as_ref()],
+        bump,
+        space = BatchDetails::space(num_recipients)
+    )]
+    batch_state: Account<'info, BatchDetails>,
+
+    #[account(
+        init,
+        payer = user_sending,
+        seeds=[b"batch_wallet".as_ref(), user_sending.key().as_ref(), mint_of_token_being_sent.key().as_ref(), batch_idx.to_le_bytes().as_ref()],
+        bump,
+        token::mint = mint_of_token_being_sent,
+        token::authority = batch_state,
+        token::token_program = token_program,
+    )]
+    escrow_wallet_state: InterfaceAccount<'info, TokenAccount>,
+
+    // Users and accounts in the system
+    #[account(mut)]
+    user_sending: Signer<'info>, // Alice
+    mint_of_token_being_sent: InterfaceAccount<'info, Mint>, // USDC
+
+    // Alice's USDC wallet that funds every recipient of the batch
+    #[account(
+            mut,
+            constraint=wallet_to_withdraw_from.owner == user_sending.key() @ ErrorCode::WalletToWithdrawFromInvalid,
+            constraint=wallet_to_withdraw_from.mint == mint_of_token_being_sent.key() @ ErrorCode::WalletToWithdrawFromInvalid
+        )]
+    wallet_to_withdraw_from: InterfaceAccount<'info, TokenAccount>,
+
+    // Application level accounts
+    system_program: Program<'info, System>,
+    token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+#[instruction(batch_idx: u64)]
+pub struct ClaimBatchGrant<'info> {
+    // Derived PDAs
+    #[account(
+        mut,
+        seeds=[b"batch_state".as_ref(), user_sending.key().as_ref(), mint_of_token_being_sent.key().as_ref(), batch_idx.to_le_bytes().as_ref()],
+        bump,
+        constraint=batch_state.idx == batch_idx @ ErrorCode::InvalidStateIdx
+    )]
+    batch_state: Account<'info, BatchDetails>,
+
+    #[account(
+        mut,
+        seeds=[b"batch_wallet".as_ref(), user_sending.key().as_ref(), mint_of_token_being_sent.key().as_ref(), batch_idx.to_le_bytes().as_ref()],
+        bump,
+    )]
+    escrow_wallet_state: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        init_if_needed,
+        payer = user_receiving,
+        associated_token::mint = mint_of_token_being_sent,
+        associated_token::authority = user_receiving,
+        associated_token::token_program = token_program,
+    )]
+    wallet_to_deposit_to: InterfaceAccount<'info, TokenAccount>, // Recipient's USDC wallet (will be initialized if it did not exist)
+
+    // Users and accounts in the system
+    /// CHECK: This field holds the account information for the sender.
+    #[account(mut)]
+    user_sending: AccountInfo<'info>, // Alice
+    #[account(mut)]
+    user_receiving: Signer<'info>, // One of the recipients of the batch
+    #[account(mut)]
+    mint_of_token_being_sent: InterfaceAccount<'info, Mint>, // USDC
+
+    // Application level accounts
+    system_program: Program<'info, System>,
+    token_program: Interface<'info, TokenInterface>,
+    associated_token_program: Program<'info, AssociatedToken>,
+}
+
+#[derive(Accounts)]
+#[instruction(batch_idx: u64)]
+pub struct ReclaimBatchGrant<'info> {
+    // Derived PDAs
+    #[account(
+        mut,
+        seeds=[b"batch_state".as_ref(), user_sending.key().as_ref(), mint_of_token_being_sent.key().as_ref(), batch_idx.to_le_bytes().as_ref()],
+        bump,
+        close = user_sending,
+        constraint=batch_state.idx == batch_idx @ ErrorCode::InvalidStateIdx
+    )]
+    batch_state: Account<'info, BatchDetails>,
+
+    #[account(
+        mut,
+        seeds=[b"batch_wallet".as_ref(), user_sending.key().as_ref(), mint_of_token_being_sent.key().as_ref(), batch_idx.to_le_bytes().as_ref()],
+        bump,
+    )]
+    escrow_wallet_state: InterfaceAccount<'info, TokenAccount>,
+
+    // Wallet to refund to
+    #[account(
+        mut,
+        constraint=refund_wallet.owner == user_sending.key() @ ErrorCode::RefundWalletInvalid,
+        constraint=refund_wallet.mint == mint_of_token_being_sent.key() @ ErrorCode::RefundWalletInvalid
+    )]
+    refund_wallet: InterfaceAccount<'info, TokenAccount>,
+
+    // Users and accounts in the system
+    #[account(mut)]
+    user_sending: Signer<'info>, // Alice
+    #[account(mut)]
+    mint_of_token_being_sent: InterfaceAccount<'info, Mint>, // USDC
+
+    // Application level accounts
+    token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct InitializeSubscription<'info> {
+    // Derived PDAs
+    #[account(
+        init_if_needed,
+        payer = user_sending,
+        seeds=[b"counter".as_ref(), user_sending.key().as_ref()],
+        bump,
+        space = 8 + std::mem::size_of::<GrantCounter>()
+    )]
+    grant_counter: Account<'info, GrantCounter>,
+
+    #[account(
+        init,
+        payer = user_sending,
+        seeds=[b"state".as_ref(), user_sending.key().as_ref(), user_receiving.key.as_ref(), mint_of_token_being_sent.key().as_ref(), grant_counter.next_idx.to_le_bytes().as_ref()],
+        bump,
+        space = 8 + std::mem::size_of::<Details>()
+    )]
+    application_state: Account<'info, Details>,
+
+    #[account(
+        init_if_needed,
+        payer = user_sending,
+        seeds=[b"sender_index".as_ref(), user_sending.key().as_ref()],
+        bump,
+        space = GrantIndex::space_of(sender_index)
+    )]
+    sender_index: Account<'info, GrantIndex>,
+
+    #[account(
+        init_if_needed,
+        payer = user_sending,
+        seeds=[b"receiver_index".as_ref(), user_receiving.key.as_ref()],
+        bump,
+        space = GrantIndex::space_of(receiver_index)
+    )]
+    receiver_index: Account<'info, GrantIndex>,
+
+    // Users and accounts in the system
+    #[account(mut)]
+    user_sending: Signer<'info>, // Alice
+    /// CHECK: This field holds the account information for the receiving user.
+    user_receiving: AccountInfo<'info>, // Bob
+    mint_of_token_being_sent: InterfaceAccount<'info, Mint>, // USDC
+
+    // Alice's USDC wallet that every period is charged from. A token account has a single
+    // delegate, so a wallet already backing another subscription cannot back this one.
+    #[account(
+            mut,
+            constraint=wallet_to_withdraw_from.owner == user_sending.key() @ ErrorCode::WalletToWithdrawFromInvalid,
+            constraint=wallet_to_withdraw_from.mint == mint_of_token_being_sent.key() @ ErrorCode::WalletToWithdrawFromInvalid,
+            constraint=wallet_to_withdraw_from.delegate.is_none() @ ErrorCode::WalletAlreadyDelegated
+        )]
+    wallet_to_withdraw_from: InterfaceAccount<'info, TokenAccount>,
+
+    // Application level accounts
+    system_program: Program<'info, System>,
+    token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+#[instruction(application_idx: u64)]
+pub struct ChargeSubscription<'info> {
     // Derived PDAs
     #[account(
         mut,
         seeds=[b"state".as_ref(), user_sending.key().as_ref(), user_receiving.key.as_ref(), mint_of_token_being_sent.key().as_ref(), application_idx.to_le_bytes().as_ref()],
         bump,
-        )]
+        constraint=application_state.idx == application_idx @ ErrorCode::InvalidStateIdx
+    )]
     application_state: Account<'info, Details>,
 
     #[account(
         mut,
-        seeds=[b"wallet".as_ref(), user_sending.key().as_ref(), user_receiving.key.as_ref(), mint_of_token_being_sent.key().as_ref(), application_idx.to_le_bytes().as_ref()],
+        seeds=[b"sender_index".as_ref(), user_sending.key().as_ref()],
+        bump,
+    )]
+    sender_index: Account<'info, GrantIndex>,
+
+    #[account(
+        mut,
+        seeds=[b"receiver_index".as_ref(), user_receiving.key.as_ref()],
         bump,
     )]
-    escrow_wallet_state: Account<'info, TokenAccount>,
+    receiver_index: Account<'info, GrantIndex>,
+
+    // Alice's USDC wallet the state account is a delegate of
+    #[account(
+        mut,
+        constraint=wallet_to_withdraw_from.key() == application_state.escrow_wallet @ ErrorCode::WalletToWithdrawFromInvalid
+    )]
+    wallet_to_withdraw_from: InterfaceAccount<'info, TokenAccount>,
 
     #[account(
         init_if_needed,
-        payer = user_receiving,
+        payer = payer,
         associated_token::mint = mint_of_token_being_sent,
         associated_token::authority = user_receiving,
+        associated_token::token_program = token_program,
     )]
-    wallet_to_deposit_to: Account<'info, TokenAccount>, // Bob's USDC wallet (will be initialized if it did not exist)
+    wallet_to_deposit_to: InterfaceAccount<'info, TokenAccount>, // Bob's USDC wallet (will be initialized if it did not exist)
 
     // Users and accounts in the system
+    // Bob or any cranker, it only pays for the transaction
+    #[account(mut)]
+    payer: Signer<'info>,
     /// CHECK: This field holds the account information for the sender.
     #[account(mut)]
     user_sending: AccountInfo<'info>, // Alice
-    #[account(mut)]
-    user_receiving: Signer<'info>, // Bob
-    mint_of_token_being_sent: Account<'info, Mint>, // USDC
+    /// CHECK: This field holds the account information for the receiving user.
+    user_receiving: AccountInfo<'info>, // Bob
+    mint_of_token_being_sent: InterfaceAccount<'info, Mint>, // USDC
 
     // Application level accounts
     system_program: Program<'info, System>,
-    token_program: Program<'info, Token>,
+    token_program: Interface<'info, TokenInterface>,
     associated_token_program: Program<'info, AssociatedToken>,
-    // rent: Sysvar<'info, Rent>,
 }
 
 #[derive(Accounts)]
 #[instruction(application_idx: u64)]
-pub struct PullBackFunds<'info> {
+pub struct CancelSubscription<'info> {
     // Derived PDAs
     #[account(
         mut,
         seeds=[b"state".as_ref(), user_sending.key().as_ref(), user_receiving.key.as_ref(), mint_of_token_being_sent.key().as_ref(), application_idx.to_le_bytes().as_ref()],
         bump,
+        close = user_sending,
+        constraint=application_state.idx == application_idx @ ErrorCode::InvalidStateIdx
     )]
     application_state: Account<'info, Details>,
 
     #[account(
-    mut,
-    seeds=[b"wallet".as_ref(), user_sending.key().as_ref(), user_receiving.key.as_ref(), mint_of_token_being_sent.key().as_ref(), application_idx.to_le_bytes().as_ref()],
-    bump,
-)]
-    escrow_wallet_state: Account<'info, TokenAccount>,
+        mut,
+        seeds=[b"sender_index".as_ref(), user_sending.key().as_ref()],
+        bump,
+    )]
+    sender_index: Account<'info, GrantIndex>,
+
+    #[account(
+        mut,
+        seeds=[b"receiver_index".as_ref(), user_receiving.key.as_ref()],
+        bump,
+    )]
+    receiver_index: Account<'info, GrantIndex>,
+
+    // Alice's USDC wallet the state account is a delegate of
+    #[account(
+        mut,
+        constraint=wallet_to_withdraw_from.key() == application_state.escrow_wallet @ ErrorCode::WalletToWithdrawFromInvalid
+    )]
+    wallet_to_withdraw_from: InterfaceAccount<'info, TokenAccount>,
 
     // Users and accounts in the system
-    /// CHECK: This field holds the account information for the sender.
     #[account(mut)]
-    user_sending: AccountInfo<'info>, // Alice
-    user_receiving: Signer<'info>,                  // Bob
-    mint_of_token_being_sent: Account<'info, Mint>, // USDC
+    user_sending: Signer<'info>, // Alice
+    /// CHECK: This field holds the account information for the receiving user.
+    user_receiving: AccountInfo<'info>, // Bob
+    mint_of_token_being_sent: InterfaceAccount<'info, Mint>, // USDC
 
-    // Wallet to deposit to
+    // Application level accounts
+    token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct CloseGrantIndex<'info> {
     #[account(
         mut,
-        constraint=refund_wallet.owner == user_sending.key(),
-        constraint=refund_wallet.mint == mint_of_token_being_sent.key()
+        close = payer,
+        has_one = payer,
+        constraint = grant_index.open_grants.is_empty() @ ErrorCode::GrantIndexNotEmpty
     )]
-    refund_wallet: Account<'info, TokenAccount>,
+    grant_index: Account<'info, GrantIndex>,
+
+    /// CHECK: Only receives the rent, checked against the index.
+    #[account(mut)]
+    payer: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct InitializeConfig<'info> {
+    #[account(
+        init,
+        payer = operator,
+        seeds=[b"config".as_ref()],
+        bump,
+        space = Config::SPACE
+    )]
+    config: Account<'info, Config>,
+
+    #[account(mut)]
+    operator: Signer<'info>, // The platform running Safe Pay
+
+    // Only whoever can upgrade the program sets up its config, nobody can claim the PDA first
+    #[account(
+        constraint=program.programdata_address()? == Some(program_data.key()) @ ErrorCode::UnauthorizedOperator
+    )]
+    program: Program<'info, crate::program::Safepay>,
+    #[account(
+        constraint=program_data.upgrade_authority_address == Some(operator.key()) @ ErrorCode::UnauthorizedOperator
+    )]
+    program_data: Account<'info, ProgramData>,
 
-    // Application level accounts
     system_program: Program<'info, System>,
-    token_program: Program<'info, Token>,
-    associated_token_program: Program<'info, AssociatedToken>,
-    // rent: Sysvar<'info, Rent>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateConfig<'info> {
+    #[account(
+        mut,
+        seeds=[b"config".as_ref()],
+        bump,
+        constraint=config.operator == operator.key() @ ErrorCode::UnauthorizedOperator
+    )]
+    config: Account<'info, Config>,
+
+    operator: Signer<'info>,
 }
 
 #[account]
 pub struct Details {
+    // A primary key that allows us to derive other important accounts
+    pub idx: u64,
+
+    // Alice
+    pub user_sending: Pubkey,
+
+    // Bob
+    pub user_receiving: Pubkey,
+
+    // The Mint of the token that Alice wants to send to Bob
+    pub mint_of_token_being_sent: Pubkey,
+
+    // The escrow wallet
+    pub escrow_wallet: Pubkey,
+
+    // The amount of tokens Alice wants to send to Bob
+    pub amount_tokens: u64,
+
+    // An enumm that is to represent some kind of state machine
+    pub stage: u8,
+
+    // Key whose ed25519 verdict settles the grant, `Pubkey::default()` when there is none.
+    // With an attester Bob cannot complete and Alice cannot pull back, Bob can still decline.
+    pub attester: Pubkey,
+
+    // Subscriptions only: `amount_tokens` is charged once every `period_seconds`
+    pub period_seconds: i64,
+
+    // Subscriptions only: when Bob was last charged, 0 before the first charge
+    pub last_charge_ts: i64,
+
+    // Subscriptions only: how many charges are left
+    pub periods_remaining: u32,
+
+    // How many times `amend_grant` changed `amount_tokens`
+    pub amendments: u32,
+
+    // Fee terms of the config when the grant was created, later config changes do not apply
+    pub fee_basis_points: u16,
+    pub fee_recipient: Pubkey,
+}
+impl Details {
+    fn fee_for(&self, amount: u64) -> Result<u64> {
+        let fee = (amount as u128)
+            .checked_mul(self.fee_basis_points as u128)
+            .ok_or(ErrorCode::FeeInvalid)?
+            .checked_div(BASIS_POINTS as u128)
+            .ok_or(ErrorCode::FeeInvalid)?;
+        Ok(fee as u64)
+    }
+}
+
+// Shares the `state`/`wallet` seeds of a grant so the escrow can be released
+// through `transfer_escrow_out`. FundsDeposited -> EscrowComplete once Bob
+// accepts, FundsDeposited -> PullBackComplete if Alice cancels.
+#[account]
+pub struct SwapDetails {
     // A primary key that allows us to derive other important accounts
     idx: u64,
 
@@ -330,18 +2409,310 @@ pub struct Details {
     // Bob
     user_receiving: Pubkey,
 
-    // The Mint of the token that Alice wants to send to Bob
+    // The Mint of the token that Alice deposits (token X)
     mint_of_token_being_sent: Pubkey,
 
+    // The Mint of the token that Alice expects from Bob (token Y)
+    mint_of_token_expected: Pubkey,
+
     // The escrow wallet
     escrow_wallet: Pubkey,
 
-    // The amount of tokens Alice wants to send to Bob
+    // The amount of token X Alice deposited
+    amount_tokens: u64,
+
+    // The amount of token Y Bob has to pay
+    amount_tokens_expected: u64,
+
+    // An enumm that is to represent some kind of state machine
+    stage: u8,
+}
+
+// Outcomes an attester can sign next to the grant's state account
+pub const ATTESTATION_RELEASE: u8 = 1;
+pub const ATTESTATION_REFUND: u8 = 2;
+
+pub const BASIS_POINTS: u16 = 10_000;
+pub const MAX_FEE_BASIS_POINTS: u16 = 1_000;
+pub const MAX_FEE_WAIVED_MINTS: usize = 16;
+
+// Global settings of the platform operator, a single PDA for the whole program
+#[account]
+pub struct Config {
+    // The only key allowed to change the config
+    pub operator: Pubkey,
+
+    // Owner of the token accounts that receive the fees
+    pub fee_recipient: Pubkey,
+
+    // Fee taken from every grant created while it is set, in basis points
+    pub fee_basis_points: u16,
+
+    // Mints that are never charged a fee
+    pub fee_waived_mints: Vec<Pubkey>,
+}
+impl Config {
+    pub const SPACE: usize = 8 + 32 + 32 + 2 + 4 + 32 * MAX_FEE_WAIVED_MINTS;
+
+    // Basis points and recipient a new grant of `mint` is charged, none while there is no config
+    fn fee_terms(config: &AccountInfo, mint: &Pubkey) -> Result<(u16, Pubkey)> {
+        if config.owner != &crate::ID {
+            return Ok((0, Pubkey::default()));
+        }
+        let config = Config::try_deserialize(&mut &config.data.borrow()[..])?;
+        if config.fee_waived_mints.contains(mint) {
+            return Ok((0, Pubkey::default()));
+        }
+        Ok((config.fee_basis_points, config.fee_recipient))
+    }
+}
+
+// One per sender, hands out the `application_idx` of every grant and swap Alice creates
+#[account]
+pub struct GrantCounter {
+    pub next_idx: u64,
+}
+impl GrantCounter {
+    fn take_next_idx(&mut self) -> Result<u64> {
+        let idx = self.next_idx;
+        self.next_idx = idx.checked_add(1).ok_or(ErrorCode::InvalidStateIdx)?;
+        Ok(idx)
+    }
+}
+
+// Lists the state accounts of the open grants and swaps a wallet is party to,
+// either as the sender (`sender_index`) or as the receiver (`receiver_index`)
+#[account]
+pub struct GrantIndex {
+    pub owner: Pubkey,
+    // Paid the base rent of the index, which goes back to them when it is closed
+    pub payer: Pubkey,
+    pub open_grants: Vec<Pubkey>,
+}
+impl GrantIndex {
+    const fn space(open_grants: usize) -> usize {
+        8 + 32 + 32 + 4 + 32 * open_grants
+    }
+
+    // Created empty and grown one entry at a time by `add_to_grant_index`, so an existing
+    // index is taken at the size it already has
+    fn space_of(index: &AccountInfo) -> usize {
+        Self::space(0).max(index.data_len())
+    }
+}
+
+pub const MAX_OPEN_GRANTS: usize = 128;
+
+pub const MAX_BATCH_RECIPIENTS: u32 = 4096;
+
+#[account]
+pub struct BatchDetails {
+    // A primary key that allows us to derive other important accounts
+    idx: u64,
+
+    // Alice
+    user_sending: Pubkey,
+
+    // The Mint of the token that Alice wants to send to every recipient
+    mint_of_token_being_sent: Pubkey,
+
+    // The escrow wallet holding the total of the batch
+    escrow_wallet: Pubkey,
+
+    // Root of the Merkle tree of `(index, recipient, amount)` entries
+    merkle_root: [u8; 32],
+
+    // The total amount of tokens Alice deposited for the batch
     amount_tokens: u64,
 
+    // The amount of tokens already paid out to recipients
+    amount_claimed: u64,
+
+    // Number of leaves in the Merkle tree
+    num_recipients: u32,
+
+    // From then on Alice can take back what was not claimed
+    expires_at: i64,
+
+    // One bit per leaf index, set once that entry has been paid
+    claimed_bitmap: Vec<u8>,
+
     // An enumm that is to represent some kind of state machine
     stage: u8,
 }
+impl BatchDetails {
+    fn bitmap_len(num_recipients: u32) -> usize {
+        (num_recipients as usize).div_ceil(8)
+    }
+
+    fn space(num_recipients: u32) -> usize {
+        8 + 8 + 32 + 32 + 32 + 32 + 8 + 8 + 4 + 8 + (4 + Self::bitmap_len(num_recipients)) + 1
+    }
+
+    fn is_claimed(&self, index: u32) -> bool {
+        self.claimed_bitmap[index as usize / 8] & (1 << (index % 8)) != 0
+    }
+
+    fn set_claimed(&mut self, index: u32) {
+        self.claimed_bitmap[index as usize / 8] |= 1 << (index % 8);
+    }
+}
+
+#[event]
+pub struct GrantCreated {
+    pub user_sending: Pubkey,
+    pub user_receiving: Pubkey,
+    pub mint_of_token_being_sent: Pubkey,
+    pub application_idx: u64,
+    pub amount: u64,
+    pub stage: u8,
+}
+
+#[event]
+pub struct GrantAccepted {
+    pub user_sending: Pubkey,
+    pub user_receiving: Pubkey,
+    pub mint_of_token_being_sent: Pubkey,
+    pub application_idx: u64,
+    pub amount: u64,
+    pub stage: u8,
+}
+
+#[event]
+pub struct GrantAmended {
+    pub user_sending: Pubkey,
+    pub user_receiving: Pubkey,
+    pub mint_of_token_being_sent: Pubkey,
+    pub application_idx: u64,
+    pub amount: u64,
+    pub amendments: u32,
+    pub stage: u8,
+}
+
+#[event]
+pub struct GrantCompleted {
+    pub user_sending: Pubkey,
+    pub user_receiving: Pubkey,
+    pub mint_of_token_being_sent: Pubkey,
+    pub application_idx: u64,
+    pub amount: u64,
+    // Part of `amount` that went to the fee recipient instead of Bob
+    pub fee: u64,
+    pub stage: u8,
+}
+
+#[event]
+pub struct GrantPulledBack {
+    pub user_sending: Pubkey,
+    pub user_receiving: Pubkey,
+    pub mint_of_token_being_sent: Pubkey,
+    pub application_idx: u64,
+    pub amount: u64,
+    pub stage: u8,
+    // Alice when she cancelled, Bob when he declined
+    pub authority: Pubkey,
+}
+
+#[event]
+pub struct SwapCreated {
+    pub user_sending: Pubkey,
+    pub user_receiving: Pubkey,
+    pub mint_of_token_being_sent: Pubkey,
+    pub mint_of_token_expected: Pubkey,
+    pub application_idx: u64,
+    pub amount: u64,
+    pub amount_expected: u64,
+    pub stage: u8,
+}
+
+#[event]
+pub struct SwapCompleted {
+    pub user_sending: Pubkey,
+    pub user_receiving: Pubkey,
+    pub mint_of_token_being_sent: Pubkey,
+    pub mint_of_token_expected: Pubkey,
+    pub application_idx: u64,
+    pub amount: u64,
+    pub amount_expected: u64,
+    pub stage: u8,
+}
+
+#[event]
+pub struct SwapCancelled {
+    pub user_sending: Pubkey,
+    pub user_receiving: Pubkey,
+    pub mint_of_token_being_sent: Pubkey,
+    pub mint_of_token_expected: Pubkey,
+    pub application_idx: u64,
+    pub amount: u64,
+    pub amount_expected: u64,
+    pub stage: u8,
+}
+
+#[event]
+pub struct BatchGrantCreated {
+    pub user_sending: Pubkey,
+    pub mint_of_token_being_sent: Pubkey,
+    pub batch_idx: u64,
+    pub amount: u64,
+    pub num_recipients: u32,
+    pub stage: u8,
+}
+
+#[event]
+pub struct BatchGrantClaimed {
+    pub user_sending: Pubkey,
+    pub user_receiving: Pubkey,
+    pub mint_of_token_being_sent: Pubkey,
+    pub batch_idx: u64,
+    pub index: u32,
+    pub amount: u64,
+    pub stage: u8,
+}
+
+#[event]
+pub struct BatchGrantReclaimed {
+    pub user_sending: Pubkey,
+    pub mint_of_token_being_sent: Pubkey,
+    pub batch_idx: u64,
+    // What was left unclaimed and went back to Alice
+    pub amount: u64,
+    pub stage: u8,
+}
+
+#[event]
+pub struct SubscriptionCreated {
+    pub user_sending: Pubkey,
+    pub user_receiving: Pubkey,
+    pub mint_of_token_being_sent: Pubkey,
+    pub application_idx: u64,
+    pub amount: u64,
+    pub period_seconds: i64,
+    pub periods_remaining: u32,
+    pub stage: u8,
+}
+
+#[event]
+pub struct SubscriptionCharged {
+    pub user_sending: Pubkey,
+    pub user_receiving: Pubkey,
+    pub mint_of_token_being_sent: Pubkey,
+    pub application_idx: u64,
+    pub amount: u64,
+    pub periods_remaining: u32,
+    pub stage: u8,
+}
+
+#[event]
+pub struct SubscriptionCancelled {
+    pub user_sending: Pubkey,
+    pub user_receiving: Pubkey,
+    pub mint_of_token_being_sent: Pubkey,
+    pub application_idx: u64,
+    pub amount: u64,
+    pub periods_remaining: u32,
+    pub stage: u8,
+}
 
 #[error_code]
 pub enum ErrorCode {
@@ -353,4 +2724,56 @@ pub enum ErrorCode {
     DelegateNotSetCorrectly,
     #[msg("Stage is invalid")]
     StageInvalid,
+    #[msg("Signer is neither the sender nor the receiver")]
+    UnauthorizedSigner,
+    #[msg("Refund wallet is not owned by the sender")]
+    RefundWalletInvalid,
+    #[msg("Number of batch recipients is invalid")]
+    BatchRecipientsInvalid,
+    #[msg("Batch recipient index is out of range")]
+    BatchRecipientIndexInvalid,
+    #[msg("Batch entry has already been claimed")]
+    BatchAlreadyClaimed,
+    #[msg("Merkle proof is invalid")]
+    BatchProofInvalid,
+    #[msg("Claim exceeds the amount deposited for the batch")]
+    BatchAmountExceeded,
+    #[msg("Signer is not the operator")]
+    UnauthorizedOperator,
+    #[msg("Fee is invalid")]
+    FeeInvalid,
+    #[msg("Fee wallet is required when a fee is charged")]
+    FeeWalletMissing,
+    #[msg("Fee wallet is not owned by the fee recipient")]
+    FeeWalletInvalid,
+    #[msg("Too many mints with a fee waiver")]
+    FeeWaiverListFull,
+    #[msg("Grant has no attester")]
+    AttesterNotSet,
+    #[msg("Attestation outcome is invalid")]
+    AttestationOutcomeInvalid,
+    #[msg("No valid attestation found in the transaction")]
+    AttestationMissing,
+    #[msg("Subscription terms are invalid")]
+    SubscriptionTermsInvalid,
+    #[msg("Subscription period has not elapsed yet")]
+    SubscriptionChargeTooEarly,
+    #[msg("Amendment amount is invalid")]
+    AmendmentInvalid,
+    #[msg("Receiver must sign amendments once the grant is accepted")]
+    ReceiverSignatureRequired,
+    #[msg("Batch expiry must be in the future")]
+    BatchExpiryInvalid,
+    #[msg("Batch has not expired yet")]
+    BatchNotExpired,
+    #[msg("Grant names an attester, only its verdict can settle it")]
+    AttestationRequired,
+    #[msg("Wallet already has a delegate, revoke it or use another wallet")]
+    WalletAlreadyDelegated,
+    #[msg("Batch escrow would receive less than the amount its entries add up to")]
+    BatchDepositInsufficient,
+    #[msg("Wallet has too many open grants, settle or decline some first")]
+    GrantIndexFull,
+    #[msg("Grant index still lists open grants")]
+    GrantIndexNotEmpty,
 }
@@ -0,0 +1,1178 @@
+use anchor_lang::{
+    prelude::{AccountInfo, Clock, Pubkey, Rent},
+    solana_program::{
+        entrypoint::ProgramResult,
+        instruction::{Instruction, InstructionError},
+        keccak,
+        program_error::ProgramError,
+        program_pack::Pack,
+        system_instruction, system_program,
+    },
+    AccountDeserialize, AccountSerialize, InstructionData, ToAccountMetas,
+};
+use anchor_spl::{
+    associated_token::{
+        get_associated_token_address, get_associated_token_address_with_program_id,
+        spl_associated_token_account::{self, instruction::create_associated_token_account},
+    },
+    token::spl_token,
+    token_2022::spl_token_2022::{
+        self,
+        extension::{
+            transfer_fee::instruction::initialize_transfer_fee_config, ExtensionType,
+            StateWithExtensions,
+        },
+    },
+};
+use solana_program_test::{processor, ProgramTest, ProgramTestContext};
+use solana_sdk::{
+    account::{Account, AccountSharedData},
+    signature::{Keypair, Signer},
+    transaction::{Transaction, TransactionError},
+};
+
+const ALICE_BALANCE: u64 = 1_337_000_000;
+const GRANT_AMOUNT: u64 = 20_000_000;
+
+struct Grant {
+    operator: Keypair,
+    alice: Keypair,
+    bob: Keypair,
+    mint: Pubkey,
+    alice_wallet: Pubkey,
+    bob_wallet: Pubkey,
+    idx: u64,
+    state: Pubkey,
+    escrow: Pubkey,
+    sender_index: Pubkey,
+    receiver_index: Pubkey,
+}
+
+// `safepay::entry` wants the slice to live as long as the accounts themselves. The clones share
+// the accounts' data and lamports, and leaking them for the rest of the test gives that lifetime.
+fn process_instruction(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    data: &[u8],
+) -> ProgramResult {
+    let accounts = Box::leak(accounts.to_vec().into_boxed_slice());
+    safepay::entry(program_id, accounts, data)
+}
+
+fn pda(seeds: &[&[u8]]) -> Pubkey {
+    Pubkey::find_program_address(seeds, &safepay::ID).0
+}
+
+fn config() -> Pubkey {
+    pda(&[b"config"])
+}
+
+fn instruction(accounts: impl ToAccountMetas, data: impl InstructionData) -> Instruction {
+    Instruction {
+        program_id: safepay::ID,
+        accounts: accounts.to_account_metas(None),
+        data: data.data(),
+    }
+}
+
+fn packed_account<T: Pack>(state: T) -> Account {
+    let mut data = vec![0; T::LEN];
+    T::pack(state, &mut data).unwrap();
+    Account {
+        lamports: Rent::default().minimum_balance(T::LEN),
+        data,
+        owner: spl_token::ID,
+        ..Account::default()
+    }
+}
+
+fn anchor_error(error: impl Into<anchor_lang::error::Error>) -> TransactionError {
+    match ProgramError::from(error.into()) {
+        ProgramError::Custom(code) => {
+            TransactionError::InstructionError(0, InstructionError::Custom(code))
+        }
+        error => panic!("unexpected program error {error:?}"),
+    }
+}
+
+async fn process(
+    context: &mut ProgramTestContext,
+    instruction: Instruction,
+    signers: &[&Keypair],
+) -> Result<(), TransactionError> {
+    // A fresh blockhash keeps a repeated instruction from being deduplicated
+    let blockhash = context.get_new_latest_blockhash().await.unwrap();
+    let mut all_signers = vec![&context.payer];
+    all_signers.extend_from_slice(signers);
+    let transaction = Transaction::new_signed_with_payer(
+        &[instruction],
+        Some(&context.payer.pubkey()),
+        &all_signers,
+        blockhash,
+    );
+    context
+        .banks_client
+        .process_transaction(transaction)
+        .await
+        .map_err(|error| error.unwrap())
+}
+
+async fn lamports(context: &mut ProgramTestContext, address: &Pubkey) -> u64 {
+    context.banks_client.get_balance(*address).await.unwrap()
+}
+
+async fn token_balance(context: &mut ProgramTestContext, address: &Pubkey) -> u64 {
+    context
+        .banks_client
+        .get_packed_account_data::<spl_token::state::Account>(*address)
+        .await
+        .unwrap()
+        .amount
+}
+
+async fn is_closed(context: &mut ProgramTestContext, address: &Pubkey) -> bool {
+    context
+        .banks_client
+        .get_account(*address)
+        .await
+        .unwrap()
+        .is_none()
+}
+
+// Sets up a fee-less config, funds Alice and Bob, and has Alice open a grant to Bob
+async fn setup() -> (ProgramTestContext, Grant) {
+    let mut program_test =
+        ProgramTest::new("safepay", safepay::ID, processor!(process_instruction));
+    let operator = Keypair::new();
+    let alice = Keypair::new();
+    let bob = Keypair::new();
+    let mint = Pubkey::new_unique();
+    for user in [&operator, &alice, &bob] {
+        program_test.add_account(
+            user.pubkey(),
+            Account::new(5_000_000_000, 0, &system_program::ID),
+        );
+    }
+
+    program_test.add_account(
+        mint,
+        packed_account(spl_token::state::Mint {
+            mint_authority: Some(operator.pubkey()).into(),
+            decimals: 6,
+            is_initialized: true,
+            ..Default::default()
+        }),
+    );
+    let alice_wallet = get_associated_token_address(&alice.pubkey(), &mint);
+    program_test.add_account(
+        alice_wallet,
+        packed_account(spl_token::state::Account {
+            mint,
+            owner: alice.pubkey(),
+            amount: ALICE_BALANCE,
+            state: spl_token::state::AccountState::Initialized,
+            ..Default::default()
+        }),
+    );
+    // Only the upgrade authority can create the config, so it is written directly
+    let config_state = safepay::Config {
+        operator: operator.pubkey(),
+        fee_recipient: operator.pubkey(),
+        fee_basis_points: 0,
+        fee_waived_mints: Vec::new(),
+    };
+    let mut data = Vec::new();
+    config_state.try_serialize(&mut data).unwrap();
+    data.resize(safepay::Config::SPACE, 0);
+    program_test.add_account(
+        config(),
+        Account {
+            lamports: Rent::default().minimum_balance(data.len()),
+            data,
+            owner: safepay::ID,
+            ..Account::default()
+        },
+    );
+    let mut context = program_test.start_with_context().await;
+
+    let idx = 0u64;
+    let (state, escrow) = grant_pdas(&alice.pubkey(), &bob.pubkey(), &mint, idx);
+    let grant = Grant {
+        bob_wallet: get_associated_token_address(&bob.pubkey(), &mint),
+        sender_index: pda(&[b"sender_index", alice.pubkey().as_ref()]),
+        receiver_index: pda(&[b"receiver_index", bob.pubkey().as_ref()]),
+        operator,
+        alice,
+        bob,
+        mint,
+        alice_wallet,
+        idx,
+        state,
+        escrow,
+    };
+
+    process(
+        &mut context,
+        initialize_new_grant(&grant, None),
+        &[&grant.alice],
+    )
+    .await
+    .unwrap();
+
+    (context, grant)
+}
+
+fn grant_pdas(alice: &Pubkey, bob: &Pubkey, mint: &Pubkey, idx: u64) -> (Pubkey, Pubkey) {
+    let state = pda(&[
+        b"state",
+        alice.as_ref(),
+        bob.as_ref(),
+        mint.as_ref(),
+        &idx.to_le_bytes(),
+    ]);
+    let escrow = pda(&[
+        b"wallet",
+        alice.as_ref(),
+        bob.as_ref(),
+        mint.as_ref(),
+        &idx.to_le_bytes(),
+    ]);
+    (state, escrow)
+}
+
+impl Grant {
+    // The grant Alice opens to Bob after this one
+    fn next(&self) -> Grant {
+        let idx = self.idx + 1;
+        let (state, escrow) = grant_pdas(&self.alice.pubkey(), &self.bob.pubkey(), &self.mint, idx);
+        Grant {
+            operator: self.operator.insecure_clone(),
+            alice: self.alice.insecure_clone(),
+            bob: self.bob.insecure_clone(),
+            idx,
+            state,
+            escrow,
+            ..*self
+        }
+    }
+}
+
+fn initialize_new_grant(grant: &Grant, attester: Option<Pubkey>) -> Instruction {
+    instruction(
+        safepay::accounts::InitializeNewGrant {
+            grant_counter: pda(&[b"counter", grant.alice.pubkey().as_ref()]),
+            application_state: grant.state,
+            escrow_wallet_state: grant.escrow,
+            sender_index: grant.sender_index,
+            receiver_index: grant.receiver_index,
+            config: config(),
+            user_sending: grant.alice.pubkey(),
+            user_receiving: grant.bob.pubkey(),
+            mint_of_token_being_sent: grant.mint,
+            wallet_to_withdraw_from: grant.alice_wallet,
+            system_program: system_program::ID,
+            token_program: spl_token::ID,
+        },
+        safepay::instruction::InitializeNewGrant {
+            amount: GRANT_AMOUNT,
+            attester,
+        },
+    )
+}
+
+fn complete_grant(grant: &Grant) -> Instruction {
+    instruction(
+        safepay::accounts::CompleteGrant {
+            application_state: grant.state,
+            escrow_wallet_state: grant.escrow,
+            sender_index: grant.sender_index,
+            receiver_index: grant.receiver_index,
+            wallet_to_deposit_to: grant.bob_wallet,
+            fee_wallet: None,
+            user_sending: grant.alice.pubkey(),
+            user_receiving: grant.bob.pubkey(),
+            mint_of_token_being_sent: grant.mint,
+            system_program: system_program::ID,
+            token_program: spl_token::ID,
+            associated_token_program: spl_associated_token_account::ID,
+        },
+        safepay::instruction::CompleteGrant {
+            application_idx: grant.idx,
+        },
+    )
+}
+
+fn pull_back(grant: &Grant, authority: Pubkey) -> Instruction {
+    instruction(
+        safepay::accounts::PullBackFunds {
+            application_state: grant.state,
+            escrow_wallet_state: grant.escrow,
+            sender_index: grant.sender_index,
+            receiver_index: grant.receiver_index,
+            authority,
+            user_sending: grant.alice.pubkey(),
+            user_receiving: grant.bob.pubkey(),
+            mint_of_token_being_sent: grant.mint,
+            refund_wallet: grant.alice_wallet,
+            system_program: system_program::ID,
+            token_program: spl_token::ID,
+            associated_token_program: spl_associated_token_account::ID,
+        },
+        safepay::instruction::PullBack {
+            application_idx: grant.idx,
+        },
+    )
+}
+
+#[tokio::test]
+async fn initialize_moves_the_amount_into_the_escrow() {
+    let (mut context, grant) = setup().await;
+
+    assert_eq!(
+        token_balance(&mut context, &grant.alice_wallet).await,
+        ALICE_BALANCE - GRANT_AMOUNT
+    );
+    assert_eq!(
+        token_balance(&mut context, &grant.escrow).await,
+        GRANT_AMOUNT
+    );
+    let state = context
+        .banks_client
+        .get_account(grant.state)
+        .await
+        .unwrap()
+        .expect("state account is created");
+    assert_eq!(state.owner, safepay::ID);
+}
+
+#[tokio::test]
+async fn complete_releases_the_escrow_to_bob_and_refunds_rent_to_alice() {
+    let (mut context, grant) = setup().await;
+    let alice_lamports = lamports(&mut context, &grant.alice.pubkey()).await;
+    let escrow_rent = lamports(&mut context, &grant.escrow).await;
+    let state_rent = lamports(&mut context, &grant.state).await;
+    let index_rent = lamports(&mut context, &grant.sender_index).await
+        + lamports(&mut context, &grant.receiver_index).await;
+    assert_eq!(
+        escrow_rent,
+        Rent::default().minimum_balance(spl_token::state::Account::LEN)
+    );
+
+    process(&mut context, complete_grant(&grant), &[&grant.bob])
+        .await
+        .unwrap();
+
+    assert_eq!(
+        token_balance(&mut context, &grant.bob_wallet).await,
+        GRANT_AMOUNT
+    );
+    assert!(is_closed(&mut context, &grant.escrow).await);
+    assert!(is_closed(&mut context, &grant.state).await);
+    // Bob's only open grant is gone, so both indexes are closed as well
+    assert!(is_closed(&mut context, &grant.receiver_index).await);
+    assert_eq!(
+        lamports(&mut context, &grant.alice.pubkey()).await,
+        alice_lamports + escrow_rent + state_rent + index_rent
+    );
+}
+
+#[tokio::test]
+async fn pull_back_returns_the_escrow_to_alice_and_refunds_rent() {
+    let (mut context, grant) = setup().await;
+    let alice_lamports = lamports(&mut context, &grant.alice.pubkey()).await;
+    let rent = lamports(&mut context, &grant.escrow).await
+        + lamports(&mut context, &grant.state).await
+        + lamports(&mut context, &grant.sender_index).await
+        + lamports(&mut context, &grant.receiver_index).await;
+
+    process(
+        &mut context,
+        pull_back(&grant, grant.alice.pubkey()),
+        &[&grant.alice],
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(
+        token_balance(&mut context, &grant.alice_wallet).await,
+        ALICE_BALANCE
+    );
+    assert!(is_closed(&mut context, &grant.escrow).await);
+    assert!(is_closed(&mut context, &grant.state).await);
+    assert_eq!(
+        lamports(&mut context, &grant.alice.pubkey()).await,
+        alice_lamports + rent
+    );
+}
+
+#[tokio::test]
+async fn a_completed_grant_cannot_be_completed_again() {
+    let (mut context, grant) = setup().await;
+    process(&mut context, complete_grant(&grant), &[&grant.bob])
+        .await
+        .unwrap();
+
+    let result = process(&mut context, complete_grant(&grant), &[&grant.bob]).await;
+
+    assert_eq!(
+        result,
+        Err(anchor_error(
+            anchor_lang::error::ErrorCode::AccountNotInitialized
+        ))
+    );
+    assert_eq!(
+        token_balance(&mut context, &grant.bob_wallet).await,
+        GRANT_AMOUNT
+    );
+}
+
+#[tokio::test]
+async fn a_pulled_back_grant_cannot_be_completed() {
+    let (mut context, grant) = setup().await;
+    process(
+        &mut context,
+        pull_back(&grant, grant.alice.pubkey()),
+        &[&grant.alice],
+    )
+    .await
+    .unwrap();
+
+    let result = process(&mut context, complete_grant(&grant), &[&grant.bob]).await;
+
+    assert_eq!(
+        result,
+        Err(anchor_error(
+            anchor_lang::error::ErrorCode::AccountNotInitialized
+        ))
+    );
+}
+
+#[tokio::test]
+async fn complete_requires_bobs_signature() {
+    let (mut context, grant) = setup().await;
+    let mut ix = complete_grant(&grant);
+    for account in ix.accounts.iter_mut() {
+        account.is_signer = false;
+    }
+
+    let result = process(&mut context, ix, &[]).await;
+
+    assert_eq!(
+        result,
+        Err(anchor_error(
+            anchor_lang::error::ErrorCode::AccountNotSigner
+        ))
+    );
+    assert_eq!(
+        token_balance(&mut context, &grant.escrow).await,
+        GRANT_AMOUNT
+    );
+}
+
+#[tokio::test]
+async fn only_alice_or_bob_can_pull_back() {
+    let (mut context, grant) = setup().await;
+    let mallory = Keypair::new();
+
+    let result = process(
+        &mut context,
+        pull_back(&grant, mallory.pubkey()),
+        &[&mallory],
+    )
+    .await;
+
+    assert_eq!(
+        result,
+        Err(anchor_error(safepay::ErrorCode::UnauthorizedSigner))
+    );
+    assert_eq!(
+        token_balance(&mut context, &grant.escrow).await,
+        GRANT_AMOUNT
+    );
+}
+
+#[tokio::test]
+async fn alice_cannot_pull_back_once_bob_accepted() {
+    let (mut context, grant) = setup().await;
+    process(
+        &mut context,
+        instruction(
+            safepay::accounts::AcceptGrant {
+                application_state: grant.state,
+                user_sending: grant.alice.pubkey(),
+                user_receiving: grant.bob.pubkey(),
+                mint_of_token_being_sent: grant.mint,
+            },
+            safepay::instruction::AcceptGrant {
+                _application_idx: grant.idx,
+            },
+        ),
+        &[&grant.bob],
+    )
+    .await
+    .unwrap();
+
+    let result = process(
+        &mut context,
+        pull_back(&grant, grant.alice.pubkey()),
+        &[&grant.alice],
+    )
+    .await;
+    assert_eq!(result, Err(anchor_error(safepay::ErrorCode::StageInvalid)));
+
+    // Bob can still take the funds he accepted
+    process(&mut context, complete_grant(&grant), &[&grant.bob])
+        .await
+        .unwrap();
+    assert_eq!(
+        token_balance(&mut context, &grant.bob_wallet).await,
+        GRANT_AMOUNT
+    );
+}
+
+fn update_fee(grant: &Grant, fee_basis_points: u16) -> Instruction {
+    instruction(
+        safepay::accounts::UpdateConfig {
+            config: config(),
+            operator: grant.operator.pubkey(),
+        },
+        safepay::instruction::UpdateConfig {
+            operator: grant.operator.pubkey(),
+            fee_recipient: grant.operator.pubkey(),
+            fee_basis_points,
+        },
+    )
+}
+
+#[tokio::test]
+async fn an_open_grant_keeps_the_fee_it_was_opened_with() {
+    let (mut context, grant) = setup().await;
+
+    let result = process(
+        &mut context,
+        update_fee(&grant, safepay::MAX_FEE_BASIS_POINTS + 1),
+        &[&grant.operator],
+    )
+    .await;
+    assert_eq!(result, Err(anchor_error(safepay::ErrorCode::FeeInvalid)));
+
+    process(
+        &mut context,
+        update_fee(&grant, safepay::MAX_FEE_BASIS_POINTS),
+        &[&grant.operator],
+    )
+    .await
+    .unwrap();
+
+    // The grant keeps the fee-less terms it was created with
+    process(&mut context, complete_grant(&grant), &[&grant.bob])
+        .await
+        .unwrap();
+    assert_eq!(
+        token_balance(&mut context, &grant.bob_wallet).await,
+        GRANT_AMOUNT
+    );
+}
+
+#[tokio::test]
+async fn alice_reclaims_an_expired_batch_that_was_not_fully_claimed() {
+    let (mut context, grant) = setup().await;
+    let batch_idx = 7u64;
+    let batch_state = pda(&[
+        b"batch_state",
+        grant.alice.pubkey().as_ref(),
+        grant.mint.as_ref(),
+        &batch_idx.to_le_bytes(),
+    ]);
+    let batch_escrow = pda(&[
+        b"batch_wallet",
+        grant.alice.pubkey().as_ref(),
+        grant.mint.as_ref(),
+        &batch_idx.to_le_bytes(),
+    ]);
+    // A single entry, so the leaf is the root and the proof is empty
+    let claim_amount = 5_000_000u64;
+    let root = keccak::hashv(&[
+        &0u32.to_le_bytes(),
+        grant.bob.pubkey().as_ref(),
+        &claim_amount.to_le_bytes(),
+    ])
+    .0;
+    let mut clock = context.banks_client.get_sysvar::<Clock>().await.unwrap();
+    let expires_at = clock.unix_timestamp + 3600;
+
+    process(
+        &mut context,
+        instruction(
+            safepay::accounts::InitializeNewBatchGrant {
+                batch_state,
+                escrow_wallet_state: batch_escrow,
+                user_sending: grant.alice.pubkey(),
+                mint_of_token_being_sent: grant.mint,
+                wallet_to_withdraw_from: grant.alice_wallet,
+                system_program: system_program::ID,
+                token_program: spl_token::ID,
+            },
+            safepay::instruction::InitializeNewBatchGrant {
+                batch_idx,
+                num_recipients: 2,
+                amount: GRANT_AMOUNT,
+                merkle_root: root,
+                expires_at,
+            },
+        ),
+        &[&grant.alice],
+    )
+    .await
+    .unwrap();
+    process(
+        &mut context,
+        instruction(
+            safepay::accounts::ClaimBatchGrant {
+                batch_state,
+                escrow_wallet_state: batch_escrow,
+                wallet_to_deposit_to: grant.bob_wallet,
+                user_sending: grant.alice.pubkey(),
+                user_receiving: grant.bob.pubkey(),
+                mint_of_token_being_sent: grant.mint,
+                system_program: system_program::ID,
+                token_program: spl_token::ID,
+                associated_token_program: spl_associated_token_account::ID,
+            },
+            safepay::instruction::ClaimBatchGrant {
+                _batch_idx: batch_idx,
+                index: 0,
+                amount: claim_amount,
+                proof: Vec::new(),
+            },
+        ),
+        &[&grant.bob],
+    )
+    .await
+    .unwrap();
+
+    let reclaim = instruction(
+        safepay::accounts::ReclaimBatchGrant {
+            batch_state,
+            escrow_wallet_state: batch_escrow,
+            refund_wallet: grant.alice_wallet,
+            user_sending: grant.alice.pubkey(),
+            mint_of_token_being_sent: grant.mint,
+            token_program: spl_token::ID,
+        },
+        safepay::instruction::ReclaimBatchGrant {
+            _batch_idx: batch_idx,
+        },
+    );
+    let result = process(&mut context, reclaim.clone(), &[&grant.alice]).await;
+    assert_eq!(
+        result,
+        Err(anchor_error(safepay::ErrorCode::BatchNotExpired))
+    );
+
+    clock.unix_timestamp = expires_at;
+    context.set_sysvar(&clock);
+    process(&mut context, reclaim, &[&grant.alice])
+        .await
+        .unwrap();
+
+    // Only the open grant's amount is still out of Alice's wallet
+    assert_eq!(
+        token_balance(&mut context, &grant.alice_wallet).await,
+        ALICE_BALANCE - GRANT_AMOUNT - claim_amount
+    );
+    assert!(is_closed(&mut context, &batch_escrow).await);
+    assert!(is_closed(&mut context, &batch_state).await);
+}
+
+#[tokio::test]
+async fn the_indexes_grow_with_open_grants_and_refund_their_rent() {
+    let (mut context, grant) = setup().await;
+    let second = grant.next();
+    let index_rent =
+        |open_grants: usize| Rent::default().minimum_balance(8 + 32 + 32 + 4 + 32 * open_grants);
+    process(
+        &mut context,
+        initialize_new_grant(&second, None),
+        &[&second.alice],
+    )
+    .await
+    .unwrap();
+    for index in [grant.sender_index, grant.receiver_index] {
+        assert_eq!(lamports(&mut context, &index).await, index_rent(2));
+    }
+
+    // Alice gets back the rent of the entries along with the state and escrow rent
+    let alice_lamports = lamports(&mut context, &grant.alice.pubkey()).await;
+    let rent =
+        lamports(&mut context, &grant.escrow).await + lamports(&mut context, &grant.state).await;
+    process(&mut context, complete_grant(&grant), &[&grant.bob])
+        .await
+        .unwrap();
+    for index in [grant.sender_index, grant.receiver_index] {
+        assert_eq!(lamports(&mut context, &index).await, index_rent(1));
+    }
+    assert_eq!(
+        lamports(&mut context, &grant.alice.pubkey()).await,
+        alice_lamports + rent + 2 * (index_rent(2) - index_rent(1))
+    );
+
+    // Empty indexes are closed
+    process(&mut context, complete_grant(&second), &[&second.bob])
+        .await
+        .unwrap();
+    assert!(is_closed(&mut context, &grant.sender_index).await);
+    assert!(is_closed(&mut context, &grant.receiver_index).await);
+}
+
+#[tokio::test]
+async fn a_full_index_refuses_new_grants_until_bob_declines_one() {
+    let (mut context, grant) = setup().await;
+    // Fills Bob's index up with other grants next to the one of `setup`
+    let index = context
+        .banks_client
+        .get_account(grant.receiver_index)
+        .await
+        .unwrap()
+        .unwrap();
+    let mut receiver_index =
+        safepay::GrantIndex::try_deserialize(&mut index.data.as_slice()).unwrap();
+    receiver_index
+        .open_grants
+        .resize_with(safepay::MAX_OPEN_GRANTS, Pubkey::new_unique);
+    let mut data = Vec::new();
+    receiver_index.try_serialize(&mut data).unwrap();
+    context.set_account(
+        &grant.receiver_index,
+        &AccountSharedData::from(Account {
+            lamports: Rent::default().minimum_balance(data.len()),
+            data,
+            ..index
+        }),
+    );
+
+    let second = grant.next();
+    let result = process(
+        &mut context,
+        initialize_new_grant(&second, None),
+        &[&second.alice],
+    )
+    .await;
+    assert_eq!(
+        result,
+        Err(anchor_error(safepay::ErrorCode::GrantIndexFull))
+    );
+
+    process(
+        &mut context,
+        pull_back(&grant, grant.bob.pubkey()),
+        &[&grant.bob],
+    )
+    .await
+    .unwrap();
+    process(
+        &mut context,
+        initialize_new_grant(&second, None),
+        &[&second.alice],
+    )
+    .await
+    .unwrap();
+}
+
+#[tokio::test]
+async fn an_index_emptied_by_another_sender_refunds_its_payer() {
+    let (mut context, grant) = setup().await;
+    // Carol also opens a grant to Bob, whose index Alice created
+    let carol = Keypair::new();
+    let carol_wallet = get_associated_token_address(&carol.pubkey(), &grant.mint);
+    context.set_account(
+        &carol.pubkey(),
+        &AccountSharedData::from(Account::new(5_000_000_000, 0, &system_program::ID)),
+    );
+    context.set_account(
+        &carol_wallet,
+        &AccountSharedData::from(packed_account(spl_token::state::Account {
+            mint: grant.mint,
+            owner: carol.pubkey(),
+            amount: ALICE_BALANCE,
+            state: spl_token::state::AccountState::Initialized,
+            ..Default::default()
+        })),
+    );
+    let (state, escrow) = grant_pdas(&carol.pubkey(), &grant.bob.pubkey(), &grant.mint, 0);
+    let sender_index = pda(&[b"sender_index", carol.pubkey().as_ref()]);
+    let from_carol = Grant {
+        operator: grant.operator.insecure_clone(),
+        alice: carol,
+        bob: grant.bob.insecure_clone(),
+        alice_wallet: carol_wallet,
+        idx: 0,
+        state,
+        escrow,
+        sender_index,
+        ..grant.next()
+    };
+    process(
+        &mut context,
+        initialize_new_grant(&from_carol, None),
+        &[&from_carol.alice],
+    )
+    .await
+    .unwrap();
+
+    process(&mut context, complete_grant(&grant), &[&grant.bob])
+        .await
+        .unwrap();
+    process(&mut context, complete_grant(&from_carol), &[&grant.bob])
+        .await
+        .unwrap();
+
+    // Carol settled last, yet the base rent of Bob's index stays for Alice
+    let base_rent = Rent::default().minimum_balance(8 + 32 + 32 + 4);
+    assert!(is_closed(&mut context, &from_carol.sender_index).await);
+    assert_eq!(
+        lamports(&mut context, &grant.receiver_index).await,
+        base_rent
+    );
+
+    let alice_lamports = lamports(&mut context, &grant.alice.pubkey()).await;
+    process(
+        &mut context,
+        instruction(
+            safepay::accounts::CloseGrantIndex {
+                grant_index: grant.receiver_index,
+                payer: grant.alice.pubkey(),
+            },
+            safepay::instruction::CloseGrantIndex {},
+        ),
+        &[],
+    )
+    .await
+    .unwrap();
+    assert!(is_closed(&mut context, &grant.receiver_index).await);
+    assert_eq!(
+        lamports(&mut context, &grant.alice.pubkey()).await,
+        alice_lamports + base_rent
+    );
+}
+
+#[tokio::test]
+async fn an_attested_grant_is_only_settled_by_the_attester_or_declined_by_bob() {
+    let (mut context, grant) = setup().await;
+    let attested = grant.next();
+    process(
+        &mut context,
+        initialize_new_grant(&attested, Some(Pubkey::new_unique())),
+        &[&attested.alice],
+    )
+    .await
+    .unwrap();
+
+    let result = process(&mut context, complete_grant(&attested), &[&attested.bob]).await;
+    assert_eq!(
+        result,
+        Err(anchor_error(safepay::ErrorCode::AttestationRequired))
+    );
+    let result = process(
+        &mut context,
+        pull_back(&attested, attested.alice.pubkey()),
+        &[&attested.alice],
+    )
+    .await;
+    assert_eq!(
+        result,
+        Err(anchor_error(safepay::ErrorCode::AttestationRequired))
+    );
+
+    // Declining only hands the funds back to Alice
+    process(
+        &mut context,
+        pull_back(&attested, attested.bob.pubkey()),
+        &[&attested.bob],
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        token_balance(&mut context, &grant.alice_wallet).await,
+        ALICE_BALANCE - GRANT_AMOUNT
+    );
+}
+
+async fn token_2022_balance(context: &mut ProgramTestContext, address: &Pubkey) -> u64 {
+    let account = context
+        .banks_client
+        .get_account(*address)
+        .await
+        .unwrap()
+        .unwrap();
+    StateWithExtensions::<spl_token_2022::state::Account>::unpack(&account.data)
+        .unwrap()
+        .base
+        .amount
+}
+
+// Creates a Token-2022 mint with a transfer fee of `fee_basis_points`, `authority` mints
+// `ALICE_BALANCE` into the associated wallet of `holder`
+async fn token_2022_mint(
+    context: &mut ProgramTestContext,
+    authority: &Keypair,
+    holder: &Pubkey,
+    fee_basis_points: u16,
+) -> (Keypair, Pubkey) {
+    let mint = Keypair::new();
+    let mint_len = ExtensionType::try_calculate_account_len::<spl_token_2022::state::Mint>(&[
+        ExtensionType::TransferFeeConfig,
+    ])
+    .unwrap();
+    let wallet =
+        get_associated_token_address_with_program_id(holder, &mint.pubkey(), &spl_token_2022::ID);
+    let payer = context.payer.pubkey();
+    process(
+        context,
+        system_instruction::create_account(
+            &payer,
+            &mint.pubkey(),
+            Rent::default().minimum_balance(mint_len),
+            mint_len as u64,
+            &spl_token_2022::ID,
+        ),
+        &[&mint],
+    )
+    .await
+    .unwrap();
+    for instruction in [
+        initialize_transfer_fee_config(
+            &spl_token_2022::ID,
+            &mint.pubkey(),
+            None,
+            None,
+            fee_basis_points,
+            u64::MAX,
+        )
+        .unwrap(),
+        spl_token_2022::instruction::initialize_mint2(
+            &spl_token_2022::ID,
+            &mint.pubkey(),
+            &authority.pubkey(),
+            None,
+            6,
+        )
+        .unwrap(),
+        create_associated_token_account(&payer, holder, &mint.pubkey(), &spl_token_2022::ID),
+    ] {
+        process(context, instruction, &[]).await.unwrap();
+    }
+    process(
+        context,
+        spl_token_2022::instruction::mint_to(
+            &spl_token_2022::ID,
+            &mint.pubkey(),
+            &wallet,
+            &authority.pubkey(),
+            &[],
+            ALICE_BALANCE,
+        )
+        .unwrap(),
+        &[authority],
+    )
+    .await
+    .unwrap();
+
+    (mint, wallet)
+}
+
+#[tokio::test]
+async fn a_batch_on_a_transfer_fee_mint_pays_out_every_entry() {
+    let (mut context, grant) = setup().await;
+    // A 1% transfer fee mint, Alice holds some of it
+    let (mint, alice_wallet) =
+        token_2022_mint(&mut context, &grant.operator, &grant.alice.pubkey(), 100).await;
+
+    // Bob and the operator are owed half of the batch each
+    let batch_idx = 3u64;
+    let batch_state = pda(&[
+        b"batch_state",
+        grant.alice.pubkey().as_ref(),
+        mint.pubkey().as_ref(),
+        &batch_idx.to_le_bytes(),
+    ]);
+    let batch_escrow = pda(&[
+        b"batch_wallet",
+        grant.alice.pubkey().as_ref(),
+        mint.pubkey().as_ref(),
+        &batch_idx.to_le_bytes(),
+    ]);
+    let claim_amount = GRANT_AMOUNT / 2;
+    let recipients = [&grant.bob, &grant.operator];
+    let leaves: Vec<[u8; 32]> = recipients
+        .iter()
+        .enumerate()
+        .map(|(index, recipient)| {
+            keccak::hashv(&[
+                &(index as u32).to_le_bytes(),
+                recipient.pubkey().as_ref(),
+                &claim_amount.to_le_bytes(),
+            ])
+            .0
+        })
+        .collect();
+    let (low, high) = if leaves[0] <= leaves[1] {
+        (leaves[0], leaves[1])
+    } else {
+        (leaves[1], leaves[0])
+    };
+    let root = keccak::hashv(&[&low, &high]).0;
+    let clock = context.banks_client.get_sysvar::<Clock>().await.unwrap();
+
+    process(
+        &mut context,
+        instruction(
+            safepay::accounts::InitializeNewBatchGrant {
+                batch_state,
+                escrow_wallet_state: batch_escrow,
+                user_sending: grant.alice.pubkey(),
+                mint_of_token_being_sent: mint.pubkey(),
+                wallet_to_withdraw_from: alice_wallet,
+                system_program: system_program::ID,
+                token_program: spl_token_2022::ID,
+            },
+            safepay::instruction::InitializeNewBatchGrant {
+                batch_idx,
+                num_recipients: 2,
+                amount: GRANT_AMOUNT,
+                merkle_root: root,
+                expires_at: clock.unix_timestamp + 3600,
+            },
+        ),
+        &[&grant.alice],
+    )
+    .await
+    .unwrap();
+    // Alice paid the fee on top, the escrow holds the whole batch
+    assert!(token_2022_balance(&mut context, &alice_wallet).await < ALICE_BALANCE - GRANT_AMOUNT);
+    assert_eq!(
+        token_2022_balance(&mut context, &batch_escrow).await,
+        GRANT_AMOUNT
+    );
+
+    for (index, recipient) in recipients.iter().enumerate() {
+        let wallet = get_associated_token_address_with_program_id(
+            &recipient.pubkey(),
+            &mint.pubkey(),
+            &spl_token_2022::ID,
+        );
+        process(
+            &mut context,
+            instruction(
+                safepay::accounts::ClaimBatchGrant {
+                    batch_state,
+                    escrow_wallet_state: batch_escrow,
+                    wallet_to_deposit_to: wallet,
+                    user_sending: grant.alice.pubkey(),
+                    user_receiving: recipient.pubkey(),
+                    mint_of_token_being_sent: mint.pubkey(),
+                    system_program: system_program::ID,
+                    token_program: spl_token_2022::ID,
+                    associated_token_program: spl_associated_token_account::ID,
+                },
+                safepay::instruction::ClaimBatchGrant {
+                    _batch_idx: batch_idx,
+                    index: index as u32,
+                    amount: claim_amount,
+                    proof: vec![leaves[1 - index]],
+                },
+            ),
+            &[recipient],
+        )
+        .await
+        .unwrap();
+        // Each recipient bears the fee of their own payout
+        assert_eq!(
+            token_2022_balance(&mut context, &wallet).await,
+            claim_amount - claim_amount / 100
+        );
+    }
+
+    assert!(is_closed(&mut context, &batch_escrow).await);
+    assert!(is_closed(&mut context, &batch_state).await);
+}
+
+#[tokio::test]
+async fn a_swap_settles_tokens_of_different_token_programs() {
+    let (mut context, grant) = setup().await;
+    // Alice offers her SPL Token mint against Bob's Token-2022 mint
+    let (mint_expected, bob_wallet) =
+        token_2022_mint(&mut context, &grant.operator, &grant.bob.pubkey(), 0).await;
+    let swap = grant.next();
+    let amount_expected = 7_000_000u64;
+    process(
+        &mut context,
+        instruction(
+            safepay::accounts::InitializeNewSwap {
+                grant_counter: pda(&[b"counter", swap.alice.pubkey().as_ref()]),
+                application_state: swap.state,
+                escrow_wallet_state: swap.escrow,
+                sender_index: swap.sender_index,
+                receiver_index: swap.receiver_index,
+                user_sending: swap.alice.pubkey(),
+                user_receiving: swap.bob.pubkey(),
+                mint_of_token_being_sent: swap.mint,
+                mint_of_token_expected: mint_expected.pubkey(),
+                wallet_to_withdraw_from: swap.alice_wallet,
+                system_program: system_program::ID,
+                token_program: spl_token::ID,
+            },
+            safepay::instruction::InitializeNewSwap {
+                amount: GRANT_AMOUNT,
+                amount_expected,
+            },
+        ),
+        &[&swap.alice],
+    )
+    .await
+    .unwrap();
+
+    let alice_expected_wallet = get_associated_token_address_with_program_id(
+        &swap.alice.pubkey(),
+        &mint_expected.pubkey(),
+        &spl_token_2022::ID,
+    );
+    process(
+        &mut context,
+        instruction(
+            safepay::accounts::AcceptSwap {
+                application_state: swap.state,
+                escrow_wallet_state: swap.escrow,
+                sender_index: swap.sender_index,
+                receiver_index: swap.receiver_index,
+                wallet_to_deposit_to: swap.bob_wallet,
+                sender_wallet_to_deposit_to: alice_expected_wallet,
+                wallet_to_withdraw_from: bob_wallet,
+                user_sending: swap.alice.pubkey(),
+                user_receiving: swap.bob.pubkey(),
+                mint_of_token_being_sent: swap.mint,
+                mint_of_token_expected: mint_expected.pubkey(),
+                system_program: system_program::ID,
+                token_program: spl_token::ID,
+                expected_token_program: spl_token_2022::ID,
+                associated_token_program: spl_associated_token_account::ID,
+            },
+            safepay::instruction::AcceptSwap {
+                application_idx: swap.idx,
+            },
+        ),
+        &[&swap.bob],
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(
+        token_balance(&mut context, &swap.bob_wallet).await,
+        GRANT_AMOUNT
+    );
+    assert_eq!(
+        token_2022_balance(&mut context, &alice_expected_wallet).await,
+        amount_expected
+    );
+    assert!(is_closed(&mut context, &swap.state).await);
+}
@@ -9,10 +9,14 @@ import {
 } from "@solana/spl-token";
 import { Safepay } from "../target/types/safepay";
 import { assert } from "chai";
+import { keccak_256 } from "@noble/hashes/sha3";
 
 interface PDAParameters {
   escrowWalletKey: anchor.web3.PublicKey;
   stateKey: anchor.web3.PublicKey;
+  counterKey: anchor.web3.PublicKey;
+  senderIndexKey: anchor.web3.PublicKey;
+  receiverIndexKey: anchor.web3.PublicKey;
   idx: anchor.BN;
 }
 
@@ -34,7 +38,15 @@ describe("safepay", () => {
     bob: anchor.web3.PublicKey,
     mint: anchor.web3.PublicKey
   ): Promise<PDAParameters> => {
-    const uid = new anchor.BN(parseInt((Date.now() / 1000).toString()));
+    // The next index is handed out by Alice's counter account
+    let [counterPubKey] = PublicKey.findProgramAddressSync(
+      [Buffer.from("counter"), alice.toBuffer()],
+      program.programId
+    );
+    const counter = await program.account.grantCounter.fetchNullable(
+      counterPubKey
+    );
+    const uid = counter ? counter.nextIdx : new anchor.BN(0);
     const uidBuffer = uid.toBuffer("le", 8);
 
     let [statePubKey] = PublicKey.findProgramAddressSync(
@@ -57,10 +69,21 @@ describe("safepay", () => {
       ],
       program.programId
     );
+    let [senderIndexPubKey] = PublicKey.findProgramAddressSync(
+      [Buffer.from("sender_index"), alice.toBuffer()],
+      program.programId
+    );
+    let [receiverIndexPubKey] = PublicKey.findProgramAddressSync(
+      [Buffer.from("receiver_index"), bob.toBuffer()],
+      program.programId
+    );
     return {
       idx: uid,
       escrowWalletKey: walletPubKey,
       stateKey: statePubKey,
+      counterKey: counterPubKey,
+      senderIndexKey: senderIndexPubKey,
+      receiverIndexKey: receiverIndexPubKey,
     };
   };
 
@@ -170,6 +193,32 @@ describe("safepay", () => {
   //   };
   // };
 
+  const [configKey] = PublicKey.findProgramAddressSync(
+    [Buffer.from("config")],
+    program.programId
+  );
+
+  before(async () => {
+    // The provider wallet deployed the program and acts as the platform operator,
+    // without any fee by default
+    if (!(await program.account.config.fetchNullable(configKey))) {
+      const [programData] = PublicKey.findProgramAddressSync(
+        [program.programId.toBuffer()],
+        new PublicKey("BPFLoaderUpgradeab1e11111111111111111111111")
+      );
+      await program.methods
+        .initializeConfig(provider.wallet.publicKey, 0)
+        .accounts({
+          config: configKey,
+          operator: provider.wallet.publicKey,
+          program: program.programId,
+          programData,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .rpc();
+    }
+  });
+
   beforeEach(async () => {
     mintAddress = await createMintToken(provider.connection);
     [alice, aliceWallet] = await createUserAndAssociatedWallet(
@@ -194,10 +243,14 @@ describe("safepay", () => {
     const amount = new anchor.BN(20000000);
 
     const tx = await program.methods
-      .initializeNewGrant(pda.idx, amount)
+      .initializeNewGrant(amount, null)
       .accounts({
         applicationState: pda.stateKey,
         escrowWalletState: pda.escrowWalletKey,
+        grantCounter: pda.counterKey,
+        senderIndex: pda.senderIndexKey,
+        receiverIndex: pda.receiverIndexKey,
+        config: configKey,
         userSending: alice.publicKey,
         userReceiving: bob.publicKey,
         mintOfTokenBeingSent: mintAddress,
@@ -216,6 +269,20 @@ describe("safepay", () => {
 
     console.log("Initialize New Grant transaction signature", tx);
 
+    // Assert that the grant is listed for both parties and the counter moved on.
+    const senderIndex = await program.account.grantIndex.fetch(
+      pda.senderIndexKey
+    );
+    assert.ok(senderIndex.openGrants[0].equals(pda.stateKey));
+    const receiverIndex = await program.account.grantIndex.fetch(
+      pda.receiverIndexKey
+    );
+    assert.ok(receiverIndex.openGrants[0].equals(pda.stateKey));
+    assert.equal(
+      (await program.account.grantCounter.fetch(pda.counterKey)).nextIdx.toNumber(),
+      1
+    );
+
     // Fetch the details of Application State account
     console.log(
       "Current State After",
@@ -241,12 +308,21 @@ describe("safepay", () => {
     );
     console.log("Bob Associated Account", bobTokenAccount);
 
+    let completedEvent;
+    const listener = program.addEventListener(
+      "GrantCompleted",
+      (event) => (completedEvent = event)
+    );
+
     const tx2 = await program.methods
       .completeGrant(pda.idx)
       .accounts({
         applicationState: pda.stateKey,
         escrowWalletState: pda.escrowWalletKey,
+        senderIndex: pda.senderIndexKey,
+        receiverIndex: pda.receiverIndexKey,
         walletToDepositTo: bobTokenAccount,
+        feeWallet: null,
         userSending: alice.publicKey,
         userReceiving: bob.publicKey,
         mintOfTokenBeingSent: mintAddress,
@@ -261,6 +337,13 @@ describe("safepay", () => {
 
     console.log("Complete Grant transaction signature", tx2);
 
+    // Assert that the outcome was emitted before the state account was closed.
+    await new Promise((resolve) => setTimeout(resolve, 1000));
+    await program.removeEventListener(listener);
+    assert.ok(completedEvent.applicationIdx.eq(pda.idx));
+    assert.ok(completedEvent.amount.eq(amount));
+    assert.equal(completedEvent.stage, 2);
+
     // Assert that 20 tokens were sent back.
     const [, bobBalance] = await readAccount(bobTokenAccount, provider);
     assert.equal(bobBalance, "20000000");
@@ -275,23 +358,31 @@ describe("safepay", () => {
         "Cannot read properties of null (reading 'data')"
       );
     }
-    // Fetch the details of Application State account
-    console.log(
-      "Current State After",
-      (await program.account.details.fetch(pda.stateKey)).stage
+    // Assert that the Application State account was closed as well.
+    assert.isNull(await program.account.details.fetchNullable(pda.stateKey));
+    // Neither party has another open grant, so their indexes were closed too.
+    assert.isNull(
+      await program.account.grantIndex.fetchNullable(pda.senderIndexKey)
+    );
+    assert.isNull(
+      await program.account.grantIndex.fetchNullable(pda.receiverIndexKey)
     );
   });
 
-  it.only("can pull back funds once they are deposited", async () => {
+  it("can pull back funds once they are deposited", async () => {
     const [, aliceBalancePre] = await readAccount(aliceWallet, provider);
     assert.equal(aliceBalancePre, "1337000000");
     const amount = new anchor.BN(20000000);
 
     const tx = await program.methods
-      .initializeNewGrant(pda.idx, amount)
+      .initializeNewGrant(amount, null)
       .accounts({
         applicationState: pda.stateKey,
         escrowWalletState: pda.escrowWalletKey,
+        grantCounter: pda.counterKey,
+        senderIndex: pda.senderIndexKey,
+        receiverIndex: pda.receiverIndexKey,
+        config: configKey,
         userSending: alice.publicKey,
         userReceiving: bob.publicKey,
         mintOfTokenBeingSent: mintAddress,
@@ -332,7 +423,10 @@ describe("safepay", () => {
       .accounts({
         applicationState: pda.stateKey,
         escrowWalletState: pda.escrowWalletKey,
+        senderIndex: pda.senderIndexKey,
+        receiverIndex: pda.receiverIndexKey,
         refundWallet: aliceWallet,
+        authority: alice.publicKey,
         userSending: alice.publicKey,
         userReceiving: bob.publicKey,
         mintOfTokenBeingSent: mintAddress,
@@ -341,7 +435,7 @@ describe("safepay", () => {
         tokenProgram: spl.TOKEN_PROGRAM_ID,
         // rent: anchor.web3.SYSVAR_RENT_PUBKEY,
       })
-      .signers([bob])
+      .signers([alice])
       .rpc();
 
     console.log("Pull Back transaction signature", tx2);
@@ -349,6 +443,7 @@ describe("safepay", () => {
     // Assert that 20 tokens were sent back.
     const [, aliceBalanceRefund] = await readAccount(aliceWallet, provider);
     assert.equal(aliceBalanceRefund, "1337000000");
+    assert.isNull(await program.account.details.fetchNullable(pda.stateKey));
     // // Assert that escrow was correctly closed.
     try {
       const [info, balance] = await readAccount(pda.escrowWalletKey, provider);
@@ -361,4 +456,945 @@ describe("safepay", () => {
       );
     }
   });
+
+  it("bob can decline a grant and only the parties can pull back", async () => {
+    const amount = new anchor.BN(20000000);
+
+    await program.methods
+      .initializeNewGrant(amount, null)
+      .accounts({
+        applicationState: pda.stateKey,
+        escrowWalletState: pda.escrowWalletKey,
+        grantCounter: pda.counterKey,
+        senderIndex: pda.senderIndexKey,
+        receiverIndex: pda.receiverIndexKey,
+        config: configKey,
+        userSending: alice.publicKey,
+        userReceiving: bob.publicKey,
+        mintOfTokenBeingSent: mintAddress,
+        walletToWithdrawFrom: aliceWallet,
+
+        systemProgram: anchor.web3.SystemProgram.programId,
+        tokenProgram: spl.TOKEN_PROGRAM_ID,
+      })
+      .signers([alice])
+      .rpc();
+
+    const pullBackAs = (authority: anchor.web3.Keypair) =>
+      program.methods
+        .pullBack(pda.idx)
+        .accounts({
+          applicationState: pda.stateKey,
+          escrowWalletState: pda.escrowWalletKey,
+          senderIndex: pda.senderIndexKey,
+          receiverIndex: pda.receiverIndexKey,
+          refundWallet: aliceWallet,
+          authority: authority.publicKey,
+          userSending: alice.publicKey,
+          userReceiving: bob.publicKey,
+          mintOfTokenBeingSent: mintAddress,
+
+          systemProgram: anchor.web3.SystemProgram.programId,
+          tokenProgram: spl.TOKEN_PROGRAM_ID,
+        })
+        .signers([authority])
+        .rpc();
+
+    // A third party cannot pull the funds back.
+    const [mallory] = await createUserAndAssociatedWallet(provider.connection);
+    try {
+      await pullBackAs(mallory);
+      return assert.fail("Only Alice or Bob can pull back");
+    } catch (e) {
+      assert.include(e.message, "UnauthorizedSigner");
+    }
+
+    const tx = await pullBackAs(bob);
+    console.log("Decline transaction signature", tx);
+
+    const [, aliceBalanceRefund] = await readAccount(aliceWallet, provider);
+    assert.equal(aliceBalanceRefund, "1337000000");
+  });
+
+  it("alice cannot pull back once bob accepted the grant", async () => {
+    const amount = new anchor.BN(20000000);
+
+    await program.methods
+      .initializeNewGrant(amount, null)
+      .accounts({
+        applicationState: pda.stateKey,
+        escrowWalletState: pda.escrowWalletKey,
+        grantCounter: pda.counterKey,
+        senderIndex: pda.senderIndexKey,
+        receiverIndex: pda.receiverIndexKey,
+        config: configKey,
+        userSending: alice.publicKey,
+        userReceiving: bob.publicKey,
+        mintOfTokenBeingSent: mintAddress,
+        walletToWithdrawFrom: aliceWallet,
+
+        systemProgram: anchor.web3.SystemProgram.programId,
+        tokenProgram: spl.TOKEN_PROGRAM_ID,
+      })
+      .signers([alice])
+      .rpc();
+
+    const tx = await program.methods
+      .acceptGrant(pda.idx)
+      .accounts({
+        applicationState: pda.stateKey,
+        userSending: alice.publicKey,
+        userReceiving: bob.publicKey,
+        mintOfTokenBeingSent: mintAddress,
+      })
+      .signers([bob])
+      .rpc();
+
+    console.log("Accept Grant transaction signature", tx);
+    assert.equal(
+      (await program.account.details.fetch(pda.stateKey)).stage,
+      4
+    );
+
+    try {
+      await program.methods
+        .pullBack(pda.idx)
+        .accounts({
+          applicationState: pda.stateKey,
+          escrowWalletState: pda.escrowWalletKey,
+          senderIndex: pda.senderIndexKey,
+          receiverIndex: pda.receiverIndexKey,
+          refundWallet: aliceWallet,
+          authority: alice.publicKey,
+          userSending: alice.publicKey,
+          userReceiving: bob.publicKey,
+          mintOfTokenBeingSent: mintAddress,
+
+          systemProgram: anchor.web3.SystemProgram.programId,
+          tokenProgram: spl.TOKEN_PROGRAM_ID,
+        })
+        .signers([alice])
+        .rpc();
+      return assert.fail("Alice should not pull back an accepted grant");
+    } catch (e) {
+      assert.include(e.message, "StageInvalid");
+    }
+
+    const bobTokenAccount = await spl.getAssociatedTokenAddress(
+      mintAddress,
+      bob.publicKey
+    );
+    await program.methods
+      .completeGrant(pda.idx)
+      .accounts({
+        applicationState: pda.stateKey,
+        escrowWalletState: pda.escrowWalletKey,
+        senderIndex: pda.senderIndexKey,
+        receiverIndex: pda.receiverIndexKey,
+        walletToDepositTo: bobTokenAccount,
+        feeWallet: null,
+        userSending: alice.publicKey,
+        userReceiving: bob.publicKey,
+        mintOfTokenBeingSent: mintAddress,
+
+        systemProgram: anchor.web3.SystemProgram.programId,
+        tokenProgram: spl.TOKEN_PROGRAM_ID,
+        associatedTokenProgram: spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+      })
+      .signers([bob])
+      .rpc();
+
+    const [, bobBalance] = await readAccount(bobTokenAccount, provider);
+    assert.equal(bobBalance, "20000000");
+  });
+
+  it("alice amends a grant alone until bob accepts it", async () => {
+    await program.methods
+      .initializeNewGrant(new anchor.BN(20000000), null)
+      .accounts({
+        applicationState: pda.stateKey,
+        escrowWalletState: pda.escrowWalletKey,
+        grantCounter: pda.counterKey,
+        senderIndex: pda.senderIndexKey,
+        receiverIndex: pda.receiverIndexKey,
+        config: configKey,
+        userSending: alice.publicKey,
+        userReceiving: bob.publicKey,
+        mintOfTokenBeingSent: mintAddress,
+        walletToWithdrawFrom: aliceWallet,
+
+        systemProgram: anchor.web3.SystemProgram.programId,
+        tokenProgram: spl.TOKEN_PROGRAM_ID,
+      })
+      .signers([alice])
+      .rpc();
+
+    const amend = (amount: number, isTopUp: boolean) =>
+      program.methods
+        .amendGrant(pda.idx, new anchor.BN(amount), isTopUp)
+        .accounts({
+          applicationState: pda.stateKey,
+          escrowWalletState: pda.escrowWalletKey,
+          senderWallet: aliceWallet,
+          userSending: alice.publicKey,
+          userReceiving: bob.publicKey,
+          mintOfTokenBeingSent: mintAddress,
+          tokenProgram: spl.TOKEN_PROGRAM_ID,
+        });
+
+    await amend(5000000, true).signers([alice]).rpc();
+    await amend(10000000, false).signers([alice]).rpc();
+
+    const [, escrowBalance] = await readAccount(pda.escrowWalletKey, provider);
+    assert.equal(escrowBalance, "15000000");
+    const [, aliceBalance] = await readAccount(aliceWallet, provider);
+    assert.equal(aliceBalance, "1322000000");
+    let state = await program.account.details.fetch(pda.stateKey);
+    assert.equal(state.amountTokens.toString(), "15000000");
+    assert.equal(state.amendments, 2);
+
+    await program.methods
+      .acceptGrant(pda.idx)
+      .accounts({
+        applicationState: pda.stateKey,
+        userSending: alice.publicKey,
+        userReceiving: bob.publicKey,
+        mintOfTokenBeingSent: mintAddress,
+      })
+      .signers([bob])
+      .rpc();
+
+    try {
+      await amend(5000000, false).signers([alice]).rpc();
+      return assert.fail("Bob has to agree once he accepted");
+    } catch (e) {
+      assert.include(e.message, "ReceiverSignatureRequired");
+    }
+
+    // Bob is not a signer in the IDL, so he is flagged on the instruction itself
+    const ix = await amend(5000000, false).instruction();
+    ix.keys.find((key) => key.pubkey.equals(bob.publicKey)).isSigner = true;
+    await provider.sendAndConfirm(new anchor.web3.Transaction().add(ix), [
+      alice,
+      bob,
+    ]);
+
+    state = await program.account.details.fetch(pda.stateKey);
+    assert.equal(state.amountTokens.toString(), "10000000");
+    assert.equal(state.amendments, 3);
+  });
+
+  it("charges the operator fee on completion unless the mint is waived", async () => {
+    const operator = provider.wallet.publicKey;
+    const feeWallet = (
+      await getOrCreateAssociatedTokenAccount(
+        provider.connection,
+        (provider.wallet as anchor.Wallet).payer,
+        mintAddress,
+        operator
+      )
+    ).address;
+    const setFee = (feeBasisPoints: number) =>
+      program.methods
+        .updateConfig(operator, operator, feeBasisPoints)
+        .accounts({ config: configKey, operator })
+        .rpc();
+
+    // 2.5% fee
+    await setFee(250);
+    try {
+      const amount = new anchor.BN(20000000);
+      await program.methods
+        .initializeNewGrant(amount, null)
+        .accounts({
+          applicationState: pda.stateKey,
+          escrowWalletState: pda.escrowWalletKey,
+          grantCounter: pda.counterKey,
+          senderIndex: pda.senderIndexKey,
+          receiverIndex: pda.receiverIndexKey,
+          config: configKey,
+          userSending: alice.publicKey,
+          userReceiving: bob.publicKey,
+          mintOfTokenBeingSent: mintAddress,
+          walletToWithdrawFrom: aliceWallet,
+
+          systemProgram: anchor.web3.SystemProgram.programId,
+          tokenProgram: spl.TOKEN_PROGRAM_ID,
+        })
+        .signers([alice])
+        .rpc();
+
+      // The grant keeps the fee it was created with, later changes do not apply
+      const state = await program.account.details.fetch(pda.stateKey);
+      assert.equal(state.feeBasisPoints, 250);
+      assert.ok(state.feeRecipient.equals(operator));
+      await setFee(1000);
+
+      // Above the 10% cap
+      try {
+        await setFee(1001);
+        return assert.fail("The fee should be capped");
+      } catch (e) {
+        assert.include(e.message, "FeeInvalid");
+      }
+
+      const bobTokenAccount = await spl.getAssociatedTokenAddress(
+        mintAddress,
+        bob.publicKey
+      );
+      await program.methods
+        .completeGrant(pda.idx)
+        .accounts({
+          applicationState: pda.stateKey,
+          escrowWalletState: pda.escrowWalletKey,
+          senderIndex: pda.senderIndexKey,
+          receiverIndex: pda.receiverIndexKey,
+          walletToDepositTo: bobTokenAccount,
+          feeWallet: feeWallet,
+          userSending: alice.publicKey,
+          userReceiving: bob.publicKey,
+          mintOfTokenBeingSent: mintAddress,
+
+          systemProgram: anchor.web3.SystemProgram.programId,
+          tokenProgram: spl.TOKEN_PROGRAM_ID,
+          associatedTokenProgram: spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+        })
+        .signers([bob])
+        .rpc();
+
+      const [, bobBalance] = await readAccount(bobTokenAccount, provider);
+      assert.equal(bobBalance, "19500000");
+      const [, feeBalance] = await readAccount(feeWallet, provider);
+      assert.equal(feeBalance, "500000");
+
+      // Waived mints are never charged.
+      await program.methods
+        .addFeeWaiver(mintAddress)
+        .accounts({ config: configKey, operator })
+        .rpc();
+      const config = await program.account.config.fetch(configKey);
+      assert.ok(config.feeWaivedMints.some((mint) => mint.equals(mintAddress)));
+      await program.methods
+        .removeFeeWaiver(mintAddress)
+        .accounts({ config: configKey, operator })
+        .rpc();
+    } finally {
+      await setFee(0);
+    }
+  });
+
+  it("an attester's signed verdict releases the grant without bob", async () => {
+    // A local keypair stands in for the delivery oracle.
+    const attester = Keypair.generate();
+    const amount = new anchor.BN(20000000);
+
+    await program.methods
+      .initializeNewGrant(amount, attester.publicKey)
+      .accounts({
+        applicationState: pda.stateKey,
+        escrowWalletState: pda.escrowWalletKey,
+        grantCounter: pda.counterKey,
+        senderIndex: pda.senderIndexKey,
+        receiverIndex: pda.receiverIndexKey,
+        config: configKey,
+        userSending: alice.publicKey,
+        userReceiving: bob.publicKey,
+        mintOfTokenBeingSent: mintAddress,
+        walletToWithdrawFrom: aliceWallet,
+
+        systemProgram: anchor.web3.SystemProgram.programId,
+        tokenProgram: spl.TOKEN_PROGRAM_ID,
+      })
+      .signers([alice])
+      .rpc();
+
+    const bobTokenAccount = await spl.getAssociatedTokenAddress(
+      mintAddress,
+      bob.publicKey
+    );
+    const settle = (outcome: number, signedOutcome: number) =>
+      program.methods
+        .settleWithAttestation(pda.idx, outcome)
+        .accounts({
+          applicationState: pda.stateKey,
+          escrowWalletState: pda.escrowWalletKey,
+          senderIndex: pda.senderIndexKey,
+          receiverIndex: pda.receiverIndexKey,
+          walletToDepositTo: bobTokenAccount,
+          refundWallet: aliceWallet,
+          feeWallet: null,
+          payer: provider.wallet.publicKey,
+          userSending: alice.publicKey,
+          userReceiving: bob.publicKey,
+          mintOfTokenBeingSent: mintAddress,
+          instructions: anchor.web3.SYSVAR_INSTRUCTIONS_PUBKEY,
+
+          systemProgram: anchor.web3.SystemProgram.programId,
+          tokenProgram: spl.TOKEN_PROGRAM_ID,
+          associatedTokenProgram: spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+        })
+        .preInstructions([
+          anchor.web3.Ed25519Program.createInstructionWithPrivateKey({
+            privateKey: attester.secretKey,
+            message: Buffer.concat([
+              pda.stateKey.toBuffer(),
+              Buffer.from([signedOutcome]),
+            ]),
+          }),
+        ])
+        .rpc();
+
+    // The verdict must match the outcome being executed.
+    try {
+      await settle(1, 2);
+      return assert.fail("Attestation should not match");
+    } catch (e) {
+      assert.include(e.message, "AttestationMissing");
+    }
+
+    const tx = await settle(1, 1);
+    console.log("Settle With Attestation transaction signature", tx);
+
+    const [, bobBalance] = await readAccount(bobTokenAccount, provider);
+    assert.equal(bobBalance, "20000000");
+    assert.isNull(await program.account.details.fetchNullable(pda.stateKey));
+  });
+
+  it("escrows a Token-2022 mint and records what arrives after the transfer fee", async () => {
+    const payer = provider.wallet as anchor.Wallet;
+    const mintKeyPair = Keypair.generate();
+    const mintLen = spl.getMintLen([spl.ExtensionType.TransferFeeConfig]);
+    const lamports = await provider.connection.getMinimumBalanceForRentExemption(
+      mintLen
+    );
+    // 1% on every transfer
+    await provider.sendAndConfirm(
+      new anchor.web3.Transaction().add(
+        anchor.web3.SystemProgram.createAccount({
+          fromPubkey: payer.publicKey,
+          newAccountPubkey: mintKeyPair.publicKey,
+          space: mintLen,
+          lamports,
+          programId: spl.TOKEN_2022_PROGRAM_ID,
+        }),
+        spl.createInitializeTransferFeeConfigInstruction(
+          mintKeyPair.publicKey,
+          payer.publicKey,
+          payer.publicKey,
+          100,
+          BigInt(1000000000),
+          spl.TOKEN_2022_PROGRAM_ID
+        ),
+        spl.createInitializeMintInstruction(
+          mintKeyPair.publicKey,
+          6,
+          payer.publicKey,
+          null,
+          spl.TOKEN_2022_PROGRAM_ID
+        )
+      ),
+      [mintKeyPair]
+    );
+    const mint2022 = mintKeyPair.publicKey;
+
+    const alice2022Wallet = await spl.createAssociatedTokenAccount(
+      provider.connection,
+      payer.payer,
+      mint2022,
+      alice.publicKey,
+      undefined,
+      spl.TOKEN_2022_PROGRAM_ID
+    );
+    await mintTo(
+      provider.connection,
+      payer.payer,
+      mint2022,
+      alice2022Wallet,
+      payer.publicKey,
+      1337000000,
+      [],
+      undefined,
+      spl.TOKEN_2022_PROGRAM_ID
+    );
+    const pda2022 = await getPdaParams(
+      provider.connection,
+      alice.publicKey,
+      bob.publicKey,
+      mint2022
+    );
+
+    await program.methods
+      .initializeNewGrant(new anchor.BN(20000000), null)
+      .accounts({
+        applicationState: pda2022.stateKey,
+        escrowWalletState: pda2022.escrowWalletKey,
+        grantCounter: pda2022.counterKey,
+        senderIndex: pda2022.senderIndexKey,
+        receiverIndex: pda2022.receiverIndexKey,
+        config: configKey,
+        userSending: alice.publicKey,
+        userReceiving: bob.publicKey,
+        mintOfTokenBeingSent: mint2022,
+        walletToWithdrawFrom: alice2022Wallet,
+
+        systemProgram: anchor.web3.SystemProgram.programId,
+        tokenProgram: spl.TOKEN_2022_PROGRAM_ID,
+      })
+      .signers([alice])
+      .rpc();
+
+    // The escrow only received 99% of the deposit
+    const [, escrowBalance] = await readAccount(
+      pda2022.escrowWalletKey,
+      provider
+    );
+    assert.equal(escrowBalance, "19800000");
+    const state = await program.account.details.fetch(pda2022.stateKey);
+    assert.equal(state.amountTokens.toString(), "19800000");
+
+    const bobTokenAccount = spl.getAssociatedTokenAddressSync(
+      mint2022,
+      bob.publicKey,
+      false,
+      spl.TOKEN_2022_PROGRAM_ID
+    );
+    await program.methods
+      .completeGrant(pda2022.idx)
+      .accounts({
+        applicationState: pda2022.stateKey,
+        escrowWalletState: pda2022.escrowWalletKey,
+        senderIndex: pda2022.senderIndexKey,
+        receiverIndex: pda2022.receiverIndexKey,
+        walletToDepositTo: bobTokenAccount,
+        feeWallet: null,
+        userSending: alice.publicKey,
+        userReceiving: bob.publicKey,
+        mintOfTokenBeingSent: mint2022,
+
+        systemProgram: anchor.web3.SystemProgram.programId,
+        tokenProgram: spl.TOKEN_2022_PROGRAM_ID,
+        associatedTokenProgram: spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+      })
+      .signers([bob])
+      .rpc();
+
+    const [, bobBalance] = await readAccount(bobTokenAccount, provider);
+    assert.equal(bobBalance, "19602000");
+    assert.isNull(
+      await provider.connection.getAccountInfo(pda2022.escrowWalletKey)
+    );
+  });
+
+  it("can swap tokens atomically with bob", async () => {
+    const mintExpected = await createMintToken(provider.connection);
+    let bobWallet: anchor.web3.PublicKey;
+    [bob, bobWallet] = await createUserAndAssociatedWallet(
+      provider.connection,
+      mintExpected
+    );
+    pda = await getPdaParams(
+      provider.connection,
+      alice.publicKey,
+      bob.publicKey,
+      mintAddress
+    );
+    const amount = new anchor.BN(20000000);
+    const amountExpected = new anchor.BN(50000000);
+
+    const tx = await program.methods
+      .initializeNewSwap(amount, amountExpected)
+      .accounts({
+        applicationState: pda.stateKey,
+        escrowWalletState: pda.escrowWalletKey,
+        grantCounter: pda.counterKey,
+        senderIndex: pda.senderIndexKey,
+        receiverIndex: pda.receiverIndexKey,
+        userSending: alice.publicKey,
+        userReceiving: bob.publicKey,
+        mintOfTokenBeingSent: mintAddress,
+        mintOfTokenExpected: mintExpected,
+        walletToWithdrawFrom: aliceWallet,
+
+        systemProgram: anchor.web3.SystemProgram.programId,
+        tokenProgram: spl.TOKEN_PROGRAM_ID,
+      })
+      .signers([alice])
+      .rpc();
+
+    console.log("Initialize New Swap transaction signature", tx);
+
+    const [, escrowBalancePost] = await readAccount(
+      pda.escrowWalletKey,
+      provider
+    );
+    assert.equal(escrowBalancePost, "20000000");
+
+    const bobTokenAccount = await spl.getAssociatedTokenAddress(
+      mintAddress,
+      bob.publicKey
+    );
+    const aliceExpectedAccount = await spl.getAssociatedTokenAddress(
+      mintExpected,
+      alice.publicKey
+    );
+
+    const tx2 = await program.methods
+      .acceptSwap(pda.idx)
+      .accounts({
+        applicationState: pda.stateKey,
+        escrowWalletState: pda.escrowWalletKey,
+        senderIndex: pda.senderIndexKey,
+        receiverIndex: pda.receiverIndexKey,
+        walletToDepositTo: bobTokenAccount,
+        senderWalletToDepositTo: aliceExpectedAccount,
+        walletToWithdrawFrom: bobWallet,
+        userSending: alice.publicKey,
+        userReceiving: bob.publicKey,
+        mintOfTokenBeingSent: mintAddress,
+        mintOfTokenExpected: mintExpected,
+
+        systemProgram: anchor.web3.SystemProgram.programId,
+        tokenProgram: spl.TOKEN_PROGRAM_ID,
+        expectedTokenProgram: spl.TOKEN_PROGRAM_ID,
+        associatedTokenProgram: spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+      })
+      .signers([bob])
+      .rpc();
+
+    console.log("Accept Swap transaction signature", tx2);
+
+    // Assert that both legs settled.
+    const [, bobBalance] = await readAccount(bobTokenAccount, provider);
+    assert.equal(bobBalance, "20000000");
+    const [, aliceExpectedBalance] = await readAccount(
+      aliceExpectedAccount,
+      provider
+    );
+    assert.equal(aliceExpectedBalance, "50000000");
+    const [, bobExpectedBalance] = await readAccount(bobWallet, provider);
+    assert.equal(bobExpectedBalance, "1287000000");
+  });
+
+  it("can cancel a swap before bob accepts", async () => {
+    const mintExpected = await createMintToken(provider.connection);
+    const amount = new anchor.BN(20000000);
+    const amountExpected = new anchor.BN(50000000);
+
+    await program.methods
+      .initializeNewSwap(amount, amountExpected)
+      .accounts({
+        applicationState: pda.stateKey,
+        escrowWalletState: pda.escrowWalletKey,
+        grantCounter: pda.counterKey,
+        senderIndex: pda.senderIndexKey,
+        receiverIndex: pda.receiverIndexKey,
+        userSending: alice.publicKey,
+        userReceiving: bob.publicKey,
+        mintOfTokenBeingSent: mintAddress,
+        mintOfTokenExpected: mintExpected,
+        walletToWithdrawFrom: aliceWallet,
+
+        systemProgram: anchor.web3.SystemProgram.programId,
+        tokenProgram: spl.TOKEN_PROGRAM_ID,
+      })
+      .signers([alice])
+      .rpc();
+
+    const tx = await program.methods
+      .cancelSwap(pda.idx)
+      .accounts({
+        applicationState: pda.stateKey,
+        escrowWalletState: pda.escrowWalletKey,
+        senderIndex: pda.senderIndexKey,
+        receiverIndex: pda.receiverIndexKey,
+        refundWallet: aliceWallet,
+        userSending: alice.publicKey,
+        userReceiving: bob.publicKey,
+        mintOfTokenBeingSent: mintAddress,
+
+        systemProgram: anchor.web3.SystemProgram.programId,
+        tokenProgram: spl.TOKEN_PROGRAM_ID,
+      })
+      .signers([alice])
+      .rpc();
+
+    console.log("Cancel Swap transaction signature", tx);
+
+    const [, aliceBalanceRefund] = await readAccount(aliceWallet, provider);
+    assert.equal(aliceBalanceRefund, "1337000000");
+  });
+
+  it("bob pulls a subscription each period until alice cancels", async () => {
+    const amountPerPeriod = new anchor.BN(10000000);
+    const bobWallet = await spl.getAssociatedTokenAddress(
+      mintAddress,
+      bob.publicKey
+    );
+
+    await program.methods
+      .initializeSubscription(amountPerPeriod, new anchor.BN(3600), 3)
+      .accounts({
+        grantCounter: pda.counterKey,
+        applicationState: pda.stateKey,
+        senderIndex: pda.senderIndexKey,
+        receiverIndex: pda.receiverIndexKey,
+        userSending: alice.publicKey,
+        userReceiving: bob.publicKey,
+        mintOfTokenBeingSent: mintAddress,
+        walletToWithdrawFrom: aliceWallet,
+
+        systemProgram: anchor.web3.SystemProgram.programId,
+        tokenProgram: spl.TOKEN_PROGRAM_ID,
+      })
+      .signers([alice])
+      .rpc();
+
+    // Nothing moves until the first charge, the state account is only a delegate
+    const [aliceWalletInfo, aliceBalancePost] = await readAccount(
+      aliceWallet,
+      provider
+    );
+    assert.equal(aliceBalancePost, "1337000000");
+    assert.ok(aliceWalletInfo.delegate.equals(pda.stateKey));
+    assert.equal(aliceWalletInfo.delegatedAmount.toString(), "30000000");
+
+    // The wallet's single delegate is taken, a second subscription would replace it
+    const pdaSecond = await getPdaParams(
+      provider.connection,
+      alice.publicKey,
+      bob.publicKey,
+      mintAddress
+    );
+    try {
+      await program.methods
+        .initializeSubscription(amountPerPeriod, new anchor.BN(3600), 3)
+        .accounts({
+          grantCounter: pdaSecond.counterKey,
+          applicationState: pdaSecond.stateKey,
+          senderIndex: pdaSecond.senderIndexKey,
+          receiverIndex: pdaSecond.receiverIndexKey,
+          userSending: alice.publicKey,
+          userReceiving: bob.publicKey,
+          mintOfTokenBeingSent: mintAddress,
+          walletToWithdrawFrom: aliceWallet,
+
+          systemProgram: anchor.web3.SystemProgram.programId,
+          tokenProgram: spl.TOKEN_PROGRAM_ID,
+        })
+        .signers([alice])
+        .rpc();
+      assert.fail("A second subscription on the same wallet should fail");
+    } catch (e) {
+      assert.equal(e.error.errorCode.code, "WalletAlreadyDelegated");
+    }
+
+    const chargeAccounts = {
+      applicationState: pda.stateKey,
+      senderIndex: pda.senderIndexKey,
+      receiverIndex: pda.receiverIndexKey,
+      walletToWithdrawFrom: aliceWallet,
+      walletToDepositTo: bobWallet,
+      payer: bob.publicKey,
+      userSending: alice.publicKey,
+      userReceiving: bob.publicKey,
+      mintOfTokenBeingSent: mintAddress,
+
+      systemProgram: anchor.web3.SystemProgram.programId,
+      tokenProgram: spl.TOKEN_PROGRAM_ID,
+      associatedTokenProgram: spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+    };
+
+    await program.methods
+      .chargeSubscription(pda.idx)
+      .accounts(chargeAccounts)
+      .signers([bob])
+      .rpc();
+
+    const [, bobBalance] = await readAccount(bobWallet, provider);
+    assert.equal(bobBalance, "10000000");
+    const state = await program.account.details.fetch(pda.stateKey);
+    assert.equal(state.periodsRemaining, 2);
+
+    // The next period has not started yet
+    try {
+      await program.methods
+        .chargeSubscription(pda.idx)
+        .accounts(chargeAccounts)
+        .signers([bob])
+        .rpc();
+      assert.fail("Charging twice in a period should fail");
+    } catch (e) {
+      assert.equal(e.error.errorCode.code, "SubscriptionChargeTooEarly");
+    }
+
+    await program.methods
+      .cancelSubscription(pda.idx)
+      .accounts({
+        applicationState: pda.stateKey,
+        senderIndex: pda.senderIndexKey,
+        receiverIndex: pda.receiverIndexKey,
+        walletToWithdrawFrom: aliceWallet,
+        userSending: alice.publicKey,
+        userReceiving: bob.publicKey,
+        mintOfTokenBeingSent: mintAddress,
+
+        tokenProgram: spl.TOKEN_PROGRAM_ID,
+      })
+      .signers([alice])
+      .rpc();
+
+    const [aliceWalletCancelled, aliceBalanceCancelled] = await readAccount(
+      aliceWallet,
+      provider
+    );
+    assert.equal(aliceBalanceCancelled, "1327000000");
+    assert.equal(aliceWalletCancelled.delegateOption, 0);
+    assert.isNull(await program.account.details.fetchNullable(pda.stateKey));
+  });
+
+  it("recipients of a batch grant claim their share with a proof", async () => {
+    const [carol] = await createUserAndAssociatedWallet(provider.connection);
+    const batchIdx = new anchor.BN(parseInt((Date.now() / 1000).toString()));
+    const entries: [PublicKey, anchor.BN][] = [
+      [bob.publicKey, new anchor.BN(15000000)],
+      [carol.publicKey, new anchor.BN(5000000)],
+    ];
+
+    const leaves = entries.map(([recipient, amount], index) =>
+      Buffer.from(
+        keccak_256(
+          Buffer.concat([
+            new anchor.BN(index).toBuffer("le", 4),
+            recipient.toBuffer(),
+            amount.toBuffer("le", 8),
+          ])
+        )
+      )
+    );
+    const hashPair = (a: Buffer, b: Buffer): Buffer =>
+      Buffer.from(
+        keccak_256(
+          Buffer.compare(a, b) <= 0 ? Buffer.concat([a, b]) : Buffer.concat([b, a])
+        )
+      );
+    const root = hashPair(leaves[0], leaves[1]);
+
+    let [batchStateKey] = PublicKey.findProgramAddressSync(
+      [
+        Buffer.from("batch_state"),
+        alice.publicKey.toBuffer(),
+        mintAddress.toBuffer(),
+        batchIdx.toBuffer("le", 8),
+      ],
+      program.programId
+    );
+    let [batchWalletKey] = PublicKey.findProgramAddressSync(
+      [
+        Buffer.from("batch_wallet"),
+        alice.publicKey.toBuffer(),
+        mintAddress.toBuffer(),
+        batchIdx.toBuffer("le", 8),
+      ],
+      program.programId
+    );
+
+    const tx = await program.methods
+      .initializeNewBatchGrant(
+        batchIdx,
+        entries.length,
+        new anchor.BN(20000000),
+        Array.from(root),
+        batchIdx.addn(3600)
+      )
+      .accounts({
+        batchState: batchStateKey,
+        escrowWalletState: batchWalletKey,
+        userSending: alice.publicKey,
+        mintOfTokenBeingSent: mintAddress,
+        walletToWithdrawFrom: aliceWallet,
+
+        systemProgram: anchor.web3.SystemProgram.programId,
+        tokenProgram: spl.TOKEN_PROGRAM_ID,
+      })
+      .signers([alice])
+      .rpc();
+
+    console.log("Initialize New Batch Grant transaction signature", tx);
+
+    const [, escrowBalancePost] = await readAccount(batchWalletKey, provider);
+    assert.equal(escrowBalancePost, "20000000");
+
+    // Alice has to wait for the batch to expire before taking anything back.
+    try {
+      await program.methods
+        .reclaimBatchGrant(batchIdx)
+        .accounts({
+          batchState: batchStateKey,
+          escrowWalletState: batchWalletKey,
+          refundWallet: aliceWallet,
+          userSending: alice.publicKey,
+          mintOfTokenBeingSent: mintAddress,
+
+          tokenProgram: spl.TOKEN_PROGRAM_ID,
+        })
+        .signers([alice])
+        .rpc();
+      return assert.fail("Batch should not be expired yet");
+    } catch (e) {
+      assert.include(e.message, "BatchNotExpired");
+    }
+
+    const claim = async (index: number, recipient: Keypair) => {
+      const recipientTokenAccount = await spl.getAssociatedTokenAddress(
+        mintAddress,
+        recipient.publicKey
+      );
+      await program.methods
+        .claimBatchGrant(batchIdx, index, entries[index][1], [
+          Array.from(leaves[1 - index]),
+        ])
+        .accounts({
+          batchState: batchStateKey,
+          escrowWalletState: batchWalletKey,
+          walletToDepositTo: recipientTokenAccount,
+          userSending: alice.publicKey,
+          userReceiving: recipient.publicKey,
+          mintOfTokenBeingSent: mintAddress,
+
+          systemProgram: anchor.web3.SystemProgram.programId,
+          tokenProgram: spl.TOKEN_PROGRAM_ID,
+          associatedTokenProgram: spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+        })
+        .signers([recipient])
+        .rpc();
+      return recipientTokenAccount;
+    };
+
+    const bobTokenAccount = await claim(0, bob);
+    const [, bobBalance] = await readAccount(bobTokenAccount, provider);
+    assert.equal(bobBalance, "15000000");
+
+    // Bob cannot claim the same entry twice.
+    try {
+      await claim(0, bob);
+      return assert.fail("Entry should already be claimed");
+    } catch (e) {
+      assert.include(e.message, "BatchAlreadyClaimed");
+    }
+
+    const carolTokenAccount = await claim(1, carol);
+    const [, carolBalance] = await readAccount(carolTokenAccount, provider);
+    assert.equal(carolBalance, "5000000");
+
+    // Assert that escrow was closed once every entry was paid.
+    try {
+      await readAccount(batchWalletKey, provider);
+      return assert.fail("Account should be closed");
+    } catch (e) {
+      assert.equal(
+        e.message,
+        "Cannot read properties of null (reading 'data')"
+      );
+    }
+  });
 });